@@ -0,0 +1,147 @@
+//! Access to the system clipboard.
+//!
+//! The platform tools are tried first (pbcopy, wl-copy, xclip, xsel). When
+//! none of them works, e.g. in an SSH session on a headless box, the text is
+//! sent to the terminal as an OSC 52 escape sequence, which most terminal
+//! emulators turn into a clipboard update.
+//!
+//! [`relay`] works the other way around: it takes text from remote programs
+//! through a forwarded port and puts it into the local clipboard.
+
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::process::{Command, Stdio};
+
+/// Copies `text`, returning the name of the mechanism that was used.
+pub fn copy(text: &str) -> Result<&'static str> {
+    let mut tools: Vec<(&str, &[&str])> = Vec::new();
+    if cfg!(target_os = "macos") {
+        tools.push(("pbcopy", &[]));
+    }
+    if env::var_os("WAYLAND_DISPLAY").is_some() {
+        tools.push(("wl-copy", &[]));
+    }
+    if env::var_os("DISPLAY").is_some() {
+        tools.push(("xclip", &["-selection", "clipboard"]));
+        tools.push(("xsel", &["--clipboard", "--input"]));
+    }
+
+    for (program, args) in tools {
+        if pipe_to(program, args, text).is_ok() {
+            return Ok(program);
+        }
+    }
+
+    osc52(text)?;
+    Ok("OSC 52")
+}
+
+fn pipe_to(program: &str, args: &[&str], text: &str) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    if !child.wait()?.success() {
+        return Err(anyhow!("{} failed", program));
+    }
+    Ok(())
+}
+
+/// Asks the terminal to set the clipboard. Written to the controlling
+/// terminal so it works while stdout is redirected.
+fn osc52(text: &str) -> Result<()> {
+    let mut tty = OpenOptions::new()
+        .write(true)
+        .open("/dev/tty")
+        .context("No clipboard tool found and no terminal for OSC 52")?;
+    let mut sequence = format!("\x1b]52;c;{}\x07", base64(text.as_bytes()));
+    // tmux only forwards the sequence to the outer terminal when wrapped
+    if env::var_os("TMUX").is_some() {
+        sequence = format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"));
+    }
+    tty.write_all(sequence.as_bytes())?;
+    tty.flush()?;
+    Ok(())
+}
+
+/// Listens on a local port for text to copy, returning the port. Every
+/// connection is one copy: plain text, or an OSC 52 sequence as programs
+/// write it for terminals. Runs until masuk exits.
+pub fn relay() -> Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0").context("Failed to start the clipboard relay")?;
+    let port = listener.local_addr()?.port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut data = Vec::new();
+            if stream.and_then(|mut stream| stream.read_to_end(&mut data)).is_err() {
+                continue;
+            }
+            let text = match parse_osc52(&data) {
+                Some(text) => text,
+                None => String::from_utf8_lossy(&data).into_owned(),
+            };
+            // Nothing to report to, the session owns the terminal
+            let _ = copy(&text);
+        }
+    });
+    Ok(port)
+}
+
+/// The text of an OSC 52 sequence (`ESC ] 52 ; c ; <base64> BEL`).
+fn parse_osc52(data: &[u8]) -> Option<String> {
+    let data = std::str::from_utf8(data).ok()?;
+    let rest = data.trim().strip_prefix("\x1b]52;")?;
+    let (_, payload) = rest.split_once(';')?;
+    let payload = payload.trim_end_matches('\x07').trim_end_matches("\x1b\\");
+    String::from_utf8(unbase64(payload)?).ok()
+}
+
+/// Standard base64 with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn unbase64(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in encoded.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
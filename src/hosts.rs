@@ -0,0 +1,99 @@
+//! Entries masuk manages in `/etc/hosts`.
+//!
+//! They live in a marked block so the rest of the file is never touched:
+//!
+//! ```text
+//! # BEGIN masuk
+//! 203.0.113.7    web1    # masuk profile web1
+//! # END masuk
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const HOSTS_FILE: &str = "/etc/hosts";
+const BEGIN: &str = "# BEGIN masuk";
+const END: &str = "# END masuk";
+const PROFILE_MARKER: &str = "# masuk profile ";
+
+pub struct Entry {
+    pub ip: String,
+    pub name: String,
+    pub profile: String,
+}
+
+/// Reads masuk's entries from the hosts file.
+pub fn read() -> Result<Vec<Entry>> {
+    let content = fs::read_to_string(HOSTS_FILE).with_context(|| format!("Failed to read {}", HOSTS_FILE))?;
+    let (_, block, _) = split(&content);
+    Ok(block.iter().filter_map(|line| parse(line)).collect())
+}
+
+/// Replaces masuk's block in the hosts file with `entries`, using sudo
+/// unless masuk already runs as root.
+pub fn write(entries: &[Entry]) -> Result<()> {
+    let content = fs::read_to_string(HOSTS_FILE).with_context(|| format!("Failed to read {}", HOSTS_FILE))?;
+    let (before, _, after) = split(&content);
+
+    let mut lines: Vec<String> = before.iter().map(|l| l.to_string()).collect();
+    if !entries.is_empty() {
+        lines.push(BEGIN.to_string());
+        for entry in entries {
+            lines.push(format!("{}\t{}\t{}{}", entry.ip, entry.name, PROFILE_MARKER, entry.profile));
+        }
+        lines.push(END.to_string());
+    }
+    lines.extend(after.iter().map(|l| l.to_string()));
+
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+
+    if unsafe { libc::geteuid() } == 0 {
+        return fs::write(HOSTS_FILE, updated).with_context(|| format!("Failed to write {}", HOSTS_FILE));
+    }
+
+    let mut child = Command::new("sudo")
+        .args(["tee", HOSTS_FILE])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .context("Failed to run sudo")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(updated.as_bytes())?;
+    }
+    let status = child.wait().context("Failed to run sudo")?;
+    if !status.success() {
+        return Err(anyhow!("Failed to write {} with sudo", HOSTS_FILE));
+    }
+    Ok(())
+}
+
+/// Splits the file into the lines before, inside and after masuk's block.
+fn split(content: &str) -> (Vec<&str>, Vec<&str>, Vec<&str>) {
+    let lines: Vec<&str> = content.lines().collect();
+    let Some(begin) = lines.iter().position(|l| l.trim() == BEGIN) else {
+        return (lines, Vec::new(), Vec::new());
+    };
+    let end = lines[begin..]
+        .iter()
+        .position(|l| l.trim() == END)
+        .map(|i| begin + i)
+        .unwrap_or(lines.len());
+
+    let before = lines[..begin].to_vec();
+    let block = lines[begin + 1..end].to_vec();
+    let after = lines.get(end + 1..).map(|l| l.to_vec()).unwrap_or_default();
+    (before, block, after)
+}
+
+fn parse(line: &str) -> Option<Entry> {
+    let (fields, profile) = line.split_once(PROFILE_MARKER)?;
+    let mut fields = fields.split_whitespace();
+    Some(Entry {
+        ip: fields.next()?.to_string(),
+        name: fields.next()?.to_string(),
+        profile: profile.trim().to_string(),
+    })
+}
@@ -0,0 +1,545 @@
+//! Sources profiles can be imported from.
+
+use crate::HostConfig;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::process::Command;
+
+/// A host found in a source, before it becomes a profile.
+pub struct Found {
+    /// Suggested profile name.
+    pub name: String,
+    pub config: HostConfig,
+}
+
+impl Found {
+    fn new(name: String, host: String) -> Self {
+        Found {
+            name,
+            config: HostConfig {
+                host,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Reads a file, or stdin for `-`.
+pub fn read_input(path: &str) -> Result<String> {
+    if path == "-" {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .context("Failed to read stdin")?;
+        return Ok(input);
+    }
+    fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))
+}
+
+/// Profiles in masuk's own format: either an object mapping names to
+/// profiles, like `profiles` in the config file, or a list of profiles
+/// with a `name` field each, which is what `jq` pipelines produce easily.
+pub fn json(input: &str) -> Result<Vec<Found>> {
+    #[derive(Deserialize)]
+    struct Named {
+        name: String,
+        #[serde(flatten)]
+        config: HostConfig,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Document {
+        Map(HashMap<String, HostConfig>),
+        List(Vec<Named>),
+    }
+
+    let document: Document = serde_json::from_str(input).context(
+        "Expected an object of profiles or a list of profiles with a \"name\" and at least a \"host\"",
+    )?;
+    let mut found: Vec<Found> = match document {
+        Document::Map(profiles) => profiles
+            .into_iter()
+            .map(|(name, config)| Found { name, config })
+            .collect(),
+        Document::List(profiles) => profiles
+            .into_iter()
+            .map(|p| Found {
+                name: p.name,
+                config: p.config,
+            })
+            .collect(),
+    };
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(found)
+}
+
+/// Profiles from CSV with a header row. `name` and `host` columns are
+/// required; `user`, `port`, `key` and `tags` (separated by `;`) are
+/// optional.
+pub fn csv(input: &str) -> Result<Vec<Found>> {
+    let mut rows = input.lines().filter(|line| !line.trim().is_empty());
+    let header = rows.next().ok_or_else(|| anyhow!("The CSV input is empty"))?;
+    let columns: Vec<String> = csv_fields(header)?.iter().map(|c| c.trim().to_lowercase()).collect();
+    for column in &columns {
+        if !["name", "host", "user", "port", "key", "tags"].contains(&column.as_str()) {
+            return Err(anyhow!(
+                "Unknown CSV column '{}'. Supported: name, host, user, port, key, tags",
+                column
+            ));
+        }
+    }
+    if !columns.iter().any(|c| c == "name") || !columns.iter().any(|c| c == "host") {
+        return Err(anyhow!("The CSV header needs at least the columns name and host"));
+    }
+
+    let mut found = Vec::new();
+    for (line, row) in rows.enumerate() {
+        let line = line + 2;
+        let fields = csv_fields(row).with_context(|| format!("CSV line {}", line))?;
+        let mut name = String::new();
+        let mut entry = Found::new(String::new(), String::new());
+        for (column, value) in columns.iter().zip(fields) {
+            let value = value.trim().to_string();
+            if value.is_empty() {
+                continue;
+            }
+            match column.as_str() {
+                "name" => name = value,
+                "host" => entry.config.host = value,
+                "user" => entry.config.user = Some(value),
+                "port" => {
+                    entry.config.port = Some(
+                        value
+                            .parse()
+                            .map_err(|_| anyhow!("CSV line {}: invalid port '{}'", line, value))?,
+                    )
+                }
+                "key" => entry.config.keys = vec![value],
+                "tags" => entry.config.tags = value.split(';').map(|t| t.trim().to_string()).collect(),
+                _ => unreachable!(),
+            }
+        }
+        if name.is_empty() || entry.config.host.is_empty() {
+            return Err(anyhow!("CSV line {}: name and host are required", line));
+        }
+        entry.name = name;
+        found.push(entry);
+    }
+    Ok(found)
+}
+
+/// Splits one CSV line, honouring double quotes and `""` escapes.
+fn csv_fields(line: &str) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(anyhow!("Unterminated quote"));
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+/// A and AAAA records of a DNS zone, fetched with a zone transfer
+/// (`dig axfr`). Names are given relative to the zone, hosts as FQDNs so
+/// DNS stays the source of truth for addresses.
+pub fn dns_zone(zone: &str, server: Option<&str>) -> Result<Vec<Found>> {
+    let zone = zone.trim_end_matches('.');
+    let mut command = Command::new("dig");
+    command.args(["+noall", "+answer", "axfr", zone]);
+    if let Some(server) = server {
+        command.arg(format!("@{}", server));
+    }
+
+    let output = command.output().context("Failed to run 'dig'. Is it installed?")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // dig reports refused transfers on stdout and still exits with 0
+    if !output.status.success() || stdout.lines().any(|l| l.starts_with("; Transfer failed")) {
+        return Err(anyhow!(
+            "Zone transfer of {} failed. Is AXFR allowed from this machine? Use --server to ask another name server.",
+            zone
+        ));
+    }
+
+    let suffix = format!(".{}.", zone);
+    let mut found: Vec<Found> = Vec::new();
+    for line in stdout.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [fqdn, _ttl, _class, kind, ..] = fields[..] else {
+            continue;
+        };
+        if !matches!(kind, "A" | "AAAA") || fqdn.starts_with('*') {
+            continue;
+        }
+
+        let name = match fqdn.strip_suffix(&suffix) {
+            Some(name) => name.to_string(),
+            None => zone.to_string(),
+        };
+        // Hosts with both A and AAAA records show up twice
+        if found.iter().any(|f| f.name == name) {
+            continue;
+        }
+        found.push(Found::new(name, fqdn.trim_end_matches('.').to_string()));
+    }
+    Ok(found)
+}
+
+/// Which attributes of a Terraform object become which profile field, as
+/// dotted paths such as `tags.Name` or `network_interface.0.network_ip`.
+#[derive(Default)]
+pub struct TerraformMap {
+    pub name: Option<String>,
+    pub host: Option<String>,
+    pub user: Option<String>,
+}
+
+impl TerraformMap {
+    /// Parses `--map field=path` arguments.
+    pub fn parse(mappings: &[String]) -> Result<Self> {
+        let mut map = TerraformMap::default();
+        for mapping in mappings {
+            let (field, path) = mapping
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected --map <field>=<attribute path>, got '{}'", mapping))?;
+            let path = Some(path.to_string());
+            match field {
+                "name" => map.name = path,
+                "host" => map.host = path,
+                "user" => map.user = path,
+                _ => return Err(anyhow!("Unknown --map field '{}'. Supported: name, host, user", field)),
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// Attributes tried for the address when `--map host=...` isn't given,
+/// covering the compute resources of the common providers.
+const TERRAFORM_HOSTS: &[&str] = &[
+    "public_ip",
+    "ipv4_address",
+    "access_ip_v4",
+    "network_interface.0.access_config.0.nat_ip",
+    "private_ip",
+    "network_interface.0.network_ip",
+    "default_ip_address",
+    "ip",
+    "host",
+    "address",
+];
+
+/// Attributes tried for the profile name when `--map name=...` isn't given.
+const TERRAFORM_NAMES: &[&str] = &["tags.Name", "labels.name", "name"];
+
+/// Hosts in a Terraform state file or in `terraform output -json`.
+///
+/// In state files every managed resource with an address becomes a
+/// profile. Outputs may be a single address, a list of addresses, a map of
+/// names to addresses, or lists/maps of objects, which are read with the
+/// same attribute paths as resources.
+pub fn terraform(input: &str, map: &TerraformMap) -> Result<Vec<Found>> {
+    let document: serde_json::Value =
+        serde_json::from_str(input).context("Expected a Terraform state file or 'terraform output -json'")?;
+
+    let mut found = Vec::new();
+    if let Some(resources) = document.get("resources").and_then(|r| r.as_array()) {
+        for resource in resources {
+            if resource["mode"] == "data" {
+                continue;
+            }
+            let resource_name = resource["name"].as_str().unwrap_or("resource");
+            let instances = resource["instances"].as_array().map(Vec::as_slice).unwrap_or_default();
+            for instance in instances {
+                let fallback = match &instance["index_key"] {
+                    serde_json::Value::Null => resource_name.to_string(),
+                    serde_json::Value::String(key) => format!("{}-{}", resource_name, key),
+                    key => format!("{}-{}", resource_name, key),
+                };
+                if let Some(entry) = terraform_object(&instance["attributes"], map, fallback) {
+                    found.push(entry);
+                }
+            }
+        }
+    } else {
+        let outputs = document
+            .as_object()
+            .ok_or_else(|| anyhow!("Expected a Terraform state file or 'terraform output -json'"))?;
+        for (output, value) in outputs {
+            // `terraform output -json` wraps values, `-json <name>` doesn't
+            let value = value.get("value").unwrap_or(value);
+            match value {
+                serde_json::Value::String(host) => found.push(Found::new(output.clone(), host.clone())),
+                serde_json::Value::Array(items) => {
+                    for (index, item) in items.iter().enumerate() {
+                        let fallback = format!("{}-{}", output, index);
+                        match item {
+                            serde_json::Value::String(host) => found.push(Found::new(fallback, host.clone())),
+                            item => found.extend(terraform_object(item, map, fallback)),
+                        }
+                    }
+                }
+                serde_json::Value::Object(items) => {
+                    for (key, item) in items {
+                        match item {
+                            serde_json::Value::String(host) => found.push(Found::new(key.clone(), host.clone())),
+                            item => found.extend(terraform_object(item, map, key.clone())),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    for entry in &mut found {
+        entry.name = entry.name.split_whitespace().collect::<Vec<_>>().join("-");
+    }
+    Ok(found)
+}
+
+/// Builds a profile from resource attributes or an output object, `None`
+/// if it has no address.
+fn terraform_object(object: &serde_json::Value, map: &TerraformMap, fallback: String) -> Option<Found> {
+    let lookup = |path: &str| {
+        path.split('.')
+            .try_fold(object, |value, key| match key.parse::<usize>() {
+                Ok(index) if value.is_array() => value.get(index),
+                _ => value.get(key),
+            })
+            .and_then(|value| match value {
+                serde_json::Value::String(s) if !s.is_empty() => Some(s.clone()),
+                serde_json::Value::Number(n) => Some(n.to_string()),
+                _ => None,
+            })
+    };
+    let first = |paths: &[&str]| paths.iter().find_map(|path| lookup(path));
+
+    let host = match map.host {
+        Some(ref path) => lookup(path),
+        None => first(TERRAFORM_HOSTS),
+    }?;
+    let name = match map.name {
+        Some(ref path) => lookup(path),
+        None => first(TERRAFORM_NAMES),
+    }
+    .unwrap_or(fallback);
+
+    let mut entry = Found::new(name, host);
+    entry.config.user = map.user.as_deref().and_then(lookup);
+    Some(entry)
+}
+
+/// Workspace a state file belongs to, from its location in
+/// `terraform.tfstate.d/<workspace>/` or `TF_WORKSPACE`.
+pub fn terraform_workspace(file: &str) -> Option<String> {
+    let path = std::path::Path::new(file);
+    let parent = path.parent()?;
+    if parent.parent()?.file_name()? == "terraform.tfstate.d" {
+        return Some(parent.file_name()?.to_string_lossy().to_string());
+    }
+    std::env::var("TF_WORKSPACE").ok()
+}
+
+/// Runs `terraform output -json` in the current directory, returning the
+/// outputs and the selected workspace.
+pub fn terraform_outputs() -> Result<(String, Option<String>)> {
+    let output = Command::new("terraform")
+        .args(["output", "-json"])
+        .output()
+        .context("Failed to run 'terraform'. Is it installed?")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "'terraform output -json' failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let workspace = Command::new("terraform")
+        .args(["workspace", "show"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string());
+    Ok((String::from_utf8_lossy(&output.stdout).to_string(), workspace))
+}
+
+/// A `Host` or `Match` section of an ssh_config, or the options before the
+/// first one.
+struct Section {
+    /// Patterns of a `Host` line, empty for the options before any section.
+    patterns: Vec<String>,
+    /// `Match` criteria can't be evaluated without connecting.
+    is_match: bool,
+    /// Keywords (lowercase) and their values, in order.
+    options: Vec<(String, String)>,
+}
+
+/// Profiles for the `Host` entries of an ssh_config (`-` reads stdin),
+/// named with `prefix` in front. HostName, User, Port, IdentityFile and
+/// ProxyJump are taken over, resolved like ssh does: the first value found
+/// in a matching section wins, IdentityFiles add up. Also returns the Host
+/// patterns and Match blocks that were skipped, which can't become
+/// profiles.
+pub fn ssh_config(path: &str, prefix: &str) -> Result<(Vec<Found>, Vec<String>)> {
+    let mut lines = Vec::new();
+    ssh_config_lines(&read_input(path)?, &mut lines, 0)?;
+
+    let mut sections = vec![Section {
+        patterns: Vec::new(),
+        is_match: false,
+        options: Vec::new(),
+    }];
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (keyword, value) = ssh_config_option(line);
+        match keyword.as_str() {
+            "host" => sections.push(Section {
+                patterns: value.split_whitespace().map(str::to_string).collect(),
+                is_match: false,
+                options: Vec::new(),
+            }),
+            "match" => sections.push(Section {
+                patterns: vec![value],
+                is_match: true,
+                options: Vec::new(),
+            }),
+            _ => sections.last_mut().unwrap().options.push((keyword, value)),
+        }
+    }
+
+    let mut aliases: Vec<String> = Vec::new();
+    let mut skipped: Vec<String> = Vec::new();
+    for section in &sections {
+        if section.is_match {
+            skipped.push(format!("Match {}", section.patterns.join(" ")));
+            continue;
+        }
+        for pattern in &section.patterns {
+            let list = if pattern.contains(['*', '?', '!']) { &mut skipped } else { &mut aliases };
+            if !list.contains(pattern) {
+                list.push(pattern.clone());
+            }
+        }
+    }
+
+    let mut found = Vec::new();
+    for alias in &aliases {
+        let mut entry = Found::new(format!("{}{}", prefix, alias), alias.clone());
+        let (mut host, mut user, mut port, mut jump) = (None, None, None, None);
+        for section in &sections {
+            let applies = !section.is_match
+                && (section.patterns.is_empty()
+                    || section.patterns.iter().any(|p| !p.starts_with('!') && crate::glob_match(p, alias))
+                        && !section.patterns.iter().any(|p| p.strip_prefix('!').is_some_and(|p| crate::glob_match(p, alias))));
+            if !applies {
+                continue;
+            }
+            for (keyword, value) in &section.options {
+                match keyword.as_str() {
+                    "hostname" => host = host.or(Some(value.replace("%h", alias))),
+                    "user" => user = user.or(Some(value.clone())),
+                    "port" => port = port.or(Some(value.clone())),
+                    "proxyjump" => jump = jump.or(Some(value.clone())),
+                    "identityfile" if !entry.config.keys.contains(value) => entry.config.keys.push(value.clone()),
+                    _ => {}
+                }
+            }
+        }
+        if let Some(host) = host {
+            entry.config.host = host;
+        }
+        entry.config.user = user;
+        if let Some(port) = port {
+            entry.config.port = Some(port.parse().map_err(|_| anyhow!("Host {}: invalid port '{}'", alias, port))?);
+        }
+        // Hops naming other imported hosts become references to their profiles
+        let hops: Vec<String> = match jump.as_deref() {
+            None | Some("none") => Vec::new(),
+            Some(jump) => jump
+                .split(',')
+                .map(|hop| match aliases.iter().any(|alias| alias == hop) {
+                    true => format!("{}{}", prefix, hop),
+                    false => hop.to_string(),
+                })
+                .collect(),
+        };
+        let mut hops = hops.into_iter();
+        entry.config.jump = hops.next();
+        entry.config.via = hops.collect();
+        found.push(entry);
+    }
+    Ok((found, skipped))
+}
+
+/// Splits an ssh_config line into its lowercase keyword and the value,
+/// which may be separated by `=` and quoted.
+fn ssh_config_option(line: &str) -> (String, String) {
+    let (keyword, value) = line.split_once(|c: char| c.is_whitespace() || c == '=').unwrap_or((line, ""));
+    let value = value.trim_start_matches(|c: char| c.is_whitespace() || c == '=').trim();
+    let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(unquoted) if !unquoted.contains('"') => unquoted,
+        _ => value,
+    };
+    (keyword.to_lowercase(), value.to_string())
+}
+
+/// The lines of an ssh_config with `Include`d files in their place.
+/// Relative includes are in `~/.ssh`, and the file name may be a glob.
+fn ssh_config_lines(input: &str, lines: &mut Vec<String>, depth: usize) -> Result<()> {
+    if depth > 16 {
+        return Err(anyhow!("ssh_config includes are nested too deeply"));
+    }
+    let home = dirs::home_dir().unwrap_or_default();
+    for line in input.lines() {
+        let (keyword, value) = ssh_config_option(line.trim());
+        if keyword != "include" {
+            lines.push(line.to_string());
+            continue;
+        }
+        for include in value.split_whitespace() {
+            let path = match include.strip_prefix("~/") {
+                Some(rest) => home.join(rest),
+                None => home.join(".ssh").join(include),
+            };
+            let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            let mut files = Vec::new();
+            match path.parent().filter(|_| name.contains(['*', '?'])) {
+                Some(dir) => {
+                    for file in fs::read_dir(dir).into_iter().flatten().flatten() {
+                        if crate::glob_match(&name, &file.file_name().to_string_lossy()) {
+                            files.push(file.path());
+                        }
+                    }
+                    files.sort();
+                }
+                None => files.push(path),
+            }
+            // Like ssh, includes that don't exist are ignored
+            for file in files {
+                if let Ok(content) = fs::read_to_string(&file) {
+                    ssh_config_lines(&content, lines, depth + 1)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+//! Ctrl-C handling.
+//!
+//! Instead of dying on the spot, masuk records the interrupt and lets the
+//! running operation wind down: child processes in the foreground receive the
+//! signal themselves, and masuk then runs its normal cleanup (temporary files,
+//! webhooks, summaries) before exiting with status 130. A second Ctrl-C exits
+//! immediately.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle(_signal: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        // Only async-signal-safe calls are allowed here
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Installs the handler for SIGINT, SIGTERM and SIGHUP.
+pub fn install() {
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        unsafe {
+            libc::signal(signal, handler);
+        }
+    }
+}
+
+/// Whether an interrupt has been received.
+pub fn requested() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Error returned by operations that stopped because of an interrupt.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Fails with [`Interrupted`] once an interrupt has been received.
+pub fn check() -> anyhow::Result<()> {
+    if requested() {
+        return Err(Interrupted.into());
+    }
+    Ok(())
+}
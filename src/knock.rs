@@ -0,0 +1,49 @@
+//! Port knocking, for hosts behind knockd or a similar firewall.
+//!
+//! A knock is a connection attempt (TCP) or a single datagram (UDP) to a
+//! port nothing listens on. Nobody answers; the firewall only watches for
+//! the right sequence and then opens the SSH port for the sender.
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+/// Pause after every knock when the profile doesn't set one.
+pub const DEFAULT_DELAY_MS: u64 = 100;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// Knocks on `ports` of `host` in order, pausing `delay` after each so the
+/// packets arrive in sequence and the firewall has opened up before ssh
+/// connects.
+pub fn knock(host: &str, ports: &[u16], protocol: Protocol, delay: Duration) -> Result<()> {
+    let address = (host, 0)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| anyhow!("Can't resolve {} to knock on it", host))?;
+    for &port in ports {
+        let target = SocketAddr::new(address.ip(), port);
+        match protocol {
+            // Refused or timed out are both fine, the SYN is what counts
+            Protocol::Tcp => drop(TcpStream::connect_timeout(&target, Duration::from_millis(100))),
+            Protocol::Udp => {
+                let local = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                let socket = UdpSocket::bind(local).context("Failed to open a UDP socket for knocking")?;
+                socket
+                    .send_to(&[0], target)
+                    .with_context(|| format!("Failed to knock on {}", target))?;
+            }
+        }
+        thread::sleep(delay);
+    }
+    Ok(())
+}
@@ -0,0 +1,7 @@
+//! Library side of masuk, for code that is meant to be reused outside the
+//! `masuk` binary.
+
+pub mod secrets;
+
+#[cfg(feature = "test-support")]
+pub mod test_support;
@@ -1,30 +1,683 @@
+mod clipboard;
+mod hosts;
+mod import;
+mod interrupt;
+mod knock;
+mod progress;
+mod render;
+mod snapshot;
+mod totp;
+mod wol;
+
 use anyhow::{anyhow, Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fs;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::PathBuf;
-use std::process::Command;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use render::{out, plain, Item};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 struct HostConfig {
     host: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     user: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     port: Option<u16>,
+    /// Other addresses of the same machine, tried in order when `host`
+    /// doesn't answer.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    failover: Vec<String>,
+    /// Seconds each address gets to answer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failover_timeout: Option<u64>,
+    /// Identical machines to spread connections over, together with `host`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pool: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strategy: Option<PoolStrategy>,
+    /// Identity files, tried in order. Stored as a plain string when there's
+    /// only one.
+    #[serde(
+        rename = "key",
+        alias = "identity_file",
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "one_or_many"
+    )]
+    keys: Vec<String>,
+    /// SSH certificate signed for the key, offered along with it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    certificate_file: Option<String>,
+    /// known_hosts file for this host, e.g. one with a `@cert-authority`
+    /// line for CA-signed host keys.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    known_hosts_file: Option<String>,
+    /// Socket of the ssh agent to use instead of `$SSH_AUTH_SOCK`, or
+    /// `none` for no agent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identity_agent: Option<String>,
+    /// Remote directory `masuk push` copies into and `masuk pull` copies
+    /// relative paths from, the home directory when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_dir: Option<String>,
+    /// Local directory `masuk pull` copies into, the current one when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_dir: Option<String>,
+    /// Role of Vault's SSH secrets engine that brokers access to the host,
+    /// asked for credentials before every connection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vault_role: Option<String>,
+    /// Mount path of the SSH secrets engine, `ssh` when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vault_mount: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vault_mode: Option<VaultMode>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// Jump host, either `[user@]host[:port]` or the name of another profile.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jump: Option<String>,
+    /// Further hops after `jump`, in the same format.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    via: Vec<String>,
+    /// Arguments passed to ssh as is, before the destination.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_args: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    forward_agent: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    x11: bool,
+    /// Use trusted X11 forwarding (`-Y`) instead of `-X`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    trusted_x11: bool,
+    /// `Some(false)` explicitly disables compression, overriding ssh_config.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<bool>,
+    /// Seconds between keep-alive messages (ssh ServerAliveInterval).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keepalive_interval: Option<u32>,
+    /// Unanswered keep-alives before disconnecting (ssh ServerAliveCountMax).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keepalive_count: Option<u32>,
+    /// Local account ssh is started as, via sudo.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_user: Option<String>,
+    /// Start ssh in its own transient systemd scope.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    systemd_scope: bool,
+    /// Share one master connection between sessions (ssh ControlMaster).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    multiplex: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address_family: Option<AddressFamily>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_key_checking: Option<HostKeyChecking>,
+    /// Client used instead of `ssh`, e.g. `autossh -M 0`. Overrides the
+    /// global setting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssh_command: Option<String>,
+    /// Terminal emulator `masuk open` starts the session in, see
+    /// [`terminal_command`]. Overrides the global setting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    terminal: Option<String>,
+    /// Fingerprint of the host key last accepted by `masuk hostkey rollover`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_key_fingerprint: Option<String>,
+    /// Command run instead of the login shell on connect, e.g. `tmux new -A -s main`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_command: Option<String>,
+    /// Secret reference to the login password for hosts that only take
+    /// passwords, e.g. `keyring://masuk/router1`, handed to ssh through
+    /// SSH_ASKPASS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    /// Secret reference to the passphrase of the profile's key, handed to
+    /// ssh the same way.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_passphrase: Option<String>,
+    /// Secret reference to the TOTP secret of hosts asking for an
+    /// authenticator code, base32 or an `otpauth://` URI.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    totp: Option<String>,
+    /// Secret reference to the sudo password, e.g.
+    /// `keyring://masuk-sudo/web1`, fed to `sudo -S` by `masuk exec`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sudo_password: Option<String>,
+    /// Local shell command run before connecting, e.g. to bring up a VPN.
+    /// A failing hook cancels the connection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pre_connect: Option<String>,
+    /// Local shell command run after the session ends.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_connect: Option<String>,
+    /// Variables exported for ssh and sent to the host with SendEnv. The
+    /// server has to accept them (AcceptEnv).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    /// Facts about the host gathered by `masuk bootstrap`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    facts: BTreeMap<String, String>,
+    /// Seconds to wait for the TCP connection (ssh ConnectTimeout).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_timeout: Option<u32>,
+    /// Reconnect when the connection drops (ssh exits with 255).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    retry: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_retries: Option<u32>,
+    /// Seconds before the first reconnect, doubled for every further one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_backoff: Option<u64>,
+    /// Risk level, picking the `risk:<level>` policy.
     #[serde(skip_serializing_if = "Option::is_none")]
-    key: Option<String>,
+    risk: Option<Risk>,
+    /// What the profile connects to and how, ssh unless set.
+    #[serde(alias = "protocol", skip_serializing_if = "Option::is_none")]
+    backend: Option<Backend>,
+    /// UDP port or range for mosh, as `lo:hi` like mosh's `--port`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mosh_ports: Option<String>,
+    /// Path of mosh-server on the host, for installs outside PATH.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mosh_server: Option<String>,
+    /// Serial device of a serial console profile, e.g. `/dev/ttyUSB0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<String>,
+    /// Line speed of the serial device, the tool's default (9600) unless set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    baud: Option<u32>,
+    /// Container of a docker profile, on `host` or locally when it's empty.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    container: Option<String>,
+    /// kubectl context of a kubernetes profile, the current one unless set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    /// Pod of a kubernetes profile, or anything kubectl exec takes like `deploy/api`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pod: Option<String>,
+    /// Label selector picking a running pod when `pod` isn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selector: Option<String>,
+    /// Instance of an aws-ssm profile, e.g. `i-0abc123`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance_id: Option<String>,
+    /// Named AWS CLI profile for aws-ssm, the default credentials unless set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aws_profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    region: Option<String>,
+    /// Local port of a SOCKS proxy (ssh -D) opened while connected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks_port: Option<u16>,
+    /// SOCKS proxy to connect through: `host:port`, a local port, or a
+    /// profile with a `socks_port`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks_proxy: Option<String>,
+    /// Command ssh connects through instead of a TCP connection, with
+    /// ssh's `%h`/`%p` placeholders.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxy_command: Option<String>,
+    /// Ports to knock on before connecting, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    knock: Vec<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    knock_protocol: Option<knock::Protocol>,
+    /// Milliseconds to wait after every knock.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    knock_delay: Option<u64>,
+    /// MAC address for Wake-on-LAN.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wol_mac: Option<String>,
+    /// Where the magic packet is sent, `255.255.255.255` by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wol_broadcast: Option<String>,
+    /// Instance of a gcloud or incus profile, machine of a vagrant one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    /// Directory of the Vagrantfile of a vagrant profile.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vagrant_dir: Option<PathBuf>,
+    /// GCP project of a gcloud profile, gcloud's default project unless set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    project: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    zone: Option<String>,
+    /// Tunnel through Identity-Aware Proxy, for instances without an
+    /// external address.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    iap: bool,
+    /// Let remote programs copy into the local clipboard through a
+    /// forwarded port while connected.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    clipboard: bool,
+    /// Port the clipboard relay is forwarded to on the host, 2224 unless set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clipboard_port: Option<u16>,
+    /// Local forwardings by name, e.g. `grafana` = `3000:localhost:3000`,
+    /// opened with `masuk tunnel <profile> <name>`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tunnels: BTreeMap<String, String>,
+    /// Remote forwardings (`ssh -R`) `masuk tunnel` opens when none are
+    /// given, `[BIND:]PORT:HOST:HOSTPORT` with the port on the remote host.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    remote_forwards: Vec<String>,
+    /// Commands the host is expected to have, checked before connecting.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    requires: Vec<String>,
+    /// Minimum free space on `/` in percent, checked before connecting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_disk_free: Option<u8>,
+    /// Unix time the profile was added; unknown for older profiles.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<i64>,
+    /// Unix time of the last change to the profile.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<i64>,
+}
+
+/// (De)serializes a list that is written as a single value when it has one
+/// element.
+mod one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    pub fn serialize<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+        match values {
+            [value] => value.serialize(serializer),
+            values => values.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        })
+    }
+}
+
+/// An sshfs mount made by `masuk mount`, remembered in `mounts.json` so it
+/// can be listed and unmounted by profile.
+#[derive(Serialize, Deserialize, Clone)]
+struct Mount {
+    profile: String,
+    remote: String,
+    mountpoint: PathBuf,
+    /// Unix time of mounting.
+    mounted_at: u64,
+}
+
+/// A tunnel started with `masuk tunnel --background`, remembered in
+/// `tunnels.json` so it can be listed, stopped and reopened after this
+/// machine restarted.
+#[derive(Serialize, Deserialize, Clone)]
+struct BackgroundTunnel {
+    id: u32,
+    profile: String,
+    /// Process of the ssh connection.
+    pid: u32,
+    /// The forwardings as ssh options, e.g. `-L 8080:localhost:80`.
+    forwards: Vec<String>,
+    /// Whether `pid` is masuk watching the connection and reopening it.
+    #[serde(default)]
+    persist: bool,
+    #[serde(default)]
+    gateway_ports: bool,
+    #[serde(default)]
+    notify: bool,
+    /// Unix time of starting.
+    started_at: u64,
+}
+
+/// One hop of a resolved jump chain.
+struct Hop {
+    /// Profile the hop came from, if it wasn't a raw address.
+    profile: Option<String>,
+    address: String,
 }
 
 type Profiles = HashMap<String, HostConfig>;
 
+/// Tool used to record sessions for profiles matched by a policy.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Recorder {
+    /// Plain text log via script(1)
+    Script,
+    /// Terminal recording via asciinema
+    Asciinema,
+}
+
+/// IP version ssh connects with, named like ssh's AddressFamily option.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum AddressFamily {
+    /// Whatever the resolver returns first
+    Any,
+    /// IPv4 only (ssh -4)
+    #[value(alias = "ipv4")]
+    Inet,
+    /// IPv6 only (ssh -6)
+    #[value(alias = "ipv6")]
+    Inet6,
+}
+
+/// What carries a profile's sessions.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    Ssh,
+    /// Survives roaming and flaky links; ssh is only used to start it
+    Mosh,
+    /// Unencrypted, for legacy network gear that speaks nothing else
+    Telnet,
+    /// Local serial console (picocom or screen) on the profile's device
+    Serial,
+    /// Shell in the profile's container, on the host over ssh or locally without one
+    Docker,
+    /// Shell in a pod through the local kubectl
+    #[value(alias = "k8s")]
+    Kubernetes,
+    /// AWS Systems Manager session to an instance, no ssh needed
+    #[serde(rename = "aws-ssm")]
+    AwsSsm,
+    /// `gcloud compute ssh` to a GCP instance, optionally through IAP
+    Gcloud,
+    /// Shell in a local Incus container or VM
+    Incus,
+    /// `vagrant ssh` into a machine of the profile's Vagrant project
+    Vagrant,
+}
+
+impl Backend {
+    fn name(self) -> &'static str {
+        match self {
+            Backend::Ssh => "ssh",
+            Backend::Mosh => "mosh",
+            Backend::Telnet => "telnet",
+            Backend::Serial => "serial",
+            Backend::Docker => "docker",
+            Backend::Kubernetes => "kubernetes",
+            Backend::AwsSsm => "aws-ssm",
+            Backend::Gcloud => "gcloud",
+            Backend::Incus => "incus",
+            Backend::Vagrant => "vagrant",
+        }
+    }
+}
+
+/// How ssh treats unknown and changed host keys.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum HostKeyChecking {
+    /// Refuse hosts whose key isn't known yet
+    Strict,
+    /// Learn keys of new hosts, refuse changed keys
+    AcceptNew,
+    /// Accept any key without recording it, for ephemeral VMs
+    Off,
+}
+
+/// Global settings changed with `masuk config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Setting {
+    /// Client used instead of ssh, e.g. "autossh -M 0"
+    SshCommand,
+    /// Terminal emulator for 'masuk open', e.g. "kitty --title {profile}"
+    Terminal,
+}
+
+impl Setting {
+    fn name(self) -> &'static str {
+        match self {
+            Setting::SshCommand => "ssh-command",
+            Setting::Terminal => "terminal",
+        }
+    }
+}
+
+/// Groupings offered by `masuk ls --group-by`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum GroupBy {
+    /// Month the profile was added
+    MonthAdded,
+    Tag,
+    User,
+}
+
+/// URL flavours printed by `masuk url`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum UrlScheme {
+    Ssh,
+    Sftp,
+    Rsync,
+}
+
+/// Rules applied to every profile carrying the tag the policy is keyed by.
+/// The policy keyed by `risk:<level>` applies to profiles of that risk, the
+/// one keyed by `*` to all profiles.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct Policy {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    record: Option<Recorder>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhook: Option<String>,
+    /// Ask before connecting or running a command.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    confirm: bool,
+    /// Leave matching profiles out of commands on several profiles.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    no_fleet: bool,
+}
+
+/// Connection settings for profiles that don't set them, globally or for
+/// the profiles with a tag. See [`Masuk::with_defaults`] for the order.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+struct Defaults {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    keys: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identity_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jump: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_timeout: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keepalive_interval: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keepalive_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address_family: Option<AddressFamily>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_key_checking: Option<HostKeyChecking>,
+}
+
+impl Defaults {
+    fn is_empty(&self) -> bool {
+        *self == Defaults::default()
+    }
+
+    /// Fills in what `host_config` leaves unset, returning the labels (as
+    /// `masuk show` prints them) of the settings it filled. A default jump
+    /// host doesn't apply to itself.
+    fn fill(&self, host_config: &mut HostConfig, profile: &str) -> Vec<&'static str> {
+        fn inherit<T: Clone>(slot: &mut Option<T>, default: &Option<T>) -> bool {
+            let fill = slot.is_none() && default.is_some();
+            if fill {
+                *slot = default.clone();
+            }
+            fill
+        }
+
+        let mut filled = Vec::new();
+        if inherit(&mut host_config.user, &self.user) {
+            filled.push("user");
+        }
+        if inherit(&mut host_config.port, &self.port) {
+            filled.push("port");
+        }
+        if host_config.keys.is_empty() && !self.keys.is_empty() {
+            host_config.keys = self.keys.clone();
+            filled.push("key");
+        }
+        if inherit(&mut host_config.identity_agent, &self.identity_agent) {
+            filled.push("identity agent");
+        }
+        if self.jump.as_deref() != Some(profile) && inherit(&mut host_config.jump, &self.jump) {
+            filled.push("jump");
+        }
+        if inherit(&mut host_config.connect_timeout, &self.connect_timeout) {
+            filled.push("connect timeout");
+        }
+        if inherit(&mut host_config.keepalive_interval, &self.keepalive_interval) {
+            filled.push("keepalive interval");
+        }
+        if inherit(&mut host_config.keepalive_count, &self.keepalive_count) {
+            filled.push("keepalive count");
+        }
+        if inherit(&mut host_config.compression, &self.compression) {
+            filled.push("compression");
+        }
+        if inherit(&mut host_config.address_family, &self.address_family) {
+            filled.push("address family");
+        }
+        if inherit(&mut host_config.host_key_checking, &self.host_key_checking) {
+            filled.push("host key checking");
+        }
+        filled
+    }
+
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(ref user) = self.user {
+            parts.push(format!("user {}", user));
+        }
+        if let Some(port) = self.port {
+            parts.push(format!("port {}", port));
+        }
+        if !self.keys.is_empty() {
+            parts.push(format!("key {}", self.keys.join(", ")));
+        }
+        if let Some(ref agent) = self.identity_agent {
+            parts.push(format!("agent {}", agent));
+        }
+        if let Some(ref jump) = self.jump {
+            parts.push(format!("jump {}", jump));
+        }
+        if let Some(secs) = self.connect_timeout {
+            parts.push(format!("connect timeout {}s", secs));
+        }
+        if let Some(interval) = self.keepalive_interval {
+            parts.push(format!("keepalive every {}s", interval));
+        }
+        if let Some(count) = self.keepalive_count {
+            parts.push(format!("keepalive count {}", count));
+        }
+        if let Some(compression) = self.compression {
+            parts.push(if compression { "compression" } else { "no compression" }.to_string());
+        }
+        if let Some(family) = self.address_family {
+            parts.push(format!("address family {}", family.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()));
+        }
+        if let Some(checking) = self.host_key_checking {
+            parts.push(format!("host key checking {}", checking.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()));
+        }
+        parts.join(", ")
+    }
+}
+
+/// How connections to a profile with a pool pick the machine.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum PoolStrategy {
+    /// One machine after the other, remembered between runs
+    RoundRobin,
+    Random,
+}
+
+impl PoolStrategy {
+    fn name(self) -> &'static str {
+        match self {
+            PoolStrategy::RoundRobin => "round-robin",
+            PoolStrategy::Random => "random",
+        }
+    }
+}
+
+/// What Vault's SSH secrets engine hands out for a profile.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum VaultMode {
+    /// A certificate for the profile's key, signed by Vault's CA
+    Sign,
+    /// A one-time password, checked on the host by vault-ssh-helper
+    Otp,
+}
+
+/// How much damage a mistake on the host can do. Policies keyed by
+/// `risk:<level>` attach behavior to it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Risk {
+    Low,
+    Medium,
+    High,
+}
+
+impl Risk {
+    fn name(self) -> &'static str {
+        match self {
+            Risk::Low => "low",
+            Risk::Medium => "medium",
+            Risk::High => "high",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Config {
     #[serde(default)]
     profiles: Profiles,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    policies: HashMap<String, Policy>,
+    /// Client used for profiles without their own `ssh_command`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssh_command: Option<String>,
+    /// Terminal emulator for profiles without their own `terminal`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    terminal: Option<String>,
+    /// Connection settings for all profiles that don't set them.
+    #[serde(default, skip_serializing_if = "Defaults::is_empty")]
+    defaults: Defaults,
+    /// Connection settings for profiles with the tag, before `defaults`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    tag_defaults: HashMap<String, Defaults>,
     updated_at: i64,
 }
 
@@ -32,6 +685,11 @@ impl Default for Config {
     fn default() -> Self {
         Config {
             profiles: HashMap::new(),
+            policies: HashMap::new(),
+            ssh_command: None,
+            terminal: None,
+            defaults: Defaults::default(),
+            tag_defaults: HashMap::new(),
             updated_at: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
@@ -43,16 +701,26 @@ impl Default for Config {
 struct Masuk {
     config: Config,
     config_path: PathBuf,
+    /// `~/.config/masuk`, shared by all config files and contexts.
+    state_dir: PathBuf,
 }
 
 impl Masuk {
     fn new() -> Result<Self> {
         let home = dirs::home_dir().ok_or_else(|| anyhow!("Could not determine home directory"))?;
-        let config_path = home.join(".config/masuk/config.json");
+        let state_dir = home.join(".config/masuk");
+        let config_path = match env::var_os("MASUK_CONFIG").filter(|path| !path.is_empty()) {
+            Some(path) => PathBuf::from(path),
+            None => match env::var("MASUK_CONTEXT").ok().filter(|context| !context.is_empty()) {
+                Some(context) => context_config(&state_dir, &context)?,
+                None => state_dir.join("config.json"),
+            },
+        };
 
         let mut masuk = Masuk {
             config: Config::default(),
             config_path,
+            state_dir,
         };
 
         masuk.load_config()?;
@@ -97,204 +765,8158 @@ impl Masuk {
         Ok(())
     }
 
-    fn add(&mut self, profile: &str, host: &str, user: Option<String>, port: Option<u16>, key: Option<String>) -> Result<()> {
+    fn add(&mut self, profile: &str, host: &str, settings: ProfileArgs) -> Result<()> {
         // Add to config
-        let host_config = HostConfig {
+        let mut host_config = HostConfig {
             host: host.to_string(),
-            user,
-            port,
-            key,
+            created_at: Some(unix_time() as i64),
+            ..Default::default()
         };
+        settings.apply(&mut host_config);
+        host_config.touch();
 
-        // Build display string
-        let mut display = String::new();
-        if let Some(ref u) = host_config.user {
-            display.push_str(&format!("{}@", u));
-        }
-        display.push_str(&host_config.host);
-        if let Some(p) = host_config.port {
-            display.push_str(&format!(":{}", p));
-        }
-        if let Some(ref k) = host_config.key {
-            display.push_str(&format!(" (key: {})", k));
-        }
+        let display = host_config.describe();
 
         self.config.profiles.insert(profile.to_string(), host_config);
         self.save_config()?;
 
-        println!("✓ Added profile '{}' → {}", profile, display);
+        out().success(&format!("Added profile '{}' → {}", profile, display));
 
         Ok(())
     }
 
-    fn connect(&self, profile: &str) -> Result<()> {
+    fn set(&mut self, profile: &str, host: Option<String>, settings: ProfileArgs) -> Result<()> {
         let host_config = self
+            .config
+            .profiles
+            .get_mut(profile)
+            .ok_or_else(|| anyhow!("Profile '{}' not found. Use 'masuk add {} -h <host>' to create it.", profile, profile))?;
+
+        if let Some(host) = host {
+            host_config.host = host;
+        }
+        settings.apply(host_config);
+        host_config.touch();
+
+        let display = host_config.describe();
+        self.save_config()?;
+
+        out().success(&format!("Updated profile '{}' → {}", profile, display));
+
+        Ok(())
+    }
+
+    /// Looks up a profile and expands it for use.
+    fn resolve(&self, profile: &str) -> Result<HostConfig> {
+        let stored = self
             .config
             .profiles
             .get(profile)
             .ok_or_else(|| anyhow!("Profile '{}' not found. Use 'masuk ls' to see available profiles.", profile))?;
+        self.with_defaults(profile, stored)
+            .0
+            .expanded()
+            .with_context(|| format!("Failed to resolve profile '{}'", profile))
+    }
 
-        // Build display string
-        let mut display = String::new();
-        if let Some(ref u) = host_config.user {
-            display.push_str(&format!("{}@", u));
+    /// The profile with its unset connection settings filled in. Every
+    /// setting is taken from the first place that has it:
+    ///
+    /// 1. the profile itself
+    /// 2. the defaults of its tags, in alphabetical order of the tags
+    /// 3. the global defaults
+    /// 4. ssh's own default (ssh_config, port 22, the local user name)
+    ///
+    /// Also returns where each filled-in setting came from, by label.
+    fn with_defaults(&self, profile: &str, stored: &HostConfig) -> (HostConfig, BTreeMap<&'static str, String>) {
+        let mut host_config = stored.clone();
+        let mut sources = BTreeMap::new();
+        let mut tags = stored.tags.clone();
+        tags.sort();
+        for tag in &tags {
+            if let Some(defaults) = self.config.tag_defaults.get(tag) {
+                for label in defaults.fill(&mut host_config, profile) {
+                    sources.insert(label, format!("tag '{}'", tag));
+                }
+            }
         }
-        display.push_str(&host_config.host);
-        if let Some(p) = host_config.port {
-            display.push_str(&format!(":{}", p));
+        for label in self.config.defaults.fill(&mut host_config, profile) {
+            sources.insert(label, "global defaults".to_string());
         }
+        (host_config, sources)
+    }
 
-        println!("Connecting to {} ({})...", profile, display);
+    fn connect(&self, profile: &str, options: &ConnectOptions) -> Result<()> {
+        self.session(profile, options, None)
+    }
 
-        // Build SSH command
-        let mut cmd = Command::new("ssh");
+    /// Connects to every profile whose name matches `pattern`, one session
+    /// after another or side by side in tmux panes. Panes run masuk itself
+    /// with `args`, the connect options as given on the command line.
+    fn connect_many(
+        &self,
+        pattern: &str,
+        options: &ConnectOptions,
+        sequential: bool,
+        panes: bool,
+        args: &[String],
+    ) -> Result<()> {
+        let mut matched: Vec<&String> = self.config.profiles.keys().filter(|name| glob_match(pattern, name)).collect();
+        matched.sort_by(|a, b| natural_cmp(a, b));
 
-        // Add port if specified
-        if let Some(port) = host_config.port {
-            cmd.arg("-p").arg(port.to_string());
+        match matched.as_slice() {
+            [] => return Err(anyhow!("No profiles match '{}'. Use 'masuk ls' to see available profiles.", pattern)),
+            [profile] => return self.connect(profile, options),
+            _ if !panes && !sequential => {
+                let names: Vec<&str> = matched.iter().map(|name| name.as_str()).collect();
+                return Err(anyhow!(
+                    "'{}' matches {} profiles ({}). Use --sequential or --panes to connect to all of them.",
+                    pattern,
+                    matched.len(),
+                    names.join(", ")
+                ));
+            }
+            _ => {}
         }
 
-        // Add key if specified
-        if let Some(ref key) = host_config.key {
-            cmd.arg("-i").arg(key);
+        self.check_fleet(&matched.iter().map(|name| name.as_str()).collect::<Vec<_>>())?;
+
+        if panes {
+            let masuk = env::current_exe().context("Failed to find the masuk executable")?;
+            let commands: Vec<String> = matched
+                .iter()
+                .map(|profile| {
+                    let mut command = vec![masuk.display().to_string(), "connect".to_string(), profile.to_string()];
+                    command.extend(args.iter().cloned());
+                    shell_join(&command)
+                })
+                .collect();
+            return tmux_panes(pattern, &commands);
         }
 
-        // Build the target (user@host or just host)
-        let target = if let Some(ref user) = host_config.user {
-            format!("{}@{}", user, host_config.host)
-        } else {
-            host_config.host.clone()
+        let mut failed = Vec::new();
+        for (i, profile) in matched.iter().enumerate() {
+            out().info(&format!("── {} ({}/{}) ──", profile, i + 1, matched.len()));
+            match self.connect(profile, options) {
+                Ok(()) => {}
+                // The session itself ending with an error is not masuk's business
+                Err(e) if e.is::<ChildExit>() => {}
+                Err(e) if e.is::<interrupt::Interrupted>() => return Err(e),
+                Err(e) => {
+                    eprintln!("Error: {:#}", e);
+                    failed.push(profile.as_str());
+                }
+            }
+        }
+        if !failed.is_empty() {
+            return Err(anyhow!("Could not connect to {}", failed.join(", ")));
+        }
+        Ok(())
+    }
+
+    /// Runs a command on the host, with everything `connect` applies.
+    /// A single argument is passed to the remote shell as is, so it may use
+    /// pipes and `&&`; several are quoted word by word.
+    fn exec(&self, profile: &str, options: &ConnectOptions, command: &[String], tty: bool) -> Result<()> {
+        let remote = match command {
+            [snippet] => snippet.clone(),
+            words => shell_join(words),
         };
+        self.session(profile, options, Some((remote, tty)))
+    }
 
-        cmd.arg(target);
+    /// Checks that policies allow running on all of `profiles` at once and
+    /// asks once for those whose policies want confirmation. The returned
+    /// options don't ask again.
+    fn confirm_fleet(&self, profiles: &[String], options: &ConnectOptions) -> Result<ConnectOptions> {
+        let names: Vec<&str> = profiles.iter().map(String::as_str).collect();
+        self.check_fleet(&names)?;
+        let confirming: Vec<&str> = names
+            .iter()
+            .copied()
+            .filter(|name| self.policy_requiring(&self.config.profiles[*name], |policy| policy.confirm).is_some())
+            .collect();
+        let mut options = options.clone();
+        if !confirming.is_empty()
+            && !options.yes
+            && !confirm(&format!("Policies ask for confirmation. Run the command on {}?", confirming.join(", ")))?
+        {
+            return Err(anyhow!("Not confirmed, nothing was run"));
+        }
+        // Asked once for all of them, not again from every thread
+        options.yes = true;
+        Ok(options)
+    }
 
-        let status = cmd.status()
-            .context("Failed to execute SSH command")?;
+    /// Runs a command on several profiles in parallel, each line of output
+    /// prefixed with the profile. Starts are spread `stagger` seconds apart,
+    /// each delayed by up to `jitter` seconds more, so a fleet isn't hit all
+    /// at once.
+    fn exec_group(
+        &self,
+        profiles: &[String],
+        options: &ConnectOptions,
+        command: &[String],
+        stagger: u64,
+        jitter: u64,
+    ) -> Result<()> {
+        let options = &self.confirm_fleet(profiles, options)?;
 
-        if !status.success() {
-            return Err(anyhow!("SSH connection failed"));
-        }
+        let width = profiles.iter().map(|name| name.len()).max().unwrap_or(0);
+        let mut seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos() as u64
+            ^ (std::process::id() as u64) << 32;
 
+        let results: Vec<(String, Result<()>)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = profiles
+                .iter()
+                .enumerate()
+                .map(|(i, profile)| {
+                    let mut delay = Duration::from_secs(stagger * i as u64);
+                    if jitter > 0 {
+                        delay += Duration::from_millis(random(&mut seed) % (jitter * 1000));
+                    }
+                    let mut options = options.clone();
+                    options.output_prefix = Some(format!("{:width$} | ", profile, width = width));
+                    scope.spawn(move || {
+                        let start = Instant::now() + delay;
+                        while Instant::now() < start && !interrupt::requested() {
+                            std::thread::sleep(Duration::from_millis(50));
+                        }
+                        interrupt::check()?;
+                        self.exec(profile, &options, command, false)
+                    })
+                })
+                .collect();
+            profiles
+                .iter()
+                .cloned()
+                .zip(handles.into_iter().map(|handle| handle.join().expect("exec thread panicked")))
+                .collect()
+        });
+
+        interrupt::check()?;
+        let mut failed = 0;
+        for (profile, result) in &results {
+            match result {
+                Ok(()) => {}
+                Err(e) => {
+                    failed += 1;
+                    eprintln!("{}", plain(&format!("✗ {}: {:#}", profile, e)));
+                }
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow!("The command failed on {} of {} profiles", failed, results.len()));
+        }
+        out().success(&format!("Ran on {} profile{}", results.len(), if results.len() == 1 { "" } else { "s" }));
         Ok(())
     }
 
-    fn list(&self) -> Result<()> {
-        if self.config.profiles.is_empty() {
-            println!("No profiles configured yet. Use 'masuk add <profile> -h <host>' to add one.");
-            return Ok(());
+    /// Shows the last `lines` lines of `files` on every profile. With more
+    /// than one profile they run in parallel and their lines are merged,
+    /// each starting with the profile (in its own color on a terminal).
+    fn tail(&self, profiles: &[String], files: &[String], lines: u32, follow: bool, options: &ConnectOptions) -> Result<()> {
+        let mut options = options.clone();
+        if follow {
+            // Reconnecting is done by tail_one, which doesn't repeat lines
+            options.retry = Some(false);
+            options.max_retries = None;
+            options.backoff = None;
+        }
+        if let [profile] = profiles {
+            return self.tail_one(profile, &options, files, lines, follow);
         }
 
-        println!("\nConfigured profiles:\n");
-        let mut profiles: Vec<_> = self.config.profiles.iter().collect();
-        profiles.sort_by_key(|(name, _)| *name);
+        let options = &self.confirm_fleet(profiles, &options)?;
+        let width = profiles.iter().map(|name| name.len()).max().unwrap_or(0);
+        let colored = std::io::IsTerminal::is_terminal(&std::io::stdout())
+            && env::var_os("NO_COLOR").is_none()
+            && env::var("TERM").map_or(true, |term| term != "dumb");
+        let results: Vec<(String, Result<()>)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = profiles
+                .iter()
+                .enumerate()
+                .map(|(i, profile)| {
+                    let mut options = options.clone();
+                    let name = format!("{:width$}", profile, width = width);
+                    options.output_prefix = Some(if colored {
+                        format!("\x1b[{}m{}\x1b[0m | ", PREFIX_COLORS[i % PREFIX_COLORS.len()], name)
+                    } else {
+                        format!("{} | ", name)
+                    });
+                    scope.spawn(move || self.tail_one(profile, &options, files, lines, follow))
+                })
+                .collect();
+            profiles
+                .iter()
+                .cloned()
+                .zip(handles.into_iter().map(|handle| handle.join().expect("tail thread panicked")))
+                .collect()
+        });
 
-        for (profile, host_config) in profiles {
-            let mut display = String::new();
-            if let Some(ref u) = host_config.user {
-                display.push_str(&format!("{}@", u));
+        interrupt::check()?;
+        let mut failed = 0;
+        for (profile, result) in &results {
+            if let Err(e) = result {
+                failed += 1;
+                eprintln!("{}", plain(&format!("✗ {}: {:#}", profile, e)));
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow!("Tailing failed on {} of {} profiles", failed, results.len()));
+        }
+        Ok(())
+    }
+
+    /// Tails `files` on one profile. When following and the connection
+    /// drops, reconnects until interrupted, continuing with new lines only.
+    fn tail_one(&self, profile: &str, options: &ConnectOptions, files: &[String], lines: u32, follow: bool) -> Result<()> {
+        let mut lines = lines;
+        loop {
+            let result = self.session(profile, options, Some((tail_command(files, lines, follow), false)));
+            let dropped = matches!(result.as_ref().map_err(|e| e.downcast_ref()), Err(Some(ChildExit(255))));
+            if !follow || !dropped || interrupt::requested() {
+                return result;
             }
-            display.push_str(&host_config.host);
-            if let Some(p) = host_config.port {
-                display.push_str(&format!(":{}", p));
+
+            eprintln!(
+                "{}",
+                plain(&format!(
+                    "{}⚠ Connection to {} dropped, reconnecting in {}...",
+                    options.output_prefix.as_deref().unwrap_or(""),
+                    profile,
+                    format_duration(TAIL_RECONNECT_DELAY)
+                ))
+            );
+            let deadline = Instant::now() + Duration::from_secs(TAIL_RECONNECT_DELAY);
+            while Instant::now() < deadline && !interrupt::requested() {
+                std::thread::sleep(Duration::from_millis(100));
             }
-            if let Some(ref k) = host_config.key {
-                display.push_str(&format!(" (key: {})", k));
+            if interrupt::requested() {
+                return result;
             }
-            println!("  {} → {}", profile, display);
+            // What was shown before the drop isn't shown again
+            lines = 0;
         }
-        println!();
-        Ok(())
     }
 
-    fn remove(&mut self, profile: &str) -> Result<()> {
-        if self.config.profiles.remove(profile).is_none() {
-            return Err(anyhow!("Profile '{}' not found", profile));
+    /// Runs systemctl on all profiles at once and prints the unit's state on
+    /// each as a table.
+    fn svc(&self, profiles: &[String], action: SvcAction, unit: &str, yes: bool) -> Result<()> {
+        let names: Vec<&str> = profiles.iter().map(String::as_str).collect();
+        if names.len() > 1 {
+            self.check_fleet(&names)?;
+        }
+        if action == SvcAction::Restart && !yes {
+            let confirming: Vec<&str> = names
+                .iter()
+                .copied()
+                .filter(|name| self.policy_requiring(&self.config.profiles[*name], |policy| policy.confirm).is_some())
+                .collect();
+            if !confirming.is_empty()
+                && !confirm(&format!("Policies ask for confirmation. Restart {} on {}?", unit, confirming.join(", ")))?
+            {
+                return Err(anyhow!("Not confirmed, nothing was restarted"));
+            }
         }
 
-        self.save_config()?;
-        println!("✓ Removed profile '{}'", profile);
+        let results: Vec<Result<BTreeMap<String, String>>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = profiles
+                .iter()
+                .map(|profile| scope.spawn(move || self.unit_state(profile, action, unit)))
+                .collect();
+            handles.into_iter().map(|handle| handle.join().expect("svc thread panicked")).collect()
+        });
+        interrupt::check()?;
+
+        let mut rows = Vec::new();
+        let mut data = Vec::new();
+        let mut failed = 0;
+        for (profile, result) in profiles.iter().zip(results) {
+            match result {
+                Ok(state) => {
+                    let property = |name: &str| state.get(name).cloned().unwrap_or_else(|| "-".to_string());
+                    rows.push(vec![
+                        profile.clone(),
+                        property("LoadState"),
+                        format!("{} ({})", property("ActiveState"), property("SubState")),
+                        property("UnitFileState"),
+                        property("StateChangeTimestamp"),
+                    ]);
+                    let mut entry = json!({ "profile": profile, "unit": unit });
+                    for (name, value) in &state {
+                        entry[name] = json!(value);
+                    }
+                    data.push(entry);
+                }
+                Err(e) => {
+                    failed += 1;
+                    rows.push(vec![profile.clone(), "-".to_string(), format!("error: {:#}", e), "-".to_string(), "-".to_string()]);
+                    data.push(json!({ "profile": profile, "unit": unit, "error": format!("{:#}", e) }));
+                }
+            }
+        }
+        out().table(&["PROFILE", "LOADED", "ACTIVE", "ENABLED", "SINCE"], &rows, &json!(data));
 
+        if failed > 0 {
+            return Err(anyhow!("{} {} failed on {} of {} profiles", match action {
+                SvcAction::Status => "Checking",
+                SvcAction::Restart => "Restarting",
+            }, unit, failed, profiles.len()));
+        }
         Ok(())
     }
-}
 
-#[derive(Parser)]
-#[command(name = "masuk")]
-#[command(about = "SSH host and port manager", long_about = None)]
-struct Cli {
-    #[command(subcommand)]
-    command: Commands,
-}
+    /// The unit's `systemctl show` properties after running the action.
+    /// Restarts use sudo unless logged in as root, with the profile's sudo
+    /// password when it has one.
+    fn unit_state(&self, profile: &str, action: SvcAction, unit: &str) -> Result<BTreeMap<String, String>> {
+        let host_config = self.resolve(profile)?;
+        let unit = shell_quote(unit);
+        let show = format!("systemctl show --property={} -- {}", UNIT_PROPERTIES, unit);
+        let mut input = None;
+        let script = match action {
+            SvcAction::Status => show,
+            SvcAction::Restart => {
+                let sudo = match (&host_config.user, &host_config.sudo_password) {
+                    (Some(user), _) if user == "root" => "",
+                    (_, Some(reference)) => {
+                        input = Some(masuk::secrets::Registry::default().resolve(reference)? + "\n");
+                        "sudo "
+                    }
+                    _ => "sudo -n ",
+                };
+                let script = format!("{}systemctl restart -- {} || exit $?; {}", sudo, unit, show);
+                if input.is_some() {
+                    format!("{}{}", SUDO_PREAMBLE, script)
+                } else {
+                    script
+                }
+            }
+        };
 
-#[derive(Subcommand)]
+        let mut command = self.ssh_command(profile, &host_config)?;
+        command.insert(1, "-T".to_string());
+        command.push(script);
+        let mut child = Command::new(&command[0])
+            .args(&command[1..])
+            .envs(&host_config.env)
+            .envs(askpass_env(&host_config))
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to execute SSH command")?;
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+            stdin.write_all(input.as_bytes())?;
+        }
+        let output = child.wait_with_output().context("Failed to execute SSH command")?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let reason = stderr.lines().rfind(|line| !line.trim().is_empty()).unwrap_or("no output");
+            return Err(match exit_code(output.status) {
+                255 => anyhow!("connection failed: {}", reason.trim()),
+                code => anyhow!("systemctl exited with {}: {}", code, reason.trim()),
+            });
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+            .filter(|(_, value)| !value.is_empty())
+            .collect())
+    }
+
+    /// An interactive session, or a remote command with an optional tty.
+    /// Notices go to stderr for commands so their output can be piped.
+    fn session(&self, profile: &str, options: &ConnectOptions, remote: Option<(String, bool)>) -> Result<()> {
+        let quiet = remote.is_some();
+        let notice = |message: String| {
+            if quiet {
+                eprintln!("{}", plain(&message));
+            } else {
+                out().info(&message);
+            }
+        };
+
+        let mut host_config = self.resolve(profile)?;
+        options.apply(&mut host_config);
+        if !host_config.pool.is_empty() && options.host.is_none() {
+            let host = self.pool_member(profile, &host_config, !options.dry_run && !options.copy)?;
+            notice(format!("Using {} from the pool of {}", host, profile));
+            host_config.host = host;
+        }
+        if !host_config.failover.is_empty() && options.host.is_none() {
+            let host = self.reachable_host(profile, &host_config)?;
+            if host != host_config.host {
+                notice(format!("{} doesn't answer, using {}", host_config.host, host));
+                host_config.host = host;
+            }
+        }
+        if host_config.vault_role.is_some() {
+            if let Some(message) = self.vault_credentials(profile, &mut host_config, !options.dry_run && !options.copy)? {
+                notice(message);
+            }
+        }
+        let host_config = &host_config;
+        let display = host_config.address();
+
+        // Policies are checked before anything is printed or executed so an
+        // unwritable log directory never results in an unrecorded session
+        let recording = self.session_recording(profile, host_config)?;
+        // Telnet, serial, SSM and local container sessions share the workflow,
+        // but none of ssh's options
+        let backend = host_config.backend.unwrap_or(Backend::Ssh);
+        let terminal_only = matches!(backend, Backend::Telnet | Backend::Serial);
+        let local_client = matches!(
+            backend,
+            Backend::Kubernetes | Backend::AwsSsm | Backend::Gcloud | Backend::Incus | Backend::Vagrant
+        )
+            || backend == Backend::Docker && host_config.host.is_empty();
+        let without_ssh = terminal_only || local_client;
+        if terminal_only && remote.is_some() {
+            return Err(anyhow!("Profile '{}' uses {}, which can't run commands", profile, backend.name()));
+        }
+        if without_ssh && options.sandbox {
+            return Err(anyhow!("--sandbox only works for ssh, profile '{}' uses {}", profile, backend.name()));
+        }
+        // ssh applies -A after the ForwardAgent=no of the sandbox, and the later one wins
+        if options.sandbox && forwards_agent(&host_config.extra_args) {
+            return Err(anyhow!(
+                "--sandbox keeps the agent unexposed, but the extra args of profile '{}' forward it",
+                profile
+            ));
+        }
+        let mut command = match backend {
+            Backend::Telnet => telnet_command(host_config)?,
+            Backend::Serial => serial_command(profile, host_config)?,
+            // Filled in below, once the command for the container is known
+            _ if local_client => Vec::new(),
+            _ => self.ssh_command(profile, host_config)?,
+        };
+
+        if host_config.clipboard {
+            if without_ssh || backend == Backend::Mosh {
+                return Err(anyhow!("The clipboard relay needs ssh, profile '{}' uses {}", profile, backend.name()));
+            }
+            let forward = format!(
+                "127.0.0.1:{}:127.0.0.1:{}",
+                host_config.clipboard_port.unwrap_or(DEFAULT_CLIPBOARD_PORT),
+                clipboard::relay()?
+            );
+            command.splice(1..1, ["-R".to_string(), forward]);
+        }
+
+        let sandbox = if options.sandbox {
+            let sandbox = Sandbox::create()?;
+            command.splice(1..1, sandbox.ssh_options());
+            Some(sandbox)
+        } else {
+            None
+        };
+
+        // The profile's own command needs a tty, it replaces an interactive shell
+        let mut remote = remote.or_else(|| match host_config.remote_command {
+            Some(ref remote_command) if !options.no_command && !terminal_only => Some((remote_command.clone(), true)),
+            _ => None,
+        });
+        // Without a tty, sudo can't prompt. The password arrives as the first
+        // line of stdin instead, and sudo in the command reads it from there.
+        let sudo_password = match remote {
+            Some((ref mut remote_command, false)) if host_config.sudo_password.is_some() => {
+                *remote_command = format!("{}{}", SUDO_PREAMBLE, remote_command);
+                host_config.sudo_password.as_deref()
+            }
+            _ => None,
+        };
+        if options.verbose > 0 && !without_ssh {
+            command.insert(1, format!("-{}", "v".repeat(options.verbose.min(3) as usize)));
+        }
+
+        // mosh is for interactive sessions, commands without a tty keep ssh
+        let interactive = remote.as_ref().is_none_or(|(_, tty)| *tty);
+        if host_config.backend == Some(Backend::Mosh) && interactive {
+            command = mosh_command(host_config, command, remote.as_ref().map(|(remote_command, _)| remote_command.as_str()));
+        } else if backend == Backend::Kubernetes {
+            command = kubectl_command(profile, host_config, remote.as_ref())?;
+        } else if backend == Backend::Incus {
+            command = incus_command(profile, host_config, remote.as_ref())?;
+        } else if backend == Backend::Vagrant {
+            command = vagrant_command(profile, host_config, remote.as_ref())?;
+        } else if backend == Backend::Gcloud {
+            command = gcloud_command(profile, host_config, remote.as_ref())?;
+        } else if backend == Backend::AwsSsm {
+            command = ssm_command(profile, host_config, remote.as_ref().map(|(remote_command, _)| remote_command.as_str()))?;
+        } else if backend == Backend::Docker {
+            let docker = docker_command(profile, host_config, remote.as_ref())?;
+            if local_client {
+                command = docker;
+            } else {
+                if interactive {
+                    command.insert(1, "-t".to_string());
+                }
+                command.push(shell_join(&docker));
+            }
+        } else if let Some((ref remote_command, tty)) = remote {
+            if tty {
+                command.insert(1, "-t".to_string());
+            }
+            command.push(remote_command.clone());
+        }
+
+        command = isolate(host_config, command);
+
+        if options.dry_run || options.copy {
+            if let Some((recorder, ref log_file)) = recording {
+                command = recorder.wrap(&command, log_file);
+            }
+            let mut line: Vec<String> = host_config
+                .env
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, shell_quote(value)))
+                .collect();
+            line.push(shell_join(&command));
+            let line = line.join(" ");
+
+            if options.copy {
+                let via = clipboard::copy(&line)?;
+                eprintln!("{}", plain(&format!("✓ Copied the command for {} to the clipboard ({})", profile, via)));
+            } else {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+
+        if !options.yes {
+            self.confirm_policy(profile, host_config, if quiet { "Run the command on" } else { "Connect to" })?;
+        }
+
+        if !quiet {
+            out().info(&format!("Connecting to {} ({})...", profile, display));
+        }
+        if let Some(ref sandbox) = sandbox {
+            notice(format!("Sandboxed: using throwaway known_hosts {}", sandbox.known_hosts.display()));
+        }
+
+        let connection = command;
+        let record = |attempt: u32| match recording {
+            Some((recorder, ref log_file)) => {
+                // Every attempt gets its own log, `script` would overwrite it
+                let log_file = if attempt == 0 {
+                    log_file.clone()
+                } else {
+                    let stem = log_file.file_stem().unwrap_or_default().to_string_lossy();
+                    log_file.with_file_name(format!("{}-retry{}.{}", stem, attempt, recorder.extension()))
+                };
+                notice(format!("Recording session to {}", log_file.display()));
+                recorder.wrap(&connection, &log_file)
+            }
+            None => connection.clone(),
+        };
+
+        if options.wake {
+            self.wake(profile, host_config)?;
+            // The port can only be watched from here when ssh connects to
+            // it directly and it's open without knocking
+            let direct = host_config.jump.is_none()
+                && host_config.via.is_empty()
+                && host_config.socks_proxy.is_none()
+                && host_config.proxy_command.is_none()
+                && host_config.knock.is_empty();
+            if direct && !without_ssh {
+                let port = host_config.port.unwrap_or(22);
+                notice(format!("Sent a Wake-on-LAN packet to {}, waiting for port {}...", profile, port));
+                let waited = wol::wait_for_port(&host_config.host, port, WAKE_TIMEOUT)?;
+                notice(format!("{} is up after {}s", profile, waited.as_secs()));
+            } else {
+                notice(format!("Sent a Wake-on-LAN packet to {}, connecting right away", profile));
+            }
+        }
+
+        if !host_config.knock.is_empty() && !without_ssh {
+            let delay = Duration::from_millis(host_config.knock_delay.unwrap_or(knock::DEFAULT_DELAY_MS));
+            knock::knock(
+                &host_config.host,
+                &host_config.knock,
+                host_config.knock_protocol.unwrap_or(knock::Protocol::Tcp),
+                delay,
+            )?;
+        }
+
+        if !options.no_preflight && !without_ssh {
+            for problem in self.preflight_problems(profile, host_config)? {
+                notice(format!("⚠ {}: {}", profile, problem));
+            }
+        }
+
+        if let Some(ref hook) = host_config.pre_connect {
+            let status = run_hook(hook, profile, host_config, &[])?;
+            if !status.success() {
+                return Err(anyhow!("The pre_connect hook of '{}' failed ({}), not connecting", profile, status));
+            }
+        }
+
+        let input = match sudo_password {
+            Some(reference) => Some(masuk::secrets::Registry::default().resolve(reference)? + "\n"),
+            None => None,
+        };
+
+        // Nothing between the connect and disconnect events may return early,
+        // every session that is reported as started has to be reported as ended
+        let webhooks = self.webhooks(host_config);
+        let started = Instant::now();
+        send_webhooks(&webhooks, &json!({
+            "event": "connect",
+            "profile": profile,
+            "host": host_config.host,
+            "remote_user": host_config.user,
+            "user": local_user(),
+            "timestamp": unix_time(),
+        }));
+
+        let max_retries = if host_config.retry { host_config.max_retries.unwrap_or(5) } else { 0 };
+        let backoff = host_config.retry_backoff.unwrap_or(5);
+        let mut attempt = 0;
+        let result = loop {
+            let command = record(attempt);
+            if options.verbose > 0 {
+                eprintln!("masuk: running {}", shell_join(&command));
+            }
+            let result = run_watching_timeout(
+                Command::new(&command[0])
+                    .args(&command[1..])
+                    .envs(&host_config.env)
+                    .envs(askpass_env(host_config)),
+                input.as_deref(),
+                options.output_prefix.as_deref(),
+            )
+            .context("Failed to execute SSH command");
+            let dropped = result.as_ref().is_ok_and(|(status, _)| exit_code(*status) == 255);
+            if !dropped || attempt >= max_retries || interrupt::requested() {
+                break result;
+            }
+
+            attempt += 1;
+            let delay = backoff.saturating_mul(1 << (attempt - 1).min(16)).min(300);
+            notice(format!(
+                "Connection to {} failed or dropped, reconnecting in {} (attempt {} of {})...",
+                profile,
+                format_duration(delay),
+                attempt,
+                max_retries
+            ));
+            let deadline = Instant::now() + Duration::from_secs(delay);
+            while Instant::now() < deadline && !interrupt::requested() {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            if interrupt::requested() {
+                break result;
+            }
+        };
+        let timed_out = result.as_ref().is_ok_and(|(_, timed_out)| *timed_out);
+        let status = result.map(|(status, _)| status);
+
+        drop(sandbox);
+
+        send_webhooks(&webhooks, &json!({
+            "event": "disconnect",
+            "profile": profile,
+            "host": host_config.host,
+            "remote_user": host_config.user,
+            "user": local_user(),
+            "timestamp": unix_time(),
+            "duration_secs": started.elapsed().as_secs(),
+            "exit_code": status.as_ref().ok().and_then(|s| s.code()),
+        }));
+
+        if let Some(ref hook) = host_config.post_connect {
+            let exit = status.as_ref().map(|s| exit_code(*s).to_string()).unwrap_or_default();
+            match run_hook(hook, profile, host_config, &[("MASUK_EXIT_CODE", exit)]) {
+                Ok(hook_status) if hook_status.success() => {}
+                Ok(hook_status) => eprintln!("Warning: the post_connect hook of '{}' failed ({})", profile, hook_status),
+                Err(e) => eprintln!("Warning: {:#}", e),
+            }
+        }
+
+        let status = status?;
+        let code = exit_code(status);
+
+        if options.summary {
+            notice(format!(
+                "Session to {} ended after {} (exit status {})",
+                profile,
+                format_duration(started.elapsed().as_secs()),
+                code
+            ));
+        }
+
+        interrupt::check()?;
+
+        // 255 is ssh's own failure, as opposed to the remote command's status
+        if code == 255 && timed_out {
+            match host_config.connect_timeout {
+                Some(secs) => eprintln!("Error: Connection to {} ({}) timed out after {}s", profile, display, secs),
+                None => eprintln!("Error: Connection to {} ({}) timed out", profile, display),
+            }
+        }
+        if code != 0 {
+            return Err(ChildExit(code).into());
+        }
+
+        Ok(())
+    }
+
+    /// Runs the profile's preflight checks on the host and returns what is
+    /// wrong, nothing when the profile has no checks.
+    fn preflight_problems(&self, profile: &str, host_config: &HostConfig) -> Result<Vec<String>> {
+        let mut checks = Vec::new();
+        for command in &host_config.requires {
+            let quoted = shell_quote(command);
+            checks.push(format!(
+                "command -v {} >/dev/null 2>&1 || echo {}",
+                quoted,
+                shell_quote(&format!("{} is not installed", command))
+            ));
+        }
+        if let Some(min) = host_config.min_disk_free {
+            checks.push(format!(
+                "df -P / | awk 'NR == 2 {{ free = 100 - $5; if (free < {}) print \"only \" free \"% disk space free on /\" }}'",
+                min
+            ));
+        }
+        if checks.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut command = self.ssh_command(profile, host_config)?;
+        command.insert(1, "-T".to_string());
+        command.push(checks.join("; "));
+        let output = Command::new(&command[0])
+            .args(&command[1..])
+            .envs(&host_config.env)
+            .envs(askpass_env(host_config))
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .context("Failed to execute SSH command")?;
+        if exit_code(output.status) == 255 {
+            return Ok(vec!["preflight checks could not run, the connection failed".to_string()]);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn preflight(&self, names: &[String], tags: &[String]) -> Result<()> {
+        let selected = self.select_profiles(names, tags)?;
+        let mut failed = 0;
+        for profile in &selected {
+            let host_config = self.resolve(profile)?;
+            if host_config.requires.is_empty() && host_config.min_disk_free.is_none() {
+                out().info(&format!("- {} has no preflight checks", profile));
+                continue;
+            }
+            let problems = self.preflight_problems(profile, &host_config)?;
+            interrupt::check()?;
+            if problems.is_empty() {
+                out().success(&format!("{} passed", profile));
+            } else {
+                failed += 1;
+                for problem in problems {
+                    println!("{}", plain(&format!("✗ {}: {}", profile, problem)));
+                }
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow!("{} of {} profiles failed preflight checks", failed, selected.len()));
+        }
+        Ok(())
+    }
+
+    /// Address of a SOCKS proxy given as `host:port`, a local port, or the
+    /// name of a profile that opens one.
+    fn socks_address(&self, proxy: &str) -> Result<String> {
+        if let Some(host_config) = self.config.profiles.get(proxy) {
+            let port = host_config
+                .socks_port
+                .ok_or_else(|| anyhow!("Profile '{}' opens no SOCKS proxy, set one with --socks-port", proxy))?;
+            return Ok(format!("127.0.0.1:{}", port));
+        }
+        if proxy.parse::<u16>().is_ok() {
+            return Ok(format!("127.0.0.1:{}", proxy));
+        }
+        if !proxy.contains(':') {
+            return Err(anyhow!("SOCKS proxy '{}' is neither host:port, a port nor a profile", proxy));
+        }
+        Ok(proxy.to_string())
+    }
+
+    /// Builds the ssh command line (program first) for a resolved profile.
+    fn ssh_command(&self, profile: &str, host_config: &HostConfig) -> Result<Vec<String>> {
+        if host_config.backend == Some(Backend::Serial) {
+            return Err(anyhow!("Profile '{}' is a serial console, it can't be reached over ssh", profile));
+        }
+        if matches!(
+            host_config.backend,
+            Some(Backend::Kubernetes | Backend::AwsSsm | Backend::Gcloud | Backend::Incus | Backend::Vagrant)
+        )
+            || host_config.backend == Some(Backend::Docker) && host_config.host.is_empty()
+        {
+            return Err(anyhow!("Profile '{}' is a local container, it can't be reached over ssh", profile));
+        }
+        let mut command = self.ssh_client(host_config)?;
+
+        // Multiplexed profiles become a master themselves when there's none.
+        // Other profiles still reuse masters started by `masuk warm`, and ssh
+        // connects directly when there's no master behind the path.
+        let sockets = self.sockets_dir();
+        if host_config.multiplex {
+            self.create_sockets_dir()?;
+            command.push("-o".to_string());
+            command.push("ControlMaster=auto".to_string());
+            command.push("-o".to_string());
+            command.push("ControlPersist=10m".to_string());
+        }
+        if sockets.is_dir() {
+            command.push("-o".to_string());
+            command.push(format!("ControlPath={}", sockets.join("%C").display()));
+        }
+
+        match host_config.address_family {
+            Some(AddressFamily::Inet) => command.push("-4".to_string()),
+            Some(AddressFamily::Inet6) => command.push("-6".to_string()),
+            // Explicitly set so a family from ssh_config doesn't apply
+            Some(AddressFamily::Any) => {
+                command.push("-o".to_string());
+                command.push("AddressFamily=any".to_string());
+            }
+            None => {}
+        }
+
+        match host_config.host_key_checking {
+            Some(HostKeyChecking::Strict) => {
+                command.push("-o".to_string());
+                command.push("StrictHostKeyChecking=yes".to_string());
+            }
+            Some(HostKeyChecking::AcceptNew) => {
+                command.push("-o".to_string());
+                command.push("StrictHostKeyChecking=accept-new".to_string());
+            }
+            // Keys of throwaway hosts would only pile up in known_hosts
+            Some(HostKeyChecking::Off) => {
+                command.push("-o".to_string());
+                command.push("StrictHostKeyChecking=no".to_string());
+                command.push("-o".to_string());
+                command.push("UserKnownHostsFile=/dev/null".to_string());
+            }
+            None => {}
+        }
+
+        // Add port if specified
+        if let Some(port) = host_config.port {
+            command.push("-p".to_string());
+            command.push(port.to_string());
+        }
+
+        // Add keys if specified. With several, ssh must only offer those, in
+        // order, instead of trying agent keys first.
+        for key in &host_config.keys {
+            command.push("-i".to_string());
+            command.push(key.clone());
+        }
+        if host_config.keys.len() > 1 {
+            command.push("-o".to_string());
+            command.push("IdentitiesOnly=yes".to_string());
+        }
+        if let Some(ref certificate) = host_config.certificate_file {
+            command.push("-o".to_string());
+            command.push(format!("CertificateFile={}", certificate));
+        }
+        if let Some(ref agent) = host_config.identity_agent {
+            command.push("-o".to_string());
+            command.push(format!("IdentityAgent={}", agent));
+        }
+        if host_config.password.is_some() {
+            // A wrong password would only be handed out again
+            command.push("-o".to_string());
+            command.push("NumberOfPasswordPrompts=1".to_string());
+        }
+        // After the host key checking options, so those win for --sandbox
+        // and host_key_checking=off
+        if let Some(ref known_hosts) = host_config.known_hosts_file {
+            command.push("-o".to_string());
+            command.push(format!("UserKnownHostsFile={}", known_hosts));
+        }
+
+        let hops = self.jump_chain(host_config, &mut vec![profile.to_string()])?;
+        if !hops.is_empty() {
+            let hops: Vec<_> = hops.into_iter().map(|hop| hop.address).collect();
+            command.push("-J".to_string());
+            command.push(hops.join(","));
+        }
+
+        if let Some(ref proxy) = host_config.socks_proxy {
+            if host_config.jump.is_some() || !host_config.via.is_empty() {
+                return Err(anyhow!("Profile '{}' can't use both a SOCKS proxy and jump hosts", profile));
+            }
+            command.push("-o".to_string());
+            command.push(format!("ProxyCommand=nc -X 5 -x {} %h %p", self.socks_address(proxy)?));
+        }
+        if let Some(ref proxy_command) = host_config.proxy_command {
+            if host_config.jump.is_some() || !host_config.via.is_empty() || host_config.socks_proxy.is_some() {
+                return Err(anyhow!(
+                    "Profile '{}' can't use a proxy command together with jump hosts or a SOCKS proxy",
+                    profile
+                ));
+            }
+            command.push("-o".to_string());
+            command.push(format!("ProxyCommand={}", proxy_command));
+        }
+        if let Some(port) = host_config.socks_port {
+            command.push("-D".to_string());
+            command.push(format!("127.0.0.1:{}", port));
+        }
+
+        if host_config.forward_agent {
+            command.push("-A".to_string());
+        }
+
+        if host_config.trusted_x11 {
+            command.push("-Y".to_string());
+        } else if host_config.x11 {
+            command.push("-X".to_string());
+        }
+
+        match host_config.compression {
+            Some(true) => command.push("-C".to_string()),
+            Some(false) => {
+                command.push("-o".to_string());
+                command.push("Compression=no".to_string());
+            }
+            None => {}
+        }
+
+        if let Some(interval) = host_config.keepalive_interval {
+            command.push("-o".to_string());
+            command.push(format!("ServerAliveInterval={}", interval));
+        }
+        if let Some(count) = host_config.keepalive_count {
+            command.push("-o".to_string());
+            command.push(format!("ServerAliveCountMax={}", count));
+        }
+
+        if let Some(secs) = host_config.connect_timeout {
+            command.push("-o".to_string());
+            command.push(format!("ConnectTimeout={}", secs));
+        }
+
+        for name in host_config.env.keys() {
+            command.push("-o".to_string());
+            command.push(format!("SendEnv={}", name));
+        }
+
+        command.extend(host_config.extra_args.iter().cloned());
+
+        // Build the target (user@host or just host)
+        let target = if let Some(ref user) = host_config.user {
+            format!("{}@{}", user, host_config.host)
+        } else {
+            host_config.host.clone()
+        };
+
+        command.push(target);
+        Ok(command)
+    }
+
+    /// Profiles as an ssh_config(5) fragment with one `Host` block each, so
+    /// plain ssh knows them by the same names. Profiles ssh can't reach on
+    /// its own (serial consoles, containers, cloud CLIs) are left out with
+    /// a comment.
+    fn ssh_config_fragment(&self, profiles: &BTreeMap<String, HostConfig>) -> Result<String> {
+        let mut fragment = String::from("# Generated by masuk\n");
+        for (name, host_config) in profiles {
+            let host_config = host_config
+                .expanded()
+                .with_context(|| format!("Failed to resolve profile '{}'", name))?;
+            fragment.push('\n');
+            let backend = host_config.backend.unwrap_or(Backend::Ssh);
+            if !matches!(backend, Backend::Ssh | Backend::Mosh) {
+                fragment.push_str(&format!("# {} uses {}, which ssh can't connect to\n", name, backend.name()));
+                continue;
+            }
+
+            let mut options: Vec<(&str, String)> = vec![("HostName", host_config.host.clone())];
+            if let Some(ref user) = host_config.user {
+                options.push(("User", user.clone()));
+            }
+            if let Some(port) = host_config.port {
+                options.push(("Port", port.to_string()));
+            }
+            for key in &host_config.keys {
+                options.push(("IdentityFile", config_quote(key)));
+            }
+            if host_config.keys.len() > 1 {
+                options.push(("IdentitiesOnly", "yes".to_string()));
+            }
+            if let Some(ref certificate) = host_config.certificate_file {
+                options.push(("CertificateFile", config_quote(certificate)));
+            }
+            if let Some(ref agent) = host_config.identity_agent {
+                options.push(("IdentityAgent", config_quote(agent)));
+            }
+            let hops: Vec<&str> = host_config.jump.iter().chain(&host_config.via).map(String::as_str).collect();
+            if !hops.is_empty() {
+                options.push(("ProxyJump", hops.join(",")));
+            }
+            if let Some(ref proxy) = host_config.socks_proxy {
+                options.push(("ProxyCommand", format!("nc -X 5 -x {} %h %p", self.socks_address(proxy)?)));
+            }
+            if let Some(ref proxy_command) = host_config.proxy_command {
+                options.push(("ProxyCommand", proxy_command.clone()));
+            }
+            if let Some(port) = host_config.socks_port {
+                options.push(("DynamicForward", format!("127.0.0.1:{}", port)));
+            }
+            if host_config.forward_agent {
+                options.push(("ForwardAgent", "yes".to_string()));
+            }
+            if host_config.trusted_x11 {
+                options.push(("ForwardX11", "yes".to_string()));
+                options.push(("ForwardX11Trusted", "yes".to_string()));
+            } else if host_config.x11 {
+                options.push(("ForwardX11", "yes".to_string()));
+            }
+            if let Some(compression) = host_config.compression {
+                options.push(("Compression", if compression { "yes" } else { "no" }.to_string()));
+            }
+            if let Some(interval) = host_config.keepalive_interval {
+                options.push(("ServerAliveInterval", interval.to_string()));
+            }
+            if let Some(count) = host_config.keepalive_count {
+                options.push(("ServerAliveCountMax", count.to_string()));
+            }
+            if let Some(secs) = host_config.connect_timeout {
+                options.push(("ConnectTimeout", secs.to_string()));
+            }
+            match host_config.address_family {
+                Some(AddressFamily::Any) => options.push(("AddressFamily", "any".to_string())),
+                Some(AddressFamily::Inet) => options.push(("AddressFamily", "inet".to_string())),
+                Some(AddressFamily::Inet6) => options.push(("AddressFamily", "inet6".to_string())),
+                None => {}
+            }
+            match host_config.host_key_checking {
+                Some(HostKeyChecking::Strict) => options.push(("StrictHostKeyChecking", "yes".to_string())),
+                Some(HostKeyChecking::AcceptNew) => options.push(("StrictHostKeyChecking", "accept-new".to_string())),
+                Some(HostKeyChecking::Off) => {
+                    options.push(("StrictHostKeyChecking", "no".to_string()));
+                    options.push(("UserKnownHostsFile", "/dev/null".to_string()));
+                }
+                None => {}
+            }
+            if let Some(ref known_hosts) = host_config.known_hosts_file {
+                if host_config.host_key_checking != Some(HostKeyChecking::Off) {
+                    options.push(("UserKnownHostsFile", config_quote(known_hosts)));
+                }
+            }
+            if let Some(ref remote_command) = host_config.remote_command {
+                options.push(("RemoteCommand", remote_command.clone()));
+                options.push(("RequestTTY", "yes".to_string()));
+            }
+
+            fragment.push_str(&format!("Host {}\n", name));
+            for (option, value) in options {
+                fragment.push_str(&format!("    {} {}\n", option, value));
+            }
+        }
+        Ok(fragment)
+    }
+
+    /// The client program and its own arguments, e.g. `autossh -M 0`.
+    fn ssh_client(&self, host_config: &HostConfig) -> Result<Vec<String>> {
+        let client = host_config
+            .ssh_command
+            .as_deref()
+            .or(self.config.ssh_command.as_deref())
+            .unwrap_or("ssh");
+        let client: Vec<String> = client.split_whitespace().map(expand_tilde).collect();
+        if client.is_empty() {
+            return Err(anyhow!("ssh_command is empty"));
+        }
+        Ok(client)
+    }
+
+    /// The sftp equivalent of [`Masuk::ssh_command`], without the target.
+    /// Options that only make sense for a login (forwardings, tty) are
+    /// dropped and `-p` becomes sftp's `-P`.
+    fn sftp_command(&self, profile: &str, host_config: &HostConfig) -> Result<(Vec<String>, String)> {
+        self.transfer_command("sftp", profile, host_config)
+    }
+
+    /// Like [`Masuk::sftp_command`], for scp.
+    fn scp_command(&self, profile: &str, host_config: &HostConfig) -> Result<(Vec<String>, String)> {
+        self.transfer_command("scp", profile, host_config)
+    }
+
+    /// The command line of sftp or scp, which take the same options.
+    fn transfer_command(&self, program: &str, profile: &str, host_config: &HostConfig) -> Result<(Vec<String>, String)> {
+        let client = self.ssh_client(host_config)?;
+        let (ssh, target) = self.ssh_transport(profile, host_config)?;
+
+        let mut command = vec![program.to_string()];
+        // sftp and scp take another client only without arguments of its own
+        if let [program] = client.as_slice() {
+            if program != "ssh" {
+                command.push("-S".to_string());
+                command.push(program.clone());
+            }
+        }
+
+        let mut args = ssh.into_iter().skip(client.len());
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-p" => {
+                    command.push("-P".to_string());
+                    command.extend(args.next());
+                }
+                "-o" | "-i" | "-J" | "-F" | "-c" | "-l" | "-S" => {
+                    command.push(arg);
+                    command.extend(args.next());
+                }
+                _ => command.push(arg),
+            }
+        }
+        Ok((command, target))
+    }
+
+    /// [`Masuk::ssh_command`] as the transport of a file transfer, without
+    /// the target and without what only makes sense for a login
+    /// (forwardings, tty, agent and X11 forwarding).
+    fn ssh_transport(&self, profile: &str, host_config: &HostConfig) -> Result<(Vec<String>, String)> {
+        let client = self.ssh_client(host_config)?;
+        let mut ssh = self.ssh_command(profile, host_config)?;
+        let target = ssh.pop().unwrap_or_default();
+
+        let mut command: Vec<String> = ssh.drain(..client.len()).collect();
+        let mut args = ssh.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-A" | "-a" | "-X" | "-x" | "-Y" | "-t" | "-T" | "-N" | "-f" | "-n" => {}
+                "-L" | "-R" | "-D" | "-W" => {
+                    args.next();
+                }
+                "-p" | "-o" | "-i" | "-J" | "-F" | "-c" | "-l" | "-S" => {
+                    command.push(arg);
+                    command.extend(args.next());
+                }
+                _ => command.push(arg),
+            }
+        }
+        Ok((command, target))
+    }
+
+    /// Resolves `jump` and `via` of a profile into the hops passed to
+    /// `ssh -J`, outermost first. A hop naming a profile is preceded by that
+    /// profile's own hops. `visiting` holds the profiles on the current path
+    /// and is used to detect loops.
+    fn jump_chain(&self, host_config: &HostConfig, visiting: &mut Vec<String>) -> Result<Vec<Hop>> {
+        let mut chain = Vec::new();
+
+        for hop in host_config.jump.iter().chain(&host_config.via) {
+            let hop = expand_env(hop)?;
+
+            let Some(hop_config) = self.config.profiles.get(&hop) else {
+                chain.push(Hop {
+                    profile: None,
+                    address: hop,
+                });
+                continue;
+            };
+
+            if visiting.contains(&hop) {
+                return Err(anyhow!("Jump host loop: {} -> {}", visiting.join(" -> "), hop));
+            }
+
+            let hop_config = self.with_defaults(&hop, hop_config).0.expanded()?;
+            visiting.push(hop.clone());
+            chain.extend(self.jump_chain(&hop_config, visiting)?);
+            visiting.pop();
+
+            chain.push(Hop {
+                profile: Some(hop),
+                address: hop_config.address(),
+            });
+        }
+
+        Ok(chain)
+    }
+
+    /// Policies that apply to a profile: one per tag in tag order, followed
+    /// by the `*` policy that applies to every profile.
+    fn policies_for<'a>(&'a self, host_config: &'a HostConfig) -> Vec<(String, &'a Policy)> {
+        let mut tags: Vec<String> = host_config.tags.clone();
+        tags.sort();
+        if let Some(risk) = host_config.risk {
+            tags.push(format!("risk:{}", risk.name()));
+        }
+        tags.push("*".to_string());
+
+        tags.into_iter()
+            .filter_map(|tag| self.config.policies.get(&tag).map(|policy| (tag, policy)))
+            .collect()
+    }
+
+    /// The key of the first policy of a profile that sets `rule`.
+    fn policy_requiring(&self, host_config: &HostConfig, rule: impl Fn(&Policy) -> bool) -> Option<String> {
+        self.policies_for(host_config)
+            .into_iter()
+            .find(|(_, policy)| rule(policy))
+            .map(|(tag, _)| tag)
+    }
+
+    /// Asks before touching a profile whose policy wants confirmation.
+    fn confirm_policy(&self, profile: &str, host_config: &HostConfig, what: &str) -> Result<()> {
+        let Some(tag) = self.policy_requiring(host_config, |policy| policy.confirm) else {
+            return Ok(());
+        };
+        if !confirm(&format!("Policy '{}' asks for confirmation. {} {}?", tag, what, profile))? {
+            return Err(anyhow!("Not confirmed, leaving {} alone", profile));
+        }
+        Ok(())
+    }
+
+    /// Fails for profiles whose policy keeps them out of fleet commands.
+    fn check_fleet(&self, profiles: &[&str]) -> Result<()> {
+        let forbidden: Vec<String> = profiles
+            .iter()
+            .filter_map(|name| {
+                let host_config = self.config.profiles.get(*name)?;
+                let tag = self.policy_requiring(host_config, |policy| policy.no_fleet)?;
+                Some(format!("{} (policy '{}')", name, tag))
+            })
+            .collect();
+        if !forbidden.is_empty() {
+            return Err(anyhow!(
+                "Policy forbids commands on several profiles for {}. Run them one by one.",
+                forbidden.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Distinct webhook URLs configured by the policies of a profile.
+    fn webhooks(&self, host_config: &HostConfig) -> Vec<String> {
+        let mut urls: Vec<String> = Vec::new();
+        for (_, policy) in self.policies_for(host_config) {
+            if let Some(ref url) = policy.webhook {
+                if !urls.contains(url) {
+                    urls.push(url.clone());
+                }
+            }
+        }
+        urls
+    }
+
+    /// Returns the recorder and log file mandated by the policies of the
+    /// profile's tags, or an error if the log directory cannot be written.
+    fn session_recording(&self, profile: &str, host_config: &HostConfig) -> Result<Option<(Recorder, PathBuf)>> {
+        let Some((ref tag, policy, recorder)) = self
+            .policies_for(host_config)
+            .into_iter()
+            .find_map(|(tag, policy)| policy.record.map(|recorder| (tag, policy, recorder)))
+        else {
+            return Ok(None);
+        };
+
+        let log_dir = match policy.log_dir {
+            Some(ref dir) => dir.clone(),
+            None => self.config_dir().join("sessions"),
+        };
+
+        let log_file = log_dir.join(format!("{}-{}.{}", profile, unix_time(), recorder.extension()));
+
+        // Probe the log file itself rather than trusting directory permissions
+        fs::create_dir_all(&log_dir)
+            .and_then(|_| fs::OpenOptions::new().write(true).create_new(true).open(&log_file))
+            .and_then(|_| fs::remove_file(&log_file))
+            .map_err(|e| {
+                anyhow!(
+                    "Refusing to connect: policy for tag '{}' requires session recording but log directory '{}' is not writable ({})",
+                    tag,
+                    log_dir.display(),
+                    e
+                )
+            })?;
+
+        Ok(Some((recorder, log_file)))
+    }
+
+    fn config_dir(&self) -> PathBuf {
+        self.config_path
+            .parent()
+            .map(|p| p.to_path_buf())
+            .unwrap_or_default()
+    }
+
+    /// Gets credentials for a profile with a Vault role, returning what was
+    /// done. A signed certificate replaces the profile's certificate file;
+    /// a one-time password becomes the profile's password, fetched fresh
+    /// whenever ssh asks for it. Without `fetch` (dry runs), only the
+    /// profile is changed.
+    fn vault_credentials(&self, profile: &str, host_config: &mut HostConfig, fetch: bool) -> Result<Option<String>> {
+        let role = host_config.vault_role.clone().unwrap_or_default();
+        let mount = host_config.vault_mount.as_deref().unwrap_or("ssh").trim_matches('/').to_string();
+        match host_config.vault_mode.unwrap_or(VaultMode::Sign) {
+            VaultMode::Otp => {
+                use std::net::ToSocketAddrs;
+
+                // Vault wants the address the helper on the host sees
+                let ip = (host_config.host.as_str(), host_config.port.unwrap_or(22))
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addrs| addrs.next())
+                    .map(|addr| addr.ip().to_string())
+                    .ok_or_else(|| anyhow!("Can't resolve {} for a Vault one-time password", host_config.host))?;
+                let target = match host_config.user {
+                    Some(ref user) => format!("{}@{}", user, ip),
+                    None => ip,
+                };
+                host_config.password = Some(format!("vault-ssh-otp://{}/{}#{}", mount, role, target));
+                Ok(None)
+            }
+            VaultMode::Sign => {
+                let key = host_config
+                    .keys
+                    .first()
+                    .ok_or_else(|| anyhow!("Profile '{}' needs a key (-k) for Vault to sign", profile))?;
+                let dir = self.state_dir.join("vault");
+                let certificate = dir.join(format!("{}-cert.pub", profile));
+                host_config.certificate_file = Some(certificate.display().to_string());
+                if !fetch {
+                    return Ok(None);
+                }
+
+                let mut command = Command::new("vault");
+                command
+                    .args(["write", "-field=signed_key", &format!("{}/sign/{}", mount, role)])
+                    .arg(format!("public_key=@{}.pub", key));
+                if let Some(ref user) = host_config.user {
+                    command.arg(format!("valid_principals={}", user));
+                }
+                let output = command
+                    .stdin(Stdio::inherit())
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .context("Failed to run 'vault'. Is it installed?")?;
+                if !output.status.success() {
+                    return Err(anyhow!("Vault refused to sign {}.pub with {}/sign/{}", key, mount, role));
+                }
+                fs::create_dir_all(&dir).context("Failed to create the directory for Vault certificates")?;
+                fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+                fs::write(&certificate, &output.stdout)
+                    .with_context(|| format!("Failed to write {}", certificate.display()))?;
+                Ok(Some(format!("Signed {}.pub with Vault ({}/sign/{})", key, mount, role)))
+            }
+        }
+    }
+
+    /// Directory holding ControlMaster sockets. Not per config file, to keep
+    /// socket paths short.
+    fn sockets_dir(&self) -> PathBuf {
+        self.state_dir.join("sockets")
+    }
+
+    fn create_sockets_dir(&self) -> Result<()> {
+        let sockets = self.sockets_dir();
+        fs::create_dir_all(&sockets).context("Failed to create sockets directory")?;
+        fs::set_permissions(&sockets, fs::Permissions::from_mode(0o700))
+            .context("Failed to restrict sockets directory")
+    }
+
+    /// Sends a control command (`check`, `exit`, ...) to the master
+    /// connection of a profile, capturing the output.
+    fn mux_control(&self, profile: &str, operation: &str) -> Result<std::process::Output> {
+        let host_config = self.resolve(profile)?;
+        let mut command = self.ssh_command(profile, &host_config)?;
+        command.splice(1..1, ["-O".to_string(), operation.to_string()]);
+        Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .output()
+            .context("Failed to execute SSH command")
+    }
+
+    /// Opens a master connection that stays up until closed, for scripts
+    /// running many short commands against one host. Every masuk command
+    /// for the profile goes through it; the control socket is printed so
+    /// other tools can use it too.
+    fn session_open(&self, profile: &str, persist: &str) -> Result<()> {
+        let host_config = self.resolve(profile)?;
+        self.create_sockets_dir()?;
+        let command = self.ssh_command(profile, &host_config)?;
+
+        if self.mux_control(profile, "check")?.status.success() {
+            eprintln!("{}", plain(&format!("✓ Session to {} is already open", profile)));
+        } else {
+            let mut master = command.clone();
+            master.splice(
+                1..1,
+                [
+                    "-f".to_string(),
+                    "-N".to_string(),
+                    "-o".to_string(),
+                    "ControlMaster=auto".to_string(),
+                    "-o".to_string(),
+                    format!("ControlPersist={}", persist),
+                ],
+            );
+            let status = Command::new(&master[0])
+                .args(&master[1..])
+                .stdin(Stdio::null())
+                .status()
+                .context("Failed to execute SSH command")?;
+            interrupt::check()?;
+            if !status.success() {
+                return Err(anyhow!("Failed to open a session to '{}'", profile));
+            }
+            eprintln!("{}", plain(&format!("✓ Opened session to {}, close it with 'masuk session close {}'", profile, profile)));
+        }
+
+        // ssh -G prints the configuration with the ControlPath expanded
+        let mut query = command;
+        query.insert(1, "-G".to_string());
+        let output = Command::new(&query[0])
+            .args(&query[1..])
+            .stdin(Stdio::null())
+            .output()
+            .context("Failed to execute SSH command")?;
+        let socket = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|line| line.strip_prefix("controlpath ").map(str::to_string));
+        match socket {
+            Some(socket) => println!("{}", socket),
+            None => eprintln!("Could not determine the control socket of the session"),
+        }
+        Ok(())
+    }
+
+    fn session_close(&self, profile: &str) -> Result<()> {
+        if !self.mux_control(profile, "exit")?.status.success() {
+            return Err(anyhow!("No session to '{}' is open", profile));
+        }
+        out().success(&format!("Closed session to {}", profile));
+        Ok(())
+    }
+
+    fn mux_list(&self) -> Result<()> {
+        let mut profiles: Vec<_> = self.config.profiles.keys().collect();
+        profiles.sort();
+
+        let mut running = Vec::new();
+        if self.sockets_dir().is_dir() {
+            for profile in profiles {
+                if let Ok(output) = self.mux_control(profile, "check") {
+                    if output.status.success() {
+                        let status = String::from_utf8_lossy(&output.stderr).trim().to_string();
+                        running.push((profile, status));
+                    }
+                }
+            }
+        }
+
+        let items: Vec<Item> = running
+            .into_iter()
+            .map(|(profile, status)| Item {
+                name: profile.clone(),
+                data: json!({ "name": profile, "status": status }),
+                description: status,
+            })
+            .collect();
+        out().list("Master connections", &items, "No master connections running.");
+        Ok(())
+    }
+
+    fn mux_stop(&self, names: &[String], all: bool) -> Result<()> {
+        let profiles = if all {
+            let mut profiles: Vec<_> = self.config.profiles.keys().cloned().collect();
+            profiles.sort();
+            profiles
+        } else {
+            self.select_profiles(names, &[])?
+        };
+
+        for profile in &profiles {
+            let output = self.mux_control(profile, "exit")?;
+            if output.status.success() {
+                out().success(&format!("Stopped master connection for '{}'", profile));
+            } else if !all {
+                eprintln!("No master connection running for '{}'", profile);
+            }
+        }
+        Ok(())
+    }
+
+    /// Profiles named explicitly plus all profiles carrying one of `tags`,
+    /// sorted and without duplicates.
+    fn select_profiles(&self, names: &[String], tags: &[String]) -> Result<Vec<String>> {
+        if names.is_empty() && tags.is_empty() {
+            return Err(anyhow!("Specify one or more profiles or --tag"));
+        }
+
+        let mut selected = Vec::new();
+        for name in names {
+            if !self.config.profiles.contains_key(name) {
+                return Err(anyhow!("Profile '{}' not found. Use 'masuk ls' to see available profiles.", name));
+            }
+            selected.push(name.clone());
+        }
+        for (name, host_config) in &self.config.profiles {
+            if host_config.tags.iter().any(|tag| tags.contains(tag)) {
+                selected.push(name.clone());
+            }
+        }
+
+        selected.sort();
+        selected.dedup();
+        if selected.is_empty() {
+            return Err(anyhow!("No profiles tagged {}", tags.join(", ")));
+        }
+        Ok(selected)
+    }
+
+    /// Starts ControlMaster connections for the selected profiles in
+    /// parallel, so later connections skip the handshake.
+    fn warm(&self, names: &[String], tags: &[String], persist: &str) -> Result<()> {
+        let selected = self.select_profiles(names, tags)?;
+        self.create_sockets_dir()?;
+
+        let mut failed = 0;
+        let mut running = Vec::new();
+        for profile in &selected {
+            let command = match self.resolve(profile).and_then(|c| self.ssh_command(profile, &c)) {
+                Ok(command) => command,
+                Err(e) => {
+                    eprintln!("{}", plain(&format!("✗ {}: {:#}", profile, e)));
+                    failed += 1;
+                    continue;
+                }
+            };
+
+            let warm = self
+                .mux_control(profile, "check")
+                .is_ok_and(|output| output.status.success());
+            if warm {
+                out().success(&format!("{} is already warm", profile));
+                continue;
+            }
+
+            let mut master = command;
+            master.splice(
+                1..1,
+                [
+                    "-f".to_string(),
+                    "-N".to_string(),
+                    "-o".to_string(),
+                    "ControlMaster=auto".to_string(),
+                    "-o".to_string(),
+                    format!("ControlPersist={}", persist),
+                    "-o".to_string(),
+                    "BatchMode=yes".to_string(),
+                ],
+            );
+            match Command::new(&master[0]).args(&master[1..]).stdin(Stdio::null()).spawn() {
+                Ok(child) => running.push((profile, child)),
+                Err(e) => {
+                    eprintln!("{}", plain(&format!("✗ {}: failed to execute SSH command: {}", profile, e)));
+                    failed += 1;
+                }
+            }
+        }
+
+        // ssh -f returns once the master is authenticated and in the background
+        for (profile, mut child) in running {
+            match child.wait() {
+                Ok(status) if status.success() => out().success(&format!("Warmed {}", profile)),
+                _ => {
+                    eprintln!("{}", plain(&format!("✗ Failed to warm {}", profile)));
+                    failed += 1;
+                }
+            }
+        }
+
+        interrupt::check()?;
+        if failed > 0 {
+            return Err(anyhow!("{} of {} profiles could not be warmed", failed, selected.len()));
+        }
+        Ok(())
+    }
+
+    /// Creates profiles for hosts found in an import source. Existing
+    /// profiles are kept unless `overwrite` is set.
+    fn import(&mut self, found: Vec<import::Found>, options: ImportOptions) -> Result<()> {
+        let mut imported = 0;
+        let mut newer = 0;
+        let mut skipped = Vec::new();
+        for import::Found { name, mut config } in found {
+            if name.is_empty() {
+                return Err(anyhow!("Found a profile without a name"));
+            }
+            if options.filter.as_deref().is_some_and(|pattern| !glob_match(pattern, &name)) {
+                continue;
+            }
+            if let Some(existing) = self.config.profiles.get(&name) {
+                // Without --overwrite, a copy only wins when it was changed
+                // more recently than the local profile
+                let is_newer = config.updated_at.is_some() && config.updated_at > existing.updated_at;
+                if !options.overwrite && !is_newer {
+                    skipped.push(name);
+                    continue;
+                }
+                if is_newer {
+                    newer += 1;
+                }
+            }
+
+            // Tags from the source are kept next to the ones given with -t
+            let source_tags = config.tags.clone();
+            options.settings.clone().apply(&mut config);
+            for tag in source_tags {
+                if !config.tags.contains(&tag) {
+                    config.tags.push(tag);
+                }
+            }
+            if config.created_at.is_none() {
+                config.created_at = Some(unix_time() as i64);
+            }
+            if config.updated_at.is_none() {
+                config.touch();
+            }
+            out().info(&format!("  {} → {}", name, config.describe()));
+            self.config.profiles.insert(name, config);
+            imported += 1;
+        }
+
+        if imported > 0 {
+            self.save_config()?;
+        }
+        if newer > 0 {
+            out().info(&format!("Replaced {} existing profile{} with newer copies", newer, if newer == 1 { "" } else { "s" }));
+        }
+        if !skipped.is_empty() {
+            out().info(&format!("Skipped existing profiles (use --overwrite to replace them): {}", skipped.join(", ")));
+        }
+        out().success(&format!("Imported {} profile{}", imported, if imported == 1 { "" } else { "s" }));
+        Ok(())
+    }
+
+    /// Fetches a remote file, opens it in the local editor and writes it back
+    /// after showing the diff. With `sudo` the file is read and written
+    /// through `sudo` on the host instead of plain sftp.
+    fn edit_remote(&self, profile: &str, path: &str, sudo: bool, yes: bool) -> Result<()> {
+        let host_config = self.resolve(profile)?;
+        let ssh = self.ssh_command(profile, &host_config)?;
+        let (sftp, target) = self.sftp_command(profile, &host_config)?;
+
+        let dir = TempDir::create("edit")?;
+        // Keep the file name so the editor picks the right syntax
+        let name = path.rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or("file");
+        let original = dir.path.join(format!("original-{}", name));
+        let edited = dir.path.join(name);
+
+        let status = if sudo {
+            let mut read = ssh.clone();
+            read.push(format!("sudo -n cat -- {}", shell_quote(path)));
+            let file = fs::File::create(&original).context("Failed to create temporary file")?;
+            Command::new(&read[0]).args(&read[1..]).stdout(file).status()
+        } else {
+            Command::new(&sftp[0])
+                .args(&sftp[1..])
+                .arg("-q")
+                .arg(format!("{}:{}", target, path))
+                .arg(&original)
+                .stdout(Stdio::null())
+                .status()
+        }
+        .context("Failed to fetch the file")?;
+        interrupt::check()?;
+        if !status.success() {
+            return Err(anyhow!("Failed to fetch {} from '{}'", path, profile));
+        }
+        fs::copy(&original, &edited).context("Failed to create temporary file")?;
+
+        let editor = env::var("VISUAL")
+            .or_else(|_| env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_string());
+        let mut editor: Vec<String> = editor.split_whitespace().map(String::from).collect();
+        if editor.is_empty() {
+            editor.push("vi".to_string());
+        }
+        let status = Command::new(&editor[0])
+            .args(&editor[1..])
+            .arg(&edited)
+            .status()
+            .with_context(|| format!("Failed to run editor '{}'", editor[0]))?;
+        interrupt::check()?;
+        if !status.success() {
+            return Err(anyhow!("The editor exited with {}, not writing {}", status, path));
+        }
+
+        if fs::read(&original)? == fs::read(&edited)? {
+            out().info(&format!("No changes, {} left as it was", path));
+            return Ok(());
+        }
+
+        let _ = Command::new("diff")
+            .arg("-u")
+            .args(["--label", &format!("{}:{}", profile, path), "--label", "edited"])
+            .arg(&original)
+            .arg(&edited)
+            .status();
+        if !yes && !confirm(&format!("Write the changes to {}:{}?", profile, path))? {
+            out().info(&format!("Left {} unchanged", path));
+            return Ok(());
+        }
+
+        let status = if sudo {
+            let mut write = ssh;
+            write.push(format!("sudo -n tee -- {} >/dev/null", shell_quote(path)));
+            let file = fs::File::open(&edited).context("Failed to read the edited file")?;
+            Command::new(&write[0]).args(&write[1..]).stdin(file).status()
+        } else {
+            let batch = format!("put \"{}\" \"{}\"\n", edited.display(), path.replace('"', "\\\""));
+            Command::new(&sftp[0])
+                .args(&sftp[1..])
+                .args(["-q", "-b", "-"])
+                .arg(&target)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .spawn()
+                .and_then(|mut child| {
+                    if let Some(mut stdin) = child.stdin.take() {
+                        stdin.write_all(batch.as_bytes())?;
+                    }
+                    child.wait()
+                })
+        }
+        .context("Failed to write the file back")?;
+        if !status.success() {
+            return Err(anyhow!(
+                "Failed to write {} on '{}'. Your edit is kept in {}",
+                path,
+                profile,
+                dir.keep().join(name).display()
+            ));
+        }
+        out().success(&format!("Wrote {}:{}", profile, path));
+        Ok(())
+    }
+
+    /// Copies files with scp between this machine and a profile. Remote
+    /// paths are written `<profile>:<path>`, and all of them have to be on
+    /// the same profile. `limit` is in KiB per second.
+    fn cp(&self, paths: &[String], recursive: bool, limit: Option<u64>, dry_run: bool) -> Result<()> {
+        let (command, host_config) = self.cp_command(paths, recursive, limit, !dry_run)?;
+        if dry_run {
+            println!("{}", shell_join(&command));
+            return Ok(());
+        }
+
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .envs(&host_config.env)
+            .envs(askpass_env(&host_config))
+            .status()
+            .context("Failed to run scp")?;
+        interrupt::check()?;
+        if !status.success() {
+            return Err(ChildExit(exit_code(status)).into());
+        }
+        Ok(())
+    }
+
+    /// The scp command of [`Masuk::cp`] and the profile it copies with,
+    /// whose Vault credentials are fetched if `fetch`.
+    fn cp_command(&self, paths: &[String], recursive: bool, limit: Option<u64>, fetch: bool) -> Result<(Vec<String>, HostConfig)> {
+        let mut profile: Option<&str> = None;
+        for path in paths {
+            if let Some((name, _)) = self.remote_path(path)? {
+                if profile.is_some_and(|profile| profile != name) {
+                    return Err(anyhow!("Copying between two profiles isn't supported, copy through a local directory"));
+                }
+                profile = Some(name);
+            }
+        }
+        let profile = profile.ok_or_else(|| anyhow!("None of the paths is remote, write them as <profile>:<path>"))?;
+
+        let host_config = self.transfer_host_config(profile, fetch)?;
+        let (mut command, target) = self.scp_command(profile, &host_config)?;
+        let target = transfer_target(target);
+        if recursive {
+            command.push("-r".to_string());
+        }
+        if let Some(limit) = limit {
+            // scp counts in Kbit/s
+            command.push("-l".to_string());
+            command.push((limit * 8).to_string());
+        }
+        for path in paths {
+            command.push(match self.remote_path(path)? {
+                Some((_, remote)) => format!("{}:{}", target, remote),
+                None => path.clone(),
+            });
+        }
+        Ok((command, host_config))
+    }
+
+    /// Runs rsync over the profile's ssh settings. Remote paths are written
+    /// `<profile>:<path>` like for [`Masuk::cp`]; `args` go to rsync as
+    /// they are. `limit` is in KiB per second.
+    fn rsync(&self, paths: &[String], args: &[String], limit: Option<u64>, progress: bool, dry_run: bool) -> Result<()> {
+        let mut profile: Option<&str> = None;
+        for path in paths {
+            if let Some((name, _)) = self.remote_path(path)? {
+                if profile.is_some_and(|profile| profile != name) {
+                    return Err(anyhow!("rsync can't sync two remote profiles, sync through a local directory"));
+                }
+                profile = Some(name);
+            }
+        }
+        let profile = profile.ok_or_else(|| anyhow!("None of the paths is remote, write them as <profile>:<path>"))?;
+
+        let host_config = self.transfer_host_config(profile, !dry_run)?;
+        let (ssh, target) = self.ssh_transport(profile, &host_config)?;
+        let target = transfer_target(target);
+        let mut command = vec!["rsync".to_string(), "-e".to_string(), shell_join(&ssh)];
+        if let Some(limit) = limit {
+            command.push(format!("--bwlimit={}", limit));
+        }
+        if progress {
+            command.push("--info=progress2".to_string());
+            command.push("--human-readable".to_string());
+        }
+        command.extend(args.iter().cloned());
+        for path in paths {
+            command.push(match self.remote_path(path)? {
+                Some((_, remote)) => format!("{}:{}", target, remote),
+                None => path.clone(),
+            });
+        }
+        if dry_run {
+            println!("{}", shell_join(&command));
+            return Ok(());
+        }
+
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .envs(&host_config.env)
+            .envs(askpass_env(&host_config))
+            .status()
+            .context("Failed to run rsync. Is it installed?")?;
+        interrupt::check()?;
+        if !status.success() {
+            return Err(ChildExit(exit_code(status)).into());
+        }
+        Ok(())
+    }
+
+    /// Mounts `remote` (the home directory by default) of a profile at
+    /// `mountpoint` (`~/mnt/<profile>` by default) with sshfs, using the
+    /// profile's ssh settings.
+    fn mount(&self, profile: &str, remote: Option<&str>, mountpoint: Option<PathBuf>, dry_run: bool) -> Result<()> {
+        let host_config = self.transfer_host_config(profile, !dry_run)?;
+        let (ssh, target) = self.ssh_transport(profile, &host_config)?;
+        let remote = remote.unwrap_or("");
+        let mountpoint = match mountpoint {
+            Some(path) if path.is_relative() => env::current_dir()?.join(path),
+            Some(path) => path,
+            None => dirs::home_dir()
+                .context("Could not find home directory")?
+                .join("mnt")
+                .join(profile),
+        };
+
+        // sshfs splits the command on spaces and its options on commas
+        let ssh_command: Vec<String> = ssh
+            .iter()
+            .map(|arg| arg.replace('\\', "\\\\").replace(' ', "\\ ").replace(',', "\\,"))
+            .collect();
+        let command = vec![
+            "sshfs".to_string(),
+            format!("{}:{}", transfer_target(target), remote),
+            mountpoint.display().to_string(),
+            "-o".to_string(),
+            format!("reconnect,ssh_command={}", ssh_command.join(" ")),
+        ];
+        if dry_run {
+            println!("{}", shell_join(&command));
+            return Ok(());
+        }
+
+        if is_mounted(&mountpoint) {
+            return Err(anyhow!("Something is already mounted at {}", mountpoint.display()));
+        }
+        fs::create_dir_all(&mountpoint).with_context(|| format!("Failed to create {}", mountpoint.display()))?;
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .envs(&host_config.env)
+            .envs(askpass_env(&host_config))
+            .status()
+            .context("Failed to run sshfs. Is it installed?")?;
+        interrupt::check()?;
+        if !status.success() {
+            return Err(anyhow!("sshfs failed to mount {}:{} ({})", profile, remote, status));
+        }
+
+        let mut mounts = self.mounts();
+        mounts.push(Mount {
+            profile: profile.to_string(),
+            remote: remote.to_string(),
+            mountpoint: mountpoint.clone(),
+            mounted_at: unix_time(),
+        });
+        self.save_mounts(&mounts)?;
+        out().success(&format!("Mounted {}:{} at {}", profile, remote, mountpoint.display()));
+        Ok(())
+    }
+
+    /// Unmounts everything `masuk mount` mounted for a profile.
+    fn umount(&self, profile: &str) -> Result<()> {
+        let (mine, others): (Vec<Mount>, Vec<Mount>) = self.mounts().into_iter().partition(|mount| mount.profile == profile);
+        if mine.is_empty() {
+            return Err(anyhow!("Nothing of '{}' is mounted. Use 'masuk mount --list' to see the mounts.", profile));
+        }
+        let mut still_mounted = Vec::new();
+        for mount in mine {
+            let unmount = if cfg!(target_os = "macos") {
+                Command::new("umount").arg(&mount.mountpoint).status()
+            } else {
+                Command::new("fusermount")
+                    .arg("-u")
+                    .arg(&mount.mountpoint)
+                    .status()
+                    .or_else(|_| Command::new("fusermount3").arg("-u").arg(&mount.mountpoint).status())
+            };
+            if unmount.is_ok_and(|status| status.success()) || !is_mounted(&mount.mountpoint) {
+                out().success(&format!("Unmounted {}", mount.mountpoint.display()));
+            } else {
+                eprintln!("{}", plain(&format!("✗ Failed to unmount {}, is it still in use?", mount.mountpoint.display())));
+                still_mounted.push(mount);
+            }
+        }
+        let failed = still_mounted.len();
+        self.save_mounts(&others.into_iter().chain(still_mounted).collect::<Vec<_>>())?;
+        if failed > 0 {
+            return Err(anyhow!("{} mount{} of '{}' stayed", failed, if failed == 1 { "" } else { "s" }, profile));
+        }
+        Ok(())
+    }
+
+    fn list_mounts(&self) -> Result<()> {
+        let mounts = self.mounts();
+        if mounts.is_empty() {
+            out().info("No mounts. Use 'masuk mount <profile>' to mount one.");
+            return Ok(());
+        }
+        let rows: Vec<Vec<String>> = mounts
+            .iter()
+            .map(|mount| {
+                let remote = if mount.remote.is_empty() { "~" } else { &mount.remote };
+                vec![mount.profile.clone(), remote.to_string(), mount.mountpoint.display().to_string()]
+            })
+            .collect();
+        out().table(&["PROFILE", "REMOTE", "MOUNTPOINT"], &rows, &serde_json::to_value(&mounts)?);
+        Ok(())
+    }
+
+    /// The mounts made with `masuk mount` that are still mounted.
+    fn mounts(&self) -> Vec<Mount> {
+        let mounts: Vec<Mount> = fs::read_to_string(self.state_dir.join("mounts.json"))
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+        mounts.into_iter().filter(|mount| is_mounted(&mount.mountpoint)).collect()
+    }
+
+    fn save_mounts(&self, mounts: &[Mount]) -> Result<()> {
+        let path = self.state_dir.join("mounts.json");
+        let data = serde_json::to_string_pretty(mounts).context("Failed to serialize mounts")?;
+        fs::create_dir_all(&self.state_dir).context("Failed to create config directory")?;
+        fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Copies local files into the remote directory of each profile, to
+    /// all of them at once when there are several.
+    fn push(&self, profiles: &[String], files: &[String], recursive: bool, limit: Option<u64>, dry_run: bool) -> Result<()> {
+        let mut transfers = Vec::new();
+        for profile in profiles {
+            let host_config = self.resolve(profile)?;
+            let destination = match host_config.remote_dir.as_deref() {
+                None | Some("") => format!("{}:", profile),
+                Some(dir) => format!("{}:{}/", profile, dir.trim_end_matches('/')),
+            };
+            transfers.push((profile.clone(), files.iter().cloned().chain([destination]).collect::<Vec<_>>()));
+        }
+        match transfers.as_slice() {
+            [(_, paths)] => self.cp(paths, recursive, limit, dry_run),
+            _ => self.cp_fleet(&transfers, recursive, limit, progress::local_size(files), dry_run),
+        }
+    }
+
+    /// Copies files of each profile into its local directory, from all of
+    /// them at once when there are several. Relative paths are taken from
+    /// the remote directory. Files from several profiles go into a
+    /// directory per profile, so they don't overwrite each other.
+    fn pull(&self, profiles: &[String], files: &[String], recursive: bool, limit: Option<u64>, dry_run: bool) -> Result<()> {
+        let mut transfers = Vec::new();
+        for profile in profiles {
+            let host_config = self.resolve(profile)?;
+            let remote_dir = host_config.remote_dir.as_deref().unwrap_or("").trim_end_matches('/');
+            let mut local_dir = PathBuf::from(host_config.local_dir.as_deref().unwrap_or("."));
+            if profiles.len() > 1 {
+                local_dir.push(profile);
+            }
+            let mut paths: Vec<String> = files
+                .iter()
+                .map(|file| match file.as_str() {
+                    file if file.starts_with(['/', '~']) || remote_dir.is_empty() => format!("{}:{}", profile, file),
+                    file => format!("{}:{}/{}", profile, remote_dir, file),
+                })
+                .collect();
+            paths.push(local_dir.display().to_string());
+            if !dry_run {
+                fs::create_dir_all(&local_dir).with_context(|| format!("Failed to create {}", local_dir.display()))?;
+            }
+            transfers.push((profile.clone(), paths));
+        }
+        match transfers.as_slice() {
+            [(_, paths)] => self.cp(paths, recursive, limit, dry_run),
+            _ => self.cp_fleet(&transfers, recursive, limit, None, dry_run),
+        }
+    }
+
+    /// Runs [`Masuk::cp`] for several profiles at once, with the paths of
+    /// each, showing a progress bar per profile and one for all of them,
+    /// then a table of how each went. `size` is what every profile copies,
+    /// when known beforehand.
+    fn cp_fleet(
+        &self,
+        transfers: &[(String, Vec<String>)],
+        recursive: bool,
+        limit: Option<u64>,
+        size: Option<u64>,
+        dry_run: bool,
+    ) -> Result<()> {
+        self.check_fleet(&transfers.iter().map(|(profile, _)| profile.as_str()).collect::<Vec<_>>())?;
+        let mut running = Vec::new();
+        for (profile, paths) in transfers {
+            let (command, host_config) = self.cp_command(paths, recursive, limit, !dry_run)?;
+            if dry_run {
+                println!("{}", shell_join(&command));
+                continue;
+            }
+            let mut scp = Command::new(&command[0]);
+            scp.args(&command[1..]).envs(&host_config.env).envs(askpass_env(&host_config));
+            running.push(progress::Transfer {
+                name: profile.clone(),
+                command: scp,
+                size,
+            });
+        }
+        if dry_run {
+            return Ok(());
+        }
+
+        let outcomes = progress::run(running);
+        interrupt::check()?;
+        let rows: Vec<Vec<String>> = outcomes
+            .iter()
+            .map(|outcome| {
+                let seconds = outcome.elapsed.as_secs_f64().max(0.001);
+                vec![
+                    outcome.name.clone(),
+                    if outcome.error.is_none() { "ok" } else { "failed" }.to_string(),
+                    progress::format_bytes(outcome.bytes),
+                    format_duration(outcome.elapsed.as_secs()),
+                    format!("{}/s", progress::format_bytes((outcome.bytes as f64 / seconds) as u64)),
+                ]
+            })
+            .collect();
+        let data = json!(outcomes
+            .iter()
+            .map(|outcome| json!({
+                "profile": outcome.name,
+                "ok": outcome.error.is_none(),
+                "bytes": outcome.bytes,
+                "seconds": outcome.elapsed.as_secs_f64(),
+                "error": outcome.error,
+            }))
+            .collect::<Vec<_>>());
+        out().table(&["PROFILE", "STATUS", "BYTES", "TIME", "RATE"], &rows, &data);
+
+        let failed: Vec<&progress::Outcome> = outcomes.iter().filter(|outcome| outcome.error.is_some()).collect();
+        for outcome in &failed {
+            eprintln!("{}", plain(&format!("✗ {}: {}", outcome.name, outcome.error.as_deref().unwrap_or_default())));
+        }
+        if !failed.is_empty() {
+            return Err(anyhow!("The transfer failed on {} of {} profiles", failed.len(), outcomes.len()));
+        }
+        Ok(())
+    }
+
+    /// Opens an interactive sftp session on a profile.
+    fn sftp(&self, profile: &str, dry_run: bool) -> Result<()> {
+        let host_config = self.transfer_host_config(profile, !dry_run)?;
+        let (mut command, target) = self.sftp_command(profile, &host_config)?;
+        command.push(target);
+        if dry_run {
+            println!("{}", shell_join(&command));
+            return Ok(());
+        }
+
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .envs(&host_config.env)
+            .envs(askpass_env(&host_config))
+            .status()
+            .context("Failed to run sftp")?;
+        interrupt::check()?;
+        if !status.success() {
+            return Err(ChildExit(exit_code(status)).into());
+        }
+        Ok(())
+    }
+
+    /// The resolved profile for file transfers, which only work over ssh,
+    /// with Vault credentials fetched unless `fetch` is false.
+    fn transfer_host_config(&self, profile: &str, fetch: bool) -> Result<HostConfig> {
+        let mut host_config = self.resolve(profile)?;
+        if !matches!(host_config.backend, None | Some(Backend::Ssh)) {
+            return Err(anyhow!(
+                "Profile '{}' uses {}, file transfers and tunnels only work over ssh",
+                profile,
+                host_config.backend.unwrap_or(Backend::Ssh).name()
+            ));
+        }
+        if host_config.vault_role.is_some() {
+            if let Some(message) = self.vault_credentials(profile, &mut host_config, fetch)? {
+                eprintln!("{}", plain(&message));
+            }
+        }
+        Ok(host_config)
+    }
+
+    /// Splits `<profile>:<path>`, or returns `None` for local paths. A colon
+    /// after a slash, like in `./a:b`, belongs to a local path.
+    fn remote_path<'a>(&self, path: &'a str) -> Result<Option<(&'a str, &'a str)>> {
+        let Some((name, remote)) = path.split_once(':') else {
+            return Ok(None);
+        };
+        if name.is_empty() || name.contains('/') {
+            return Ok(None);
+        }
+        if !self.config.profiles.contains_key(name) {
+            return Err(anyhow!("Profile '{}' not found. Use 'masuk ls' to see available profiles.", name));
+        }
+        Ok(Some((name, remote)))
+    }
+
+    /// Asks for the sudo password of a profile and stores it where its
+    /// `sudo_password` reference points, by default in the OS keychain.
+    fn store_sudo_password(&mut self, profile: &str) -> Result<()> {
+        self.store_secret(profile, "sudo password", "masuk-sudo", |host_config| &mut host_config.sudo_password)
+    }
+
+    /// Asks for the login password of a profile and stores it where its
+    /// `password` reference points, by default in the OS keychain.
+    fn store_password(&mut self, profile: &str) -> Result<()> {
+        self.store_secret(profile, "password", "masuk", |host_config| &mut host_config.password)
+    }
+
+    /// Asks for the passphrase of a profile's key and stores it where its
+    /// `key_passphrase` reference points, by default in the OS keychain.
+    fn store_key_passphrase(&mut self, profile: &str) -> Result<()> {
+        self.store_secret(profile, "key passphrase", "masuk-passphrase", |host_config| &mut host_config.key_passphrase)
+    }
+
+    /// Asks for the TOTP secret of a profile and stores it where its `totp`
+    /// reference points, by default in the OS keychain.
+    fn store_totp(&mut self, profile: &str) -> Result<()> {
+        self.store_secret(profile, "TOTP secret", "masuk-totp", |host_config| &mut host_config.totp)
+    }
+
+    /// Prints the current authenticator code of a profile, or copies it.
+    fn otp(&self, profile: &str, copy: bool) -> Result<()> {
+        let host_config = self.resolve(profile)?;
+        let reference = host_config.totp.as_deref().ok_or_else(|| {
+            anyhow!("Profile '{}' has no TOTP secret. Store one with 'masuk secret set {} --totp'.", profile, profile)
+        })?;
+        let secret = masuk::secrets::Registry::default().resolve(reference)?;
+        let (code, valid) = totp::Totp::parse(&secret)?.now();
+        if copy {
+            let via = clipboard::copy(&code)?;
+            out().success(&format!("Copied the code for {} to the clipboard ({}), valid for {}s", profile, via, valid));
+        } else {
+            println!("{}", code);
+        }
+        Ok(())
+    }
+
+    /// Stores a password where the reference in `field` points, setting the
+    /// reference to `keyring://<service>/<profile>` when the profile has
+    /// none yet. Only the reference ends up in the config.
+    fn store_secret(
+        &mut self,
+        profile: &str,
+        what: &str,
+        service: &str,
+        field: fn(&mut HostConfig) -> &mut Option<String>,
+    ) -> Result<()> {
+        let stored = self
+            .config
+            .profiles
+            .get_mut(profile)
+            .ok_or_else(|| anyhow!("Profile '{}' not found. Use 'masuk ls' to see available profiles.", profile))?;
+        let existing = field(stored).clone();
+        let reference = existing.clone().unwrap_or_else(|| format!("keyring://{}/{}", service, profile));
+
+        let password = read_password(&format!("{} for {}: ", what, profile))?;
+        if password.is_empty() {
+            return Err(anyhow!("No password given"));
+        }
+        masuk::secrets::Registry::default().store(&reference, &password)?;
+
+        if existing.is_none() {
+            let stored = self.config.profiles.get_mut(profile).expect("profile exists");
+            *field(stored) = Some(reference.clone());
+            stored.touch();
+            self.save_config()?;
+        }
+        out().success(&format!("Stored the {} for '{}' in {}", what, profile, reference));
+        Ok(())
+    }
+
+    /// Prints profiles as JSON that `masuk import json` reads back, all of
+    /// them unless some are selected.
+    fn export_json(&self, names: &[String], tags: &[String]) -> Result<()> {
+        let selected = if names.is_empty() && tags.is_empty() {
+            self.config.profiles.keys().cloned().collect()
+        } else {
+            self.select_profiles(names, tags)?
+        };
+        let profiles: BTreeMap<&String, &HostConfig> = selected
+            .iter()
+            .map(|name| (name, &self.config.profiles[name]))
+            .collect();
+        let json = serde_json::to_string_pretty(&profiles).context("Failed to serialize profiles")?;
+        println!("{}", json);
+        Ok(())
+    }
+
+    /// Prints profiles as an ssh_config fragment, or writes it to `output`,
+    /// so other tools that go through ssh know the same names. Profiles the
+    /// selected ones jump through are exported too.
+    fn export_ssh_config(&self, names: &[String], tags: &[String], output: Option<&std::path::Path>) -> Result<()> {
+        let mut queue: Vec<String> = if names.is_empty() && tags.is_empty() {
+            self.config.profiles.keys().cloned().collect()
+        } else {
+            self.select_profiles(names, tags)?
+        };
+        let mut profiles = BTreeMap::new();
+        while let Some(name) = queue.pop() {
+            if profiles.contains_key(&name) {
+                continue;
+            }
+            // Hops that aren't profiles are plain addresses
+            let Some(stored) = self.config.profiles.get(&name) else {
+                continue;
+            };
+            let host_config = self.with_defaults(&name, stored).0;
+            queue.extend(host_config.jump.iter().chain(&host_config.via).cloned());
+            profiles.insert(name, host_config);
+        }
+        let fragment = self.ssh_config_fragment(&profiles)?;
+
+        let Some(output) = output else {
+            print!("{}", fragment);
+            return Ok(());
+        };
+        let path = PathBuf::from(expand_tilde(&output.to_string_lossy()));
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        fs::write(&path, &fragment).with_context(|| format!("Failed to write {}", path.display()))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to set permissions on {}", path.display()))?;
+        out().success(&format!("Wrote {} profiles to {}", profiles.len(), path.display()));
+        out().info(&format!("Add 'Include {}' at the top of ~/.ssh/config to use them", path.display()));
+        Ok(())
+    }
+
+    /// Copies profiles onto the host of `profile`, so the same short names
+    /// work there for the next hop: as masuk's config or, with
+    /// `ssh_config`, as an ssh_config fragment. `install` also copies this
+    /// masuk binary over.
+    fn seed(&self, profile: &str, names: &[String], tags: &[String], ssh_config: bool, install: bool) -> Result<()> {
+        let target = self.resolve(profile)?;
+        let selected = if names.is_empty() && tags.is_empty() {
+            self.config.profiles.keys().cloned().collect()
+        } else {
+            self.select_profiles(names, tags)?
+        };
+        let profiles = self.seeded_profiles(profile, selected);
+        if profiles.is_empty() {
+            return Err(anyhow!("No profiles to seed onto '{}'", profile));
+        }
+
+        if install {
+            let uname = self.remote_output(profile, &target, "uname -sm", &[])?;
+            let local = format!("{} {}", env::consts::OS, env::consts::ARCH);
+            if !same_platform(&uname, &local) {
+                return Err(anyhow!(
+                    "'{}' runs {}, this masuk is built for {}. Install masuk there yourself, or use --ssh-config",
+                    profile,
+                    uname.trim(),
+                    local
+                ));
+            }
+            let binary = env::current_exe().context("Failed to find the masuk binary")?;
+            let binary = fs::read(&binary).with_context(|| format!("Failed to read {}", binary.display()))?;
+            self.remote_output(
+                profile,
+                &target,
+                "mkdir -p ~/.local/bin && cat > ~/.local/bin/masuk.new && chmod 755 ~/.local/bin/masuk.new \
+                 && mv ~/.local/bin/masuk.new ~/.local/bin/masuk",
+                &binary,
+            )?;
+            out().success(&format!("Installed masuk in ~/.local/bin on {}", profile));
+        }
+
+        if ssh_config {
+            let fragment = self.ssh_config_fragment(&profiles)?;
+            // Include has to come before any Host block to apply everywhere
+            self.remote_output(
+                profile,
+                &target,
+                "mkdir -p -m 700 ~/.ssh && cat > ~/.ssh/masuk_config && chmod 600 ~/.ssh/masuk_config \
+                 && { grep -qx 'Include masuk_config' ~/.ssh/config 2>/dev/null \
+                 || { { echo 'Include masuk_config'; echo; cat ~/.ssh/config 2>/dev/null || true; } > ~/.ssh/config.masuk \
+                 && chmod 600 ~/.ssh/config.masuk && mv ~/.ssh/config.masuk ~/.ssh/config; }; }",
+                fragment.as_bytes(),
+            )?;
+            out().success(&format!("Wrote {} profiles to ~/.ssh/masuk_config on {}", profiles.len(), profile));
+        } else {
+            let config = Config {
+                profiles: profiles.into_iter().collect(),
+                ..Config::default()
+            };
+            let json = serde_json::to_string_pretty(&config).context("Failed to serialize profiles")?;
+            self.remote_output(
+                profile,
+                &target,
+                "mkdir -p ~/.config/masuk && { [ ! -e ~/.config/masuk/config.json ] \
+                 || cp ~/.config/masuk/config.json ~/.config/masuk/config.json.bak; } \
+                 && cat > ~/.config/masuk/config.json",
+                json.as_bytes(),
+            )?;
+            out().success(&format!("Seeded {} profiles onto {}", config.profiles.len(), profile));
+        }
+        Ok(())
+    }
+
+    /// The profiles to copy onto `target`, with the profiles they jump
+    /// through. Hops up to `target` are dropped, the copies are used from
+    /// there, and so are keys, secrets and hooks, which only mean something
+    /// on this machine.
+    fn seeded_profiles(&self, target: &str, selected: Vec<String>) -> BTreeMap<String, HostConfig> {
+        let mut queue = selected;
+        let mut seeded = BTreeMap::new();
+        while let Some(name) = queue.pop() {
+            if name == target || seeded.contains_key(&name) {
+                continue;
+            }
+            // Hops that aren't profiles are plain addresses
+            let Some(stored) = self.config.profiles.get(&name) else {
+                continue;
+            };
+            let mut host_config = self.with_defaults(&name, stored).0;
+            let mut hops: Vec<String> = host_config.jump.take().into_iter().chain(host_config.via.drain(..)).collect();
+            if let Some(i) = hops.iter().position(|hop| hop == target) {
+                hops.drain(..=i);
+            }
+            queue.extend(hops.iter().cloned());
+            let mut hops = hops.into_iter();
+            host_config.jump = hops.next();
+            host_config.via = hops.collect();
+
+            host_config.keys.clear();
+            host_config.certificate_file = None;
+            host_config.known_hosts_file = None;
+            host_config.identity_agent = None;
+            host_config.local_dir = None;
+            host_config.remote_forwards = Vec::new();
+            host_config.vault_role = None;
+            host_config.vault_mount = None;
+            host_config.vault_mode = None;
+            host_config.password = None;
+            host_config.key_passphrase = None;
+            host_config.totp = None;
+            host_config.sudo_password = None;
+            host_config.pre_connect = None;
+            host_config.post_connect = None;
+            seeded.insert(name, host_config);
+        }
+        seeded
+    }
+
+    /// Runs `script` on the host with `input` on its stdin, returning what
+    /// it printed.
+    fn remote_output(&self, profile: &str, host_config: &HostConfig, script: &str, input: &[u8]) -> Result<String> {
+        let mut command = self.ssh_command(profile, host_config)?;
+        command.insert(1, "-T".to_string());
+        command.push(script.to_string());
+        let mut child = Command::new(&command[0])
+            .args(&command[1..])
+            .envs(askpass_env(host_config))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to execute SSH command")?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input)?;
+        }
+        let output = child.wait_with_output().context("Failed to execute SSH command")?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Command failed on '{}': {}",
+                profile,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn snapshots_dir(&self) -> PathBuf {
+        self.config_dir().join("snapshots")
+    }
+
+    fn snapshot_create(&self, name: Option<String>) -> Result<()> {
+        let dir = self.snapshots_dir();
+        let taken_at = unix_time() as i64;
+        let name = match name {
+            Some(name) => {
+                if name.is_empty() || name.contains('/') || name.starts_with('.') {
+                    return Err(anyhow!("'{}' can't be used as a snapshot name", name));
+                }
+                if dir.join(format!("{}.json", name)).exists() {
+                    return Err(anyhow!("Snapshot '{}' already exists, pick another name", name));
+                }
+                name
+            }
+            // Several snapshots on one day get numbered
+            None => {
+                let date = format_date(taken_at);
+                let mut name = date.clone();
+                let mut n = 2;
+                while dir.join(format!("{}.json", name)).exists() {
+                    name = format!("{}-{}", date, n);
+                    n += 1;
+                }
+                name
+            }
+        };
+
+        let snapshot = snapshot::Snapshot {
+            name: name.clone(),
+            taken_at,
+            profiles: self.config.profiles.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+        };
+        snapshot.save(&dir)?;
+        out().success(&format!("Saved snapshot '{}' with {} profiles", name, snapshot.profiles.len()));
+        Ok(())
+    }
+
+    fn snapshot_list(&self) -> Result<()> {
+        let items: Vec<Item> = snapshot::list(&self.snapshots_dir())?
+            .into_iter()
+            .map(|snapshot| Item {
+                description: format!("{}, {} profiles", format_date(snapshot.taken_at), snapshot.profiles.len()),
+                data: json!({ "name": snapshot.name, "taken_at": snapshot.taken_at, "profiles": snapshot.profiles.len() }),
+                name: snapshot.name,
+            })
+            .collect();
+        out().list("Snapshots", &items, "No snapshots yet. Use 'masuk snapshot create' to take one.");
+        Ok(())
+    }
+
+    fn snapshot_compare(&self, from: &str, to: Option<&str>, markdown: bool) -> Result<()> {
+        let dir = self.snapshots_dir();
+        let before = snapshot::Snapshot::load(&dir, from)?;
+        let (after, to_title) = match to {
+            Some(to) => {
+                let after = snapshot::Snapshot::load(&dir, to)?;
+                let title = format!("snapshot '{}' ({})", to, format_date(after.taken_at));
+                (after.profiles, title)
+            }
+            None => (
+                self.config.profiles.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+                format!("the current inventory ({})", format_date(unix_time() as i64)),
+            ),
+        };
+        let drift = snapshot::Drift::between(&before.profiles, &after)?;
+        let title = format!("Drift from snapshot '{}' ({}) to {}", from, format_date(before.taken_at), to_title);
+
+        if markdown {
+            print!("{}", drift.to_markdown(&title));
+            return Ok(());
+        }
+
+        let mut fields: Vec<(String, String)> = Vec::new();
+        for (name, host_config) in &drift.added {
+            fields.push(("added".to_string(), format!("{} → {}", name, host_config.describe())));
+        }
+        for (name, host_config) in &drift.removed {
+            fields.push(("removed".to_string(), format!("{} → {}", name, host_config.describe())));
+        }
+        for (name, changes) in &drift.changed {
+            for change in changes {
+                fields.push((
+                    "changed".to_string(),
+                    format!(
+                        "{} {}: {} → {}",
+                        name,
+                        change.setting,
+                        snapshot::show(&change.before),
+                        snapshot::show(&change.after)
+                    ),
+                ));
+            }
+        }
+        if fields.is_empty() {
+            fields.push(("drift".to_string(), "none".to_string()));
+        }
+        let mut data = drift.to_json();
+        data["from"] = json!(from);
+        data["to"] = json!(to);
+        out().record(&title, &fields, &data);
+        Ok(())
+    }
+
+    /// Prints a URL for the profile, for file managers and other URL-aware tools.
+    fn url(&self, profile: &str, path: Option<&str>, scheme: UrlScheme) -> Result<()> {
+        let host_config = self.resolve(profile)?;
+
+        let name = match scheme {
+            UrlScheme::Ssh => "ssh",
+            UrlScheme::Sftp => "sftp",
+            UrlScheme::Rsync => "rsync",
+        };
+        let mut url = format!("{}://", name);
+        if let Some(ref user) = host_config.user {
+            url.push_str(&percent_encode(user));
+            url.push('@');
+        }
+        if host_config.host.contains(':') {
+            url.push_str(&format!("[{}]", host_config.host));
+        } else {
+            url.push_str(&host_config.host);
+        }
+        if let Some(port) = host_config.port {
+            url.push_str(&format!(":{}", port));
+        }
+        if let Some(path) = path {
+            if scheme == UrlScheme::Ssh {
+                return Err(anyhow!("ssh:// URLs can't carry a path. Use --scheme sftp or rsync."));
+            }
+            if !path.starts_with('/') {
+                url.push('/');
+            }
+            url.push_str(&path.split('/').map(percent_encode).collect::<Vec<_>>().join("/"));
+        }
+
+        if host_config.jump.is_some() || !host_config.via.is_empty() {
+            eprintln!("Note: URLs can't express jump hosts, tools using this URL will connect directly.");
+        }
+        println!("{}", url);
+        Ok(())
+    }
+
+    /// Watches the remote host for servers listening on ports in `range`
+    /// and forwards each one to the same local port until interrupted, like
+    /// the port auto-forwarding of editors with remote support.
+    fn forward_auto(&self, profile: &str, range: RangeInclusive<u16>, interval: u64) -> Result<()> {
+        let host_config = self.resolve(profile)?;
+        self.create_sockets_dir()?;
+        let command = self.ssh_command(profile, &host_config)?;
+        let control = |args: &[&str]| {
+            let mut control = command.clone();
+            control.splice(1..1, args.iter().map(|arg| arg.to_string()));
+            Command::new(&control[0])
+                .args(&control[1..])
+                .stdin(Stdio::null())
+                .output()
+                .context("Failed to execute SSH command")
+        };
+
+        // Polls and forwards all go through one master connection
+        let started_master = !control(&["-O", "check"])?.status.success();
+        if started_master {
+            let status = Command::new(&command[0])
+                .args(["-f", "-N", "-o", "ControlMaster=auto", "-o", "ControlPersist=10m"])
+                .args(&command[1..])
+                .stdin(Stdio::null())
+                .status()
+                .context("Failed to execute SSH command")?;
+            if !status.success() {
+                return Err(anyhow!("Failed to connect to '{}'", profile));
+            }
+        }
+
+        out().info(&format!(
+            "Watching {} for servers on ports {}-{} (Ctrl-C to stop)...",
+            profile,
+            range.start(),
+            range.end()
+        ));
+        let mut forwarded: BTreeMap<u16, String> = BTreeMap::new();
+        let mut unavailable = HashSet::new();
+        while !interrupt::requested() {
+            let mut poll = command.clone();
+            poll.push("ss -ltn".to_string());
+            let listening = match Command::new(&poll[0]).args(&poll[1..]).stdin(Stdio::null()).output() {
+                Ok(output) if output.status.success() => listening_ports(&String::from_utf8_lossy(&output.stdout)),
+                _ => {
+                    if !interrupt::requested() {
+                        eprintln!("{}", plain(&format!("✗ Failed to list listening ports on {}", profile)));
+                    }
+                    BTreeMap::new()
+                }
+            };
+
+            for (&port, address) in listening.range(range.clone()) {
+                if forwarded.contains_key(&port) || unavailable.contains(&port) {
+                    continue;
+                }
+                let spec = format!("{}:{}:{}", port, address, port);
+                if control(&["-O", "forward", "-L", &spec])?.status.success() {
+                    out().success(&format!("Forwarding localhost:{} → {}:{}", port, profile, port));
+                    forwarded.insert(port, spec);
+                } else {
+                    eprintln!("{}", plain(&format!("✗ Could not forward port {}, is it in use locally?", port)));
+                    unavailable.insert(port);
+                }
+            }
+
+            let closed: Vec<u16> = forwarded.keys().copied().filter(|p| !listening.contains_key(p)).collect();
+            for port in closed {
+                if let Some(spec) = forwarded.remove(&port) {
+                    control(&["-O", "cancel", "-L", &spec])?;
+                    out().success(&format!("Stopped forwarding port {}", port));
+                }
+            }
+            unavailable.retain(|p| listening.contains_key(p));
+
+            let deadline = Instant::now() + Duration::from_secs(interval);
+            while Instant::now() < deadline && !interrupt::requested() {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+
+        for spec in forwarded.values() {
+            control(&["-O", "cancel", "-L", spec])?;
+        }
+        if started_master {
+            control(&["-O", "exit"])?;
+        }
+        interrupt::check()
+    }
+
+    /// Opens the forwardings of `options` and the named tunnels of the
+    /// profile through it (`ssh -N`), or the profile's saved remote
+    /// forwardings when there are none. Runs in the foreground until
+    /// interrupted, or in the background until stopped. With `persist`
+    /// the connection is watched and reopened when it drops.
+    fn tunnel(&self, profile: &str, options: &TunnelOptions) -> Result<()> {
+        let mut host_config = self.transfer_host_config(profile, !options.dry_run)?;
+        let mut local = options.local.clone();
+        for name in &options.names {
+            let spec = host_config.tunnels.get(name).ok_or_else(|| match host_config.tunnels.is_empty() {
+                true => anyhow!("Profile '{}' has no tunnels, add one with 'masuk set {} --tunnel {}=PORT:HOST:PORT'", profile, profile, name),
+                false => {
+                    let names: Vec<&str> = host_config.tunnels.keys().map(String::as_str).collect();
+                    anyhow!("Profile '{}' has no tunnel '{}', it has: {}", profile, name, names.join(", "))
+                }
+            })?;
+            local.push(spec.clone());
+        }
+        let remote = match local.is_empty() && options.remote.is_empty() && options.dynamic.is_empty() {
+            true => host_config.remote_forwards.clone(),
+            false => options.remote.clone(),
+        };
+        if local.is_empty() && remote.is_empty() && options.dynamic.is_empty() {
+            return Err(anyhow!(
+                "No forwardings given. Use -L, -R or -D, or save some with 'masuk set {} --tunnel' or '--remote-forward'",
+                profile
+            ));
+        }
+        if options.install_service || options.remove_service {
+            return self.tunnel_service(profile, options);
+        }
+        if options.persist {
+            // Keep-alives notice dead connections that never close
+            host_config.keepalive_interval = host_config.keepalive_interval.or(Some(15));
+            host_config.keepalive_count = host_config.keepalive_count.or(Some(3));
+        }
+        let client = self.ssh_client(&host_config)?;
+        let (mut command, target) = self.ssh_transport(profile, &host_config)?;
+        let mut forwards = Vec::new();
+        for spec in &local {
+            forwards.push("-L".to_string());
+            forwards.push(spec.clone());
+        }
+        for spec in &remote {
+            forwards.push("-R".to_string());
+            forwards.push(spec.clone());
+        }
+        for port in &options.dynamic {
+            forwards.push("-D".to_string());
+            forwards.push(port.to_string());
+        }
+
+        // A connection of its own, so the tunnel doesn't die with a master.
+        // In the background or when persisting it's a master itself, only
+        // to learn the pid of the process ssh forks into.
+        let recorded = self.tunnels();
+        let supervised: Option<u32> = env::var("MASUK_TUNNEL_ID").ok().and_then(|id| id.parse().ok());
+        let id = supervised.unwrap_or_else(|| (1..).find(|id| !recorded.iter().any(|tunnel| tunnel.id == *id)).unwrap_or(1));
+        let socket = match options.background || supervised.is_some() {
+            true => self.state_dir.join("tunnels").join(id.to_string()),
+            false => self.state_dir.join("tunnels").join(format!("p{}", std::process::id())),
+        };
+        let forks = options.background || options.persist;
+        let mut tunnel = vec!["-N".to_string()];
+        if forks {
+            tunnel.extend(["-f".to_string(), "-o".to_string(), "ControlMaster=yes".to_string()]);
+            tunnel.extend(["-o".to_string(), "ControlPersist=no".to_string()]);
+            tunnel.extend(["-o".to_string(), format!("ControlPath={}", socket.display())]);
+        } else {
+            tunnel.extend(["-o".to_string(), "ControlPath=none".to_string()]);
+        }
+        tunnel.extend(["-o".to_string(), "ExitOnForwardFailure=yes".to_string()]);
+        if options.gateway_ports {
+            tunnel.extend(["-o".to_string(), "GatewayPorts=yes".to_string()]);
+        }
+        tunnel.extend(forwards.iter().cloned());
+        command.splice(client.len()..client.len(), tunnel);
+        command.push(target);
+        if options.dry_run {
+            println!("{}", shell_join(&command));
+            return Ok(());
+        }
+
+        let mut endpoints: Vec<String> = local
+            .iter()
+            .map(|spec| {
+                let (listen, destination) = forward_endpoints(spec);
+                format!("{} → {} (on {})", listen, destination, profile)
+            })
+            .collect();
+        endpoints.extend(remote.iter().map(|spec| {
+            let (listen, destination) = forward_endpoints(spec);
+            format!("{} (on {}) → {}", listen, profile, destination)
+        }));
+        endpoints.extend(
+            options
+                .dynamic
+                .iter()
+                .map(|port| format!("localhost:{} → SOCKS proxy (on {})", port, profile)),
+        );
+        let show_endpoints = || {
+            for endpoint in &endpoints {
+                out().info(&format!("  {}", endpoint));
+            }
+            if let Some(port) = options.dynamic.first() {
+                out().info(&format!(
+                    "Set the SOCKS5 proxy of your browser to localhost:{}, or use socks5h://localhost:{} as proxy URL.",
+                    port, port
+                ));
+            }
+        };
+
+        if !forks {
+            out().info(&format!("Tunnel to {} (Ctrl-C to close):", profile));
+            show_endpoints();
+            let status = Command::new(&command[0])
+                .args(&command[1..])
+                .envs(&host_config.env)
+                .envs(askpass_env(&host_config))
+                .status()
+                .context("Failed to execute SSH command")?;
+            interrupt::check()?;
+            if !status.success() {
+                return Err(ChildExit(exit_code(status)).into());
+            }
+            return Ok(());
+        }
+
+        if let Some(pid) = supervised.and_then(|_| tunnel_pid(&socket)) {
+            // Started by 'masuk tunnel --persist --background', which made
+            // the first connection itself
+            return self.supervise_tunnel(profile, &command, &host_config, &socket, pid, options.notify);
+        }
+        if options.persist && !options.background {
+            out().info(&format!("Tunnel to {} (Ctrl-C to close), reopened when it drops:", profile));
+            show_endpoints();
+        }
+        let pid = start_tunnel(&command, &host_config, &socket)?;
+        if options.persist && !options.background {
+            let pid = pid.ok_or_else(|| anyhow!("Could not find the process of the tunnel to watch it"))?;
+            return self.supervise_tunnel(profile, &command, &host_config, &socket, pid, options.notify);
+        }
+
+        let pid = match (pid, options.persist) {
+            (Some(pid), true) => {
+                // The watcher runs detached, from a copy of this command line
+                let log = fs::File::create(socket.with_extension("log")).context("Failed to create the tunnel log")?;
+                let mut watcher = Command::new(env::current_exe().context("Failed to find the masuk executable")?);
+                watcher.args(["tunnel", profile]).args(&forwards).arg("--persist");
+                if options.gateway_ports {
+                    watcher.arg("--gateway-ports");
+                }
+                if options.notify {
+                    watcher.arg("--notify");
+                }
+                let spawned = unsafe {
+                    use std::os::unix::process::CommandExt;
+                    watcher
+                        .env("MASUK_TUNNEL_ID", id.to_string())
+                        .stdin(Stdio::null())
+                        .stdout(log.try_clone()?)
+                        .stderr(log)
+                        .pre_exec(|| {
+                            libc::setsid();
+                            Ok(())
+                        })
+                        .spawn()
+                };
+                match spawned {
+                    Ok(child) => Some(child.id()),
+                    Err(e) => {
+                        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+                        return Err(anyhow!("Failed to start watching the tunnel: {}", e));
+                    }
+                }
+            }
+            (pid, _) => pid,
+        };
+        match pid {
+            Some(pid) => {
+                let mut tunnels = recorded;
+                tunnels.push(BackgroundTunnel {
+                    id,
+                    profile: profile.to_string(),
+                    pid,
+                    forwards: forwards.chunks(2).map(|forward| forward.join(" ")).collect(),
+                    persist: options.persist,
+                    gateway_ports: options.gateway_ports,
+                    notify: options.notify,
+                    started_at: unix_time(),
+                });
+                self.save_tunnels(&tunnels)?;
+                out().success(&format!(
+                    "Tunnel {} to {} running in the background{}, stop it with 'masuk tunnel stop {}'",
+                    id,
+                    profile,
+                    if options.persist { " and reopened when it drops" } else { "" },
+                    id
+                ));
+            }
+            None => {
+                out().success(&format!("Tunnel to {} running in the background", profile));
+                eprintln!("Could not find the process of the tunnel, it won't be listed by 'masuk tunnel ls'");
+            }
+        }
+        show_endpoints();
+        Ok(())
+    }
+
+    /// Installs (and starts) or removes a service that runs the tunnel of
+    /// `options` at login: a systemd user unit, or a launchd agent on macOS.
+    /// Restarting when the tunnel exits is left to the service manager.
+    fn tunnel_service(&self, profile: &str, options: &TunnelOptions) -> Result<()> {
+        let mut name = format!("masuk-tunnel-{}", profile);
+        for tunnel in &options.names {
+            name.push('-');
+            name.push_str(tunnel);
+        }
+        let name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '-' })
+            .collect();
+        let home = dirs::home_dir().context("Could not find home directory")?;
+        let macos = cfg!(target_os = "macos");
+        let path = match macos {
+            true => home.join("Library/LaunchAgents").join(format!("{}.plist", name)),
+            false => dirs::config_dir()
+                .unwrap_or_else(|| home.join(".config"))
+                .join("systemd/user")
+                .join(format!("{}.service", name)),
+        };
+        let service_manager = |args: &[&str]| -> Result<()> {
+            let status = match macos {
+                true => Command::new("launchctl").args(args).arg(&path).status(),
+                false => Command::new("systemctl").arg("--user").args(args).arg(&name).status(),
+            }
+            .context("Failed to run the service manager")?;
+            if !status.success() {
+                return Err(anyhow!("{} {} failed", if macos { "launchctl" } else { "systemctl --user" }, args.join(" ")));
+            }
+            Ok(())
+        };
+
+        if options.remove_service {
+            if !path.exists() {
+                return Err(anyhow!("No service installed at {}", path.display()));
+            }
+            if options.dry_run {
+                println!("rm {}", shell_quote(&path.display().to_string()));
+                return Ok(());
+            }
+            // Stopping fails when it's already stopped, which is fine
+            let _ = service_manager(if macos { &["unload", "-w"] } else { &["disable", "--now"] });
+            fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+            if !macos {
+                let _ = Command::new("systemctl").args(["--user", "daemon-reload"]).status();
+            }
+            out().success(&format!("Removed service {}", name));
+            return Ok(());
+        }
+
+        let mut command = vec![
+            env::current_exe()
+                .context("Failed to find the masuk executable")?
+                .display()
+                .to_string(),
+            "tunnel".to_string(),
+            profile.to_string(),
+        ];
+        command.extend(options.names.iter().cloned());
+        for (flag, specs) in [("-L", &options.local), ("-R", &options.remote)] {
+            for spec in specs {
+                command.push(flag.to_string());
+                command.push(spec.clone());
+            }
+        }
+        for port in &options.dynamic {
+            command.push("-D".to_string());
+            command.push(port.to_string());
+        }
+        if options.gateway_ports {
+            command.push("--gateway-ports".to_string());
+        }
+        if options.persist {
+            command.push("--persist".to_string());
+        }
+        if options.notify {
+            command.push("--notify".to_string());
+        }
+        let config = fs::canonicalize(&self.config_path).unwrap_or_else(|_| self.config_path.clone());
+        let description = match options.names.is_empty() {
+            true => format!("masuk tunnel to {}", profile),
+            false => format!("masuk tunnel {} to {}", options.names.join(", "), profile),
+        };
+
+        let content = if macos {
+            let escape = |text: &str| text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+            let arguments: String = command
+                .iter()
+                .map(|arg| format!("        <string>{}</string>\n", escape(arg)))
+                .collect();
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+                 <plist version=\"1.0\">\n\
+                 <dict>\n\
+                 \x20   <key>Label</key>\n\
+                 \x20   <string>{}</string>\n\
+                 \x20   <key>ProgramArguments</key>\n\
+                 \x20   <array>\n{}\x20   </array>\n\
+                 \x20   <key>EnvironmentVariables</key>\n\
+                 \x20   <dict>\n\
+                 \x20       <key>MASUK_CONFIG</key>\n\
+                 \x20       <string>{}</string>\n\
+                 \x20   </dict>\n\
+                 \x20   <key>RunAtLoad</key>\n\
+                 \x20   <true/>\n\
+                 \x20   <key>KeepAlive</key>\n\
+                 \x20   <true/>\n\
+                 \x20   <key>ThrottleInterval</key>\n\
+                 \x20   <integer>10</integer>\n\
+                 </dict>\n\
+                 </plist>\n",
+                escape(&name),
+                arguments,
+                escape(&config.display().to_string())
+            )
+        } else {
+            let exec: Vec<String> = command.iter().map(|arg| systemd_quote(arg)).collect();
+            format!(
+                "# Written by 'masuk tunnel --install-service', remove with --remove-service\n\
+                 [Unit]\n\
+                 Description={}\n\
+                 After=network-online.target\n\
+                 Wants=network-online.target\n\
+                 \n\
+                 [Service]\n\
+                 Environment={}\n\
+                 ExecStart={}\n\
+                 Restart=always\n\
+                 RestartSec=10\n\
+                 \n\
+                 [Install]\n\
+                 WantedBy=default.target\n",
+                description,
+                systemd_quote(&format!("MASUK_CONFIG={}", config.display())),
+                exec.join(" ")
+            )
+        };
+        if options.dry_run {
+            print!("{}", content);
+            return Ok(());
+        }
+
+        let updating = path.exists();
+        let dir = path.parent().context("Service file has no directory")?;
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        fs::write(&path, content).with_context(|| format!("Failed to write {}", path.display()))?;
+        if macos {
+            // Loading again after an update needs the old one unloaded
+            if updating {
+                let _ = Command::new("launchctl").arg("unload").arg(&path).output();
+            }
+            service_manager(&["load", "-w"])?;
+        } else {
+            let _ = Command::new("systemctl").args(["--user", "daemon-reload"]).status();
+            service_manager(&["enable", "--now"])?;
+            if updating {
+                service_manager(&["restart"])?;
+            }
+        }
+        out().success(&format!("Installed service {} at {}, running now and at every login", name, path.display()));
+        Ok(())
+    }
+
+    /// Watches the ssh process `pid` of a tunnel and reopens the tunnel
+    /// with `command` when it exits, waiting longer after every failed
+    /// attempt. Runs until interrupted, then closes the tunnel.
+    fn supervise_tunnel(
+        &self,
+        profile: &str,
+        command: &[String],
+        host_config: &HostConfig,
+        socket: &std::path::Path,
+        mut pid: u32,
+        notify: bool,
+    ) -> Result<()> {
+        let backoff = host_config.retry_backoff.unwrap_or(5);
+        let wait = |duration: Duration| {
+            let deadline = Instant::now() + duration;
+            while Instant::now() < deadline && !interrupt::requested() {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        };
+        // Waits for the next attempt, cut short when the host's ssh port was
+        // closed (rebooting) and accepts connections again
+        let wait_for_host = |duration: Duration| {
+            let deadline = Instant::now() + duration;
+            let mut was_closed = false;
+            while Instant::now() < deadline && !interrupt::requested() {
+                match ssh_port_open(host_config) {
+                    Some(false) => was_closed = true,
+                    Some(true) if was_closed => {
+                        eprintln!("{} accepts connections again", profile);
+                        return;
+                    }
+                    _ => {}
+                }
+                wait(Duration::from_secs(2).min(deadline.saturating_duration_since(Instant::now())));
+            }
+        };
+        let mut boot = boot_id(socket);
+        loop {
+            while process_alive(pid) && !interrupt::requested() {
+                wait(Duration::from_secs(1));
+            }
+            if interrupt::requested() {
+                unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+                let _ = fs::remove_file(socket);
+                return interrupt::check();
+            }
+
+            let message = format!("Tunnel to {} went down, reopening it", profile);
+            eprintln!("{}", plain(&format!("✗ {}", message)));
+            if notify {
+                desktop_notification(&message);
+            }
+            let mut attempt = 0;
+            pid = loop {
+                attempt += 1;
+                let delay = backoff.saturating_mul(1 << (attempt - 1).min(16)).min(300);
+                eprintln!("Reconnecting in {} (attempt {})...", format_duration(delay), attempt);
+                wait_for_host(Duration::from_secs(delay));
+                interrupt::check()?;
+                match start_tunnel(command, host_config, socket) {
+                    Ok(Some(pid)) => break pid,
+                    Ok(None) => return Err(anyhow!("Could not find the process of the tunnel to watch it")),
+                    Err(_) => interrupt::check()?,
+                }
+            };
+            // A different boot id means the host restarted while it was gone
+            let now = boot_id(socket);
+            let rebooted = boot.is_some() && now.is_some() && now != boot;
+            boot = now.or(boot);
+            let message = match rebooted {
+                true => format!("{} restarted, its tunnel is back up", profile),
+                false => format!("Tunnel to {} is back up", profile),
+            };
+            eprintln!("{}", plain(&format!("✓ {}", message)));
+            if notify {
+                desktop_notification(&message);
+            }
+        }
+    }
+
+    /// Lists the tunnels running in the background, and mentions those
+    /// that aren't anymore.
+    fn tunnel_list(&self) -> Result<()> {
+        let (mut tunnels, down): (Vec<_>, Vec<_>) = self.tunnels().into_iter().partition(|tunnel| process_alive(tunnel.pid));
+        tunnels.sort_by_key(|tunnel| tunnel.id);
+        if !down.is_empty() {
+            let ids: Vec<String> = down.iter().map(|tunnel| format!("{} ({})", tunnel.id, tunnel.profile)).collect();
+            eprintln!(
+                "Not running anymore: {}. Reopen them with 'masuk tunnel restore', or forget them with 'masuk tunnel stop'.",
+                ids.join(", ")
+            );
+        }
+        if tunnels.is_empty() {
+            out().info("No tunnels in the background. Use 'masuk tunnel <profile> -L ... --background' to start one.");
+            return Ok(());
+        }
+        let rows: Vec<Vec<String>> = tunnels
+            .iter()
+            .map(|tunnel| {
+                vec![
+                    tunnel.id.to_string(),
+                    tunnel.profile.clone(),
+                    tunnel.pid.to_string(),
+                    if tunnel.persist { "yes" } else { "no" }.to_string(),
+                    tunnel.forwards.join(", "),
+                ]
+            })
+            .collect();
+        out().table(&["ID", "PROFILE", "PID", "PERSIST", "FORWARDS"], &rows, &serde_json::to_value(&tunnels)?);
+        Ok(())
+    }
+
+    /// Stops background tunnels by id or profile, or all of them. Those
+    /// that aren't running anymore are forgotten.
+    fn tunnel_stop(&self, targets: &[String], all: bool) -> Result<()> {
+        let mut tunnels = self.tunnels();
+        for target in targets {
+            if !tunnels.iter().any(|tunnel| tunnel.id.to_string() == *target || tunnel.profile == *target) {
+                eprintln!("No tunnel '{}' running in the background", target);
+            }
+        }
+        let (stop, keep): (Vec<_>, Vec<_>) = tunnels
+            .drain(..)
+            .partition(|tunnel| all || targets.iter().any(|target| tunnel.id.to_string() == *target || tunnel.profile == *target));
+        for tunnel in &stop {
+            if !process_alive(tunnel.pid) {
+                out().success(&format!("Forgot tunnel {} to {}, it wasn't running", tunnel.id, tunnel.profile));
+                continue;
+            }
+            if unsafe { libc::kill(tunnel.pid as libc::pid_t, libc::SIGTERM) } != 0 {
+                eprintln!("Failed to stop tunnel {} (pid {}): {}", tunnel.id, tunnel.pid, std::io::Error::last_os_error());
+                continue;
+            }
+            let _ = fs::remove_file(self.state_dir.join("tunnels").join(tunnel.id.to_string()));
+            out().success(&format!("Stopped tunnel {} to {}", tunnel.id, tunnel.profile));
+        }
+        self.save_tunnels(&keep)
+    }
+
+    /// Reopens background tunnels that aren't running anymore, e.g. after
+    /// this machine restarted or woke up, all of them or those with the
+    /// given ids or profiles. Tunnels that fail to open stay recorded for
+    /// the next try.
+    fn tunnel_restore(&self, targets: &[String]) -> Result<()> {
+        let (down, running): (Vec<_>, Vec<_>) = self.tunnels().into_iter().partition(|tunnel| !process_alive(tunnel.pid));
+        let selected = |tunnel: &BackgroundTunnel| {
+            targets.is_empty() || targets.iter().any(|target| tunnel.id.to_string() == *target || tunnel.profile == *target)
+        };
+        let (restore, keep): (Vec<_>, Vec<_>) = down.into_iter().partition(|tunnel| selected(tunnel));
+        if restore.is_empty() {
+            out().info("All background tunnels are running, nothing to reopen.");
+            return Ok(());
+        }
+        // Reopened tunnels record themselves again, under a new id
+        self.save_tunnels(&running.into_iter().chain(keep).collect::<Vec<_>>())?;
+
+        let mut failed = Vec::new();
+        for tunnel in restore {
+            let mut options = TunnelOptions {
+                names: Vec::new(),
+                local: Vec::new(),
+                remote: Vec::new(),
+                dynamic: Vec::new(),
+                gateway_ports: tunnel.gateway_ports,
+                background: true,
+                persist: tunnel.persist,
+                notify: tunnel.notify,
+                install_service: false,
+                remove_service: false,
+                dry_run: false,
+            };
+            for forward in &tunnel.forwards {
+                match forward.split_once(' ') {
+                    Some(("-L", spec)) => options.local.push(spec.to_string()),
+                    Some(("-R", spec)) => options.remote.push(spec.to_string()),
+                    Some(("-D", port)) => options.dynamic.extend(port.parse::<u16>().ok()),
+                    _ => {}
+                }
+            }
+            if let Err(e) = self.tunnel(&tunnel.profile, &options) {
+                interrupt::check()?;
+                eprintln!("{}", plain(&format!("✗ Tunnel {} to {}: {:#}", tunnel.id, tunnel.profile, e)));
+                failed.push(tunnel);
+            }
+        }
+        if !failed.is_empty() {
+            let count = failed.len();
+            let mut tunnels = self.tunnels();
+            tunnels.extend(failed);
+            self.save_tunnels(&tunnels)?;
+            return Err(anyhow!("{} tunnel{} could not be reopened", count, if count == 1 { "" } else { "s" }));
+        }
+        Ok(())
+    }
+
+    /// The tunnels started with `--background`, including those that
+    /// aren't running anymore.
+    fn tunnels(&self) -> Vec<BackgroundTunnel> {
+        fs::read_to_string(self.state_dir.join("tunnels.json"))
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default()
+    }
+
+    fn save_tunnels(&self, tunnels: &[BackgroundTunnel]) -> Result<()> {
+        let path = self.state_dir.join("tunnels.json");
+        let data = serde_json::to_string_pretty(tunnels).context("Failed to serialize tunnels")?;
+        fs::create_dir_all(&self.state_dir).context("Failed to create config directory")?;
+        fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Replaces the known_hosts entries of a reinstalled host: fetches the
+    /// new key, asks for confirmation, swaps the entries, pins the new
+    /// fingerprint and verifies that login works. Entries live in the
+    /// profile's known_hosts file, `~/.ssh/known_hosts` unless it has one.
+    fn hostkey_rollover(&mut self, profile: &str, yes: bool) -> Result<()> {
+        let host_config = self.resolve(profile)?;
+        if host_config.host_key_checking == Some(HostKeyChecking::Off) {
+            return Err(anyhow!(
+                "Profile '{}' has host key checking off, so no known_hosts file is used and logging in proves nothing. \
+                 Turn it on with 'masuk set {} --host-key-checking strict' first.",
+                profile,
+                profile
+            ));
+        }
+        let command = self.ssh_command(profile, &host_config)?;
+
+        // Let ssh record the new key in a throwaway file, going through jump
+        // hosts like a normal connection. Authentication may fail, the key is
+        // written before that.
+        let sandbox = Sandbox::create()?;
+        let mut fetch = command.clone();
+        let mut options = vec!["-o".to_string(), "ControlPath=none".to_string()];
+        options.extend(sandbox.ssh_options());
+        options.extend(["-o".to_string(), "BatchMode=yes".to_string()]);
+        fetch.splice(1..1, options);
+        fetch.push("exit".to_string());
+        out().info(&format!("Fetching the host key of {} ({})...", profile, host_config.address()));
+        Command::new(&fetch[0])
+            .args(&fetch[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .context("Failed to execute SSH command")?;
+        interrupt::check()?;
+
+        let new_entries = fs::read_to_string(&sandbox.known_hosts).unwrap_or_default();
+        if new_entries.trim().is_empty() {
+            return Err(anyhow!("Could not fetch the host key of '{}'. Is the host reachable?", profile));
+        }
+        let output = Command::new("ssh-keygen")
+            .arg("-lf")
+            .arg(&sandbox.known_hosts)
+            .output()
+            .context("Failed to run 'ssh-keygen'")?;
+        let listing = String::from_utf8_lossy(&output.stdout).to_string();
+        let fingerprint = listing
+            .split_whitespace()
+            .nth(1)
+            .ok_or_else(|| anyhow!("Failed to read the fingerprint of the new host key"))?
+            .to_string();
+
+        println!();
+        if let Some(ref old) = host_config.host_key_fingerprint {
+            println!("  old fingerprint: {}", old);
+        }
+        println!("  new fingerprint: {}", fingerprint);
+        println!("  key:             {}", listing.trim());
+        println!();
+        if host_config.host_key_fingerprint.as_deref() == Some(fingerprint.as_str()) {
+            out().info("The host key did not change.");
+        }
+        let known_hosts_file = match host_config.known_hosts_file {
+            Some(ref file) => PathBuf::from(file),
+            None => dirs::home_dir()
+                .ok_or_else(|| anyhow!("Could not find home directory"))?
+                .join(".ssh")
+                .join("known_hosts"),
+        };
+        if !yes && !confirm("Compare the fingerprint with the server console. Trust the new key?")? {
+            return Err(anyhow!("Rollover cancelled, {} is unchanged", known_hosts_file.display()));
+        }
+
+        let mut names = vec![match host_config.port {
+            Some(port) if port != 22 => format!("[{}]:{}", host_config.host, port),
+            _ => host_config.host.clone(),
+        }];
+        if let Ok(ip) = resolve_ip(&host_config) {
+            names.push(match host_config.port {
+                Some(port) if port != 22 => format!("[{}]:{}", ip, port),
+                _ => ip,
+            });
+        }
+        if let Some(dir) = known_hosts_file.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        if known_hosts_file.exists() {
+            for name in &names {
+                Command::new("ssh-keygen")
+                    .arg("-R")
+                    .arg(name)
+                    .arg("-f")
+                    .arg(&known_hosts_file)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .context("Failed to run 'ssh-keygen'")?;
+            }
+        }
+
+        let mut known_hosts = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&known_hosts_file)
+            .with_context(|| format!("Failed to open {}", known_hosts_file.display()))?;
+        known_hosts
+            .write_all(new_entries.as_bytes())
+            .with_context(|| format!("Failed to update {}", known_hosts_file.display()))?;
+        out().success(&format!(
+            "Replaced entries for {} in {}",
+            names.join(", "),
+            known_hosts_file.display()
+        ));
+
+        if let Some(stored) = self.config.profiles.get_mut(profile) {
+            stored.host_key_fingerprint = Some(fingerprint);
+            stored.touch();
+        }
+        self.save_config()?;
+        out().success(&format!("Pinned the new fingerprint in profile '{}'", profile));
+
+        // Log in for real now, refusing anything but the key just accepted
+        let mut verify = command;
+        verify.splice(
+            1..1,
+            [
+                "-o".to_string(),
+                "ControlPath=none".to_string(),
+                "-o".to_string(),
+                "StrictHostKeyChecking=yes".to_string(),
+            ],
+        );
+        verify.push("true".to_string());
+        let status = Command::new(&verify[0])
+            .args(&verify[1..])
+            .status()
+            .context("Failed to execute SSH command")?;
+        interrupt::check()?;
+        if !status.success() {
+            return Err(anyhow!("The new key is in place, but logging in to '{}' failed", profile));
+        }
+        out().success(&format!("Logged in to {} with the new host key", profile));
+        Ok(())
+    }
+
+    /// The new-VM ritual: waits for the host to come up, installs the key,
+    /// runs a bootstrap script, gathers facts and tags the profile.
+    fn bootstrap(&mut self, profile: &str, options: BootstrapOptions) -> Result<()> {
+        let mut host_config = self.resolve(profile)?;
+        // A brand-new host can't be in known_hosts yet
+        if host_config.host_key_checking.is_none() {
+            host_config.host_key_checking = Some(HostKeyChecking::AcceptNew);
+        }
+        let hops = self.jump_chain(&host_config, &mut vec![profile.to_string()])?;
+
+        if hops.is_empty() {
+            out().info(&format!("Waiting for {} to come up...", host_config.address()));
+            let deadline = Instant::now() + Duration::from_secs(options.wait);
+            loop {
+                interrupt::check()?;
+                let up = resolve_ip(&host_config).ok().and_then(|ip| ip.parse::<std::net::IpAddr>().ok()).is_some_and(|ip| {
+                    let addr = std::net::SocketAddr::new(ip, host_config.port.unwrap_or(22));
+                    std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(3)).is_ok()
+                });
+                if up {
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    return Err(anyhow!("{} did not come up within {}s", profile, options.wait));
+                }
+                std::thread::sleep(Duration::from_secs(2));
+            }
+            out().success(&format!("{} is up", profile));
+        }
+
+        if options.copy_id {
+            let mut copy_id = vec!["ssh-copy-id".to_string()];
+            if let Some(key) = host_config.keys.first() {
+                copy_id.push("-i".to_string());
+                copy_id.push(key.clone());
+            }
+            if let Some(port) = host_config.port {
+                copy_id.push("-p".to_string());
+                copy_id.push(port.to_string());
+            }
+            copy_id.push("-o".to_string());
+            copy_id.push("StrictHostKeyChecking=accept-new".to_string());
+            if !hops.is_empty() {
+                let hops: Vec<_> = hops.into_iter().map(|hop| hop.address).collect();
+                copy_id.push("-o".to_string());
+                copy_id.push(format!("ProxyJump={}", hops.join(",")));
+            }
+            copy_id.push(match host_config.user {
+                Some(ref user) => format!("{}@{}", user, host_config.host),
+                None => host_config.host.clone(),
+            });
+            let status = Command::new(&copy_id[0])
+                .args(&copy_id[1..])
+                .status()
+                .context("Failed to run 'ssh-copy-id'. Is it installed?")?;
+            interrupt::check()?;
+            if !status.success() {
+                return Err(anyhow!("Failed to install the key on '{}'", profile));
+            }
+            out().success(&format!("Installed key on {}", profile));
+        }
+
+        let command = self.ssh_command(profile, &host_config)?;
+        if let Some(ref script) = options.script {
+            let input = fs::File::open(script).with_context(|| format!("Failed to open {}", script.display()))?;
+            let mut run = command.clone();
+            run.push("sh -s".to_string());
+            out().info(&format!("Running {} on {}...", script.display(), profile));
+            let status = Command::new(&run[0])
+                .args(&run[1..])
+                .stdin(input)
+                .status()
+                .context("Failed to execute SSH command")?;
+            interrupt::check()?;
+            if !status.success() {
+                return Err(anyhow!("{} failed on '{}' with exit status {}", script.display(), profile, exit_code(status)));
+            }
+            out().success(&format!("Ran {}", script.display()));
+        }
+
+        let mut gather = command;
+        gather.push(FACTS_SCRIPT.to_string());
+        let output = Command::new(&gather[0])
+            .args(&gather[1..])
+            .stdin(Stdio::null())
+            .output()
+            .context("Failed to execute SSH command")?;
+        interrupt::check()?;
+        let facts: BTreeMap<String, String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .filter(|(_, value)| !value.trim().is_empty())
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        for (name, value) in &facts {
+            out().info(&format!("  {}: {}", name, value));
+        }
+
+        let stored = self
+            .config
+            .profiles
+            .get_mut(profile)
+            .ok_or_else(|| anyhow!("Profile '{}' not found. Use 'masuk ls' to see available profiles.", profile))?;
+        stored.facts = facts;
+        if !stored.tags.contains(&options.tag) {
+            stored.tags.push(options.tag.clone());
+        }
+        stored.touch();
+        self.save_config()?;
+        out().success(&format!("Bootstrapped {} and tagged it '{}'", profile, options.tag));
+        Ok(())
+    }
+
+    fn set_setting(&mut self, setting: Setting, value: Option<String>) -> Result<()> {
+        match setting {
+            Setting::SshCommand => self.config.ssh_command = value.clone(),
+            Setting::Terminal => self.config.terminal = value.clone(),
+        }
+        self.save_config()?;
+
+        match value {
+            Some(value) => out().success(&format!("Set {} to '{}'", setting.name(), value)),
+            None => out().success(&format!("Reset {} to its default", setting.name())),
+        }
+        Ok(())
+    }
+
+    /// Starts `masuk connect <profile> <args>` in a new terminal window and
+    /// returns right away.
+    fn open(&self, profile: &str, args: &[String]) -> Result<()> {
+        let host_config = self.resolve(profile)?;
+        let template = match host_config.terminal.as_deref().or(self.config.terminal.as_deref()) {
+            Some(template) => template.to_string(),
+            None => match env::var("TERMINAL").ok().filter(|terminal| !terminal.is_empty()) {
+                Some(terminal) => format!("{} -e", terminal),
+                None => TERMINALS
+                    .iter()
+                    .find(|template| template.split_whitespace().next().is_some_and(in_path))
+                    .map(|template| template.to_string())
+                    .ok_or_else(|| {
+                        anyhow!("No terminal emulator found. Set one with 'masuk config set terminal kitty --title {{profile}}'")
+                    })?,
+            },
+        };
+
+        let masuk = env::current_exe().context("Failed to find the masuk binary")?;
+        let mut connection = vec![masuk.display().to_string(), "connect".to_string(), profile.to_string()];
+        connection.extend(args.iter().cloned());
+        let command = terminal_command(&template, profile, &connection);
+        if command.is_empty() {
+            return Err(anyhow!("The terminal setting is empty"));
+        }
+        Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to start '{}'", command[0]))?;
+        out().success(&format!("Opened {} in {}", profile, command[0]));
+        Ok(())
+    }
+
+    /// Prints shell exports that make later masuk commands use `context` or
+    /// `config`, or whatever this run uses.
+    fn env_export(&self, context: Option<&str>, config: Option<PathBuf>) -> Result<()> {
+        let (context, config) = match (context, config) {
+            (Some(context), _) => (Some(context.to_string()), context_config(&self.state_dir, context)?),
+            (None, Some(config)) => (None, std::path::absolute(&config).with_context(|| format!("Invalid path {}", config.display()))?),
+            (None, None) => (env::var("MASUK_CONTEXT").ok().filter(|c| !c.is_empty()), self.config_path.clone()),
+        };
+        match context {
+            Some(context) => println!("export MASUK_CONTEXT={}", shell_quote(&context)),
+            None => println!("unset MASUK_CONTEXT"),
+        }
+        println!("export MASUK_CONFIG={}", shell_quote(&config.display().to_string()));
+        Ok(())
+    }
+
+    /// The machine of the profile's pool to connect to. Round-robin
+    /// continues where the last connection left off, unless `advance` is
+    /// false.
+    fn pool_member(&self, profile: &str, host_config: &HostConfig, advance: bool) -> Result<String> {
+        let members: Vec<&String> = std::iter::once(&host_config.host).chain(&host_config.pool).collect();
+        let index = match host_config.strategy.unwrap_or(PoolStrategy::RoundRobin) {
+            PoolStrategy::Random => {
+                use std::hash::{BuildHasher, Hasher};
+                // Seeded randomly for every process, which is all this needs
+                std::collections::hash_map::RandomState::new().build_hasher().finish() as usize
+            }
+            PoolStrategy::RoundRobin => {
+                let path = self.state_dir.join("pools.json");
+                let mut next: BTreeMap<String, usize> = fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|data| serde_json::from_str(&data).ok())
+                    .unwrap_or_default();
+                let index = next.get(profile).copied().unwrap_or(0);
+                if advance {
+                    next.insert(profile.to_string(), (index + 1) % members.len());
+                    let data = serde_json::to_string_pretty(&next).context("Failed to serialize pool state")?;
+                    fs::create_dir_all(&self.state_dir).context("Failed to create config directory")?;
+                    fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))?;
+                }
+                index
+            }
+        };
+        Ok(members[index % members.len()].clone())
+    }
+
+    /// The first of the profile's host and failover hosts that accepts
+    /// connections on its SSH port.
+    fn reachable_host(&self, profile: &str, host_config: &HostConfig) -> Result<String> {
+        use std::net::ToSocketAddrs;
+
+        if !matches!(host_config.backend, None | Some(Backend::Ssh) | Some(Backend::Mosh)) {
+            return Err(anyhow!(
+                "Failover hosts only work with ssh and mosh, profile '{}' uses {}",
+                profile,
+                host_config.backend.unwrap_or(Backend::Ssh).name()
+            ));
+        }
+        // Tried from here, which says nothing about what a jump host reaches
+        if host_config.jump.is_some()
+            || !host_config.via.is_empty()
+            || host_config.socks_proxy.is_some()
+            || host_config.proxy_command.is_some()
+        {
+            return Err(anyhow!("Profile '{}' can't use failover hosts together with jump hosts or proxies", profile));
+        }
+        let port = host_config.port.unwrap_or(22);
+        let timeout = Duration::from_secs(host_config.failover_timeout.unwrap_or(DEFAULT_FAILOVER_TIMEOUT));
+        let hosts: Vec<&String> = std::iter::once(&host_config.host).chain(&host_config.failover).collect();
+        for host in &hosts {
+            let answers = (host.as_str(), port)
+                .to_socket_addrs()
+                .is_ok_and(|mut addrs| addrs.any(|addr| std::net::TcpStream::connect_timeout(&addr, timeout).is_ok()));
+            if answers {
+                return Ok(host.to_string());
+            }
+        }
+        let hosts: Vec<&str> = hosts.iter().map(|host| host.as_str()).collect();
+        Err(anyhow!("None of the hosts of '{}' answer on port {}: {}", profile, port, hosts.join(", ")))
+    }
+
+    /// Sends the profile's Wake-on-LAN magic packet.
+    fn wake(&self, profile: &str, host_config: &HostConfig) -> Result<()> {
+        let mac = host_config.wol_mac.as_deref().ok_or_else(|| {
+            anyhow!(
+                "Profile '{}' has no MAC address for Wake-on-LAN, set one with 'masuk set {} --wol-mac <mac>'",
+                profile,
+                profile
+            )
+        })?;
+        wol::wake(mac, host_config.wol_broadcast.as_deref().unwrap_or(wol::DEFAULT_BROADCAST))
+    }
+
+    fn wake_profile(&self, profile: &str) -> Result<()> {
+        let host_config = self.resolve(profile)?;
+        self.wake(profile, &host_config)?;
+        out().success(&format!(
+            "Sent a Wake-on-LAN packet for {} to {}",
+            profile,
+            host_config.wol_broadcast.as_deref().unwrap_or(wol::DEFAULT_BROADCAST)
+        ));
+        Ok(())
+    }
+
+    fn show_settings(&self) -> Result<()> {
+        let items = vec![
+            Item {
+                name: Setting::SshCommand.name().to_string(),
+                description: self.config.ssh_command.as_deref().unwrap_or("ssh (default)").to_string(),
+                data: json!({ "name": Setting::SshCommand.name(), "value": self.config.ssh_command }),
+            },
+            Item {
+                name: Setting::Terminal.name().to_string(),
+                description: self.config.terminal.as_deref().unwrap_or("$TERMINAL or the first one found (default)").to_string(),
+                data: json!({ "name": Setting::Terminal.name(), "value": self.config.terminal }),
+            },
+        ];
+        out().list("Settings", &items, "");
+        Ok(())
+    }
+
+    fn list(&self, group_by: Option<GroupBy>) -> Result<()> {
+        let mut profiles: Vec<_> = self.config.profiles.iter().collect();
+        profiles.sort_by(|(a, _), (b, _)| natural_cmp(a, b));
+
+        let Some(group_by) = group_by else {
+            let items: Vec<Item> = profiles
+                .into_iter()
+                .map(|(profile, host_config)| profile_item(profile, host_config))
+                .collect();
+            out().list(
+                "Configured profiles",
+                &items,
+                "No profiles configured yet. Use 'masuk add <profile> -h <host>' to add one.",
+            );
+            return Ok(());
+        };
+
+        // A profile with several tags shows up in each of their groups
+        let mut groups: BTreeMap<String, Vec<(&String, &HostConfig)>> = BTreeMap::new();
+        for (profile, host_config) in profiles {
+            let keys = match group_by {
+                GroupBy::MonthAdded => vec![host_config
+                    .created_at
+                    .map(|time| format_date(time)[..7].to_string())
+                    .unwrap_or_else(|| "unknown".to_string())],
+                GroupBy::Tag if host_config.tags.is_empty() => vec!["untagged".to_string()],
+                GroupBy::Tag => host_config.tags.clone(),
+                GroupBy::User => vec![host_config.user.clone().unwrap_or_else(|| "default user".to_string())],
+            };
+            for key in keys {
+                groups.entry(key).or_default().push((profile, host_config));
+            }
+        }
+
+        let mut groups: Vec<_> = groups.into_iter().collect();
+        match group_by {
+            // Oldest first, so the listing reads as the history of the inventory
+            GroupBy::MonthAdded => groups.sort_by_key(|(key, _)| key == "unknown"),
+            _ => groups.sort_by(|(a, _), (b, _)| natural_cmp(a, b)),
+        }
+
+        let groups: Vec<(String, Vec<Item>)> = groups
+            .into_iter()
+            .map(|(key, members)| {
+                let items = members
+                    .into_iter()
+                    .map(|(profile, host_config)| profile_item(profile, host_config))
+                    .collect();
+                (key, items)
+            })
+            .collect();
+        out().groups(&groups);
+        Ok(())
+    }
+
+    fn show(&self, profile: &str, resolved: bool) -> Result<()> {
+        let stored = self
+            .config
+            .profiles
+            .get(profile)
+            .ok_or_else(|| anyhow!("Profile '{}' not found. Use 'masuk ls' to see available profiles.", profile))?;
+        let (host_config, sources) = if resolved {
+            let (host_config, sources) = self.with_defaults(profile, stored);
+            let host_config = host_config
+                .expanded()
+                .with_context(|| format!("Failed to resolve profile '{}'", profile))?;
+            (host_config, sources)
+        } else {
+            (stored.clone(), BTreeMap::new())
+        };
+
+        let mut fields: Vec<(String, String)> = Vec::new();
+        let mut field = |label: &str, value: String| fields.push((label.to_string(), value));
+        if !host_config.host.is_empty() {
+            field("host", host_config.host.to_string());
+        }
+        if let Some(ref u) = host_config.user {
+            field("user", u.to_string());
+        }
+        if let Some(p) = host_config.port {
+            field("port", p.to_string());
+        }
+        if !host_config.keys.is_empty() {
+            field("key", host_config.keys.join(", "));
+        }
+        if let Some(ref certificate) = host_config.certificate_file {
+            field("certificate", certificate.to_string());
+        }
+        if let Some(ref known_hosts) = host_config.known_hosts_file {
+            field("known hosts", known_hosts.to_string());
+        }
+        if let Some(ref agent) = host_config.identity_agent {
+            field("identity agent", agent.to_string());
+        }
+        if let Some(ref dir) = host_config.remote_dir {
+            field("remote dir", dir.to_string());
+        }
+        if let Some(ref dir) = host_config.local_dir {
+            field("local dir", dir.to_string());
+        }
+        if let Some(ref role) = host_config.vault_role {
+            let mount = host_config.vault_mount.as_deref().unwrap_or("ssh");
+            match host_config.vault_mode.unwrap_or(VaultMode::Sign) {
+                VaultMode::Sign => field("vault", format!("certificate from {}/sign/{}", mount, role)),
+                VaultMode::Otp => field("vault", format!("one-time password from {}/creds/{}", mount, role)),
+            }
+        }
+        if let Some(ref j) = host_config.jump {
+            field("jump", j.to_string());
+        }
+        if !host_config.pool.is_empty() {
+            field(
+                "pool",
+                format!(
+                    "{}, {} ({})",
+                    host_config.host,
+                    host_config.pool.join(", "),
+                    host_config.strategy.unwrap_or(PoolStrategy::RoundRobin).name()
+                ),
+            );
+        }
+        if !host_config.failover.is_empty() {
+            field(
+                "failover",
+                format!(
+                    "{} ({}s each)",
+                    host_config.failover.join(", "),
+                    host_config.failover_timeout.unwrap_or(DEFAULT_FAILOVER_TIMEOUT)
+                ),
+            );
+        }
+        if !host_config.via.is_empty() {
+            field("via", host_config.via.join(", "));
+        }
+        if !host_config.tags.is_empty() {
+            field("tags", host_config.tags.join(", "));
+        }
+        if host_config.forward_agent {
+            field("forward agent", "yes".to_string());
+        }
+        if host_config.trusted_x11 {
+            field("x11", "trusted".to_string());
+        } else if host_config.x11 {
+            field("x11", "yes".to_string());
+        }
+        if let Some(compression) = host_config.compression {
+            field("compression", if compression { "yes" } else { "no" }.to_string());
+        }
+        if let Some(interval) = host_config.keepalive_interval {
+            field("keepalive interval", format!("{}s", interval));
+        }
+        if let Some(count) = host_config.keepalive_count {
+            field("keepalive count", count.to_string());
+        }
+        if let Some(secs) = host_config.connect_timeout {
+            field("connect timeout", format!("{}s", secs));
+        }
+        if let Some(risk) = host_config.risk {
+            field("risk", risk.name().to_string());
+        }
+        match host_config.backend {
+            Some(Backend::Ssh) | None => {}
+            Some(backend) => field("backend", backend.name().to_string()),
+        }
+        if let Some(ref device) = host_config.device {
+            field("device", device.to_string());
+        }
+        if let Some(baud) = host_config.baud {
+            field("baud", baud.to_string());
+        }
+        if let Some(ref container) = host_config.container {
+            field("container", container.to_string());
+        }
+        if let Some(ref context) = host_config.context {
+            field("context", context.to_string());
+        }
+        if let Some(ref namespace) = host_config.namespace {
+            field("namespace", namespace.to_string());
+        }
+        if let Some(ref pod) = host_config.pod {
+            field("pod", pod.to_string());
+        }
+        if let Some(ref selector) = host_config.selector {
+            field("selector", selector.to_string());
+        }
+        if let Some(ref instance) = host_config.instance {
+            field("instance", instance.to_string());
+        }
+        if let Some(ref dir) = host_config.vagrant_dir {
+            field("vagrant dir", dir.display().to_string());
+        }
+        if let Some(ref project) = host_config.project {
+            field("project", project.to_string());
+        }
+        if let Some(ref zone) = host_config.zone {
+            field("zone", zone.to_string());
+        }
+        if host_config.iap {
+            field("iap", "yes".to_string());
+        }
+        if let Some(ref instance_id) = host_config.instance_id {
+            field("instance", instance_id.to_string());
+        }
+        if let Some(ref aws_profile) = host_config.aws_profile {
+            field("aws profile", aws_profile.to_string());
+        }
+        if let Some(ref region) = host_config.region {
+            field("region", region.to_string());
+        }
+        if let Some(port) = host_config.socks_port {
+            field("socks proxy", format!("opened on 127.0.0.1:{}", port));
+        }
+        if let Some(ref proxy) = host_config.socks_proxy {
+            field("via socks", proxy.to_string());
+        }
+        if let Some(ref proxy_command) = host_config.proxy_command {
+            field("proxy command", proxy_command.to_string());
+        }
+        if let Some(ref mac) = host_config.wol_mac {
+            field(
+                "wake on lan",
+                format!("{} via {}", mac, host_config.wol_broadcast.as_deref().unwrap_or(wol::DEFAULT_BROADCAST)),
+            );
+        }
+        if !host_config.knock.is_empty() {
+            let ports: Vec<String> = host_config.knock.iter().map(u16::to_string).collect();
+            field(
+                "knock",
+                format!(
+                    "{} ({}, {}ms apart)",
+                    ports.join(", "),
+                    if host_config.knock_protocol == Some(knock::Protocol::Udp) { "udp" } else { "tcp" },
+                    host_config.knock_delay.unwrap_or(knock::DEFAULT_DELAY_MS)
+                ),
+            );
+        }
+        if host_config.clipboard {
+            field(
+                "clipboard",
+                format!("relayed from port {}", host_config.clipboard_port.unwrap_or(DEFAULT_CLIPBOARD_PORT)),
+            );
+        }
+        if let Some(ref ports) = host_config.mosh_ports {
+            field("mosh ports", ports.to_string());
+        }
+        if let Some(ref server) = host_config.mosh_server {
+            field("mosh server", server.to_string());
+        }
+        for (name, spec) in &host_config.tunnels {
+            field("tunnel", format!("{} {}", name, spec));
+        }
+        if !host_config.remote_forwards.is_empty() {
+            field("remote forwards", host_config.remote_forwards.join(", "));
+        }
+        if !host_config.requires.is_empty() {
+            field("requires", host_config.requires.join(", "));
+        }
+        if let Some(min) = host_config.min_disk_free {
+            field("min disk free", format!("{}%", min));
+        }
+        if host_config.retry {
+            field(
+                "retry",
+                format!(
+                    "up to {} times, first after {}",
+                    host_config.max_retries.unwrap_or(5),
+                    format_duration(host_config.retry_backoff.unwrap_or(5))
+                ),
+            );
+        }
+        if let Some(ref user) = host_config.local_user {
+            field("local user", user.to_string());
+        }
+        if host_config.systemd_scope {
+            field("systemd scope", "yes".to_string());
+        }
+        if host_config.multiplex {
+            field("multiplex", "yes".to_string());
+        }
+        match host_config.address_family {
+            Some(AddressFamily::Any) => field("address family", "any".to_string()),
+            Some(AddressFamily::Inet) => field("address family", "IPv4 only".to_string()),
+            Some(AddressFamily::Inet6) => field("address family", "IPv6 only".to_string()),
+            None => {}
+        }
+        match host_config.host_key_checking {
+            Some(HostKeyChecking::Strict) => field("host key checking", "strict".to_string()),
+            Some(HostKeyChecking::AcceptNew) => field("host key checking", "accept new".to_string()),
+            Some(HostKeyChecking::Off) => field("host key checking", "off".to_string()),
+            None => {}
+        }
+        if let Some(ref remote_command) = host_config.remote_command {
+            field("remote command", remote_command.to_string());
+        }
+        if let Some(ref reference) = host_config.password {
+            field("password", reference.to_string());
+        }
+        if let Some(ref reference) = host_config.key_passphrase {
+            field("key passphrase", reference.to_string());
+        }
+        if let Some(ref reference) = host_config.totp {
+            field("totp", reference.to_string());
+        }
+        if let Some(ref reference) = host_config.sudo_password {
+            field("sudo password", reference.to_string());
+        }
+        if let Some(ref hook) = host_config.pre_connect {
+            field("pre connect", hook.to_string());
+        }
+        if let Some(ref hook) = host_config.post_connect {
+            field("post connect", hook.to_string());
+        }
+        for (name, value) in &host_config.env {
+            field("env", format!("{}={}", name, value));
+        }
+        if !host_config.facts.is_empty() {
+            let facts: Vec<String> = host_config.facts.iter().map(|(name, value)| format!("{} {}", name, value)).collect();
+            field("facts", facts.join(", "));
+        }
+        if let Some(ref fingerprint) = host_config.host_key_fingerprint {
+            field("host key", fingerprint.to_string());
+        }
+        if let Some(ref client) = host_config.ssh_command {
+            field("ssh command", client.to_string());
+        }
+        if let Some(ref terminal) = host_config.terminal {
+            field("terminal", terminal.to_string());
+        }
+        if !host_config.extra_args.is_empty() {
+            field("extra args", shell_join(&host_config.extra_args).to_string());
+        }
+        if let Some(time) = host_config.created_at {
+            field("added", format_date(time).to_string());
+        }
+        if let Some(time) = host_config.updated_at {
+            field("changed", format_date(time).to_string());
+        }
+
+        if resolved {
+            let hops = self.jump_chain(&host_config, &mut vec![profile.to_string()])?;
+            let mut path = vec!["local".to_string()];
+            path.extend(hops.into_iter().map(|hop| match hop.profile {
+                Some(name) => format!("{} ({})", name, hop.address),
+                None => hop.address,
+            }));
+            path.push(format!("{} ({})", profile, host_config.address()));
+            field("path", path.join(" → ").to_string());
+
+            for (label, value) in fields.iter_mut() {
+                if let Some(source) = sources.get(label.as_str()) {
+                    value.push_str(&format!(" (from {})", source));
+                }
+            }
+            if matches!(host_config.backend, None | Some(Backend::Ssh) | Some(Backend::Mosh)) {
+                if host_config.user.is_none() {
+                    let local = env::var("USER").unwrap_or_else(|_| "your local user name".to_string());
+                    fields.push(("user".to_string(), format!("{} (built-in)", local)));
+                }
+                if host_config.port.is_none() {
+                    fields.push(("port".to_string(), "22 (built-in)".to_string()));
+                }
+                if host_config.ssh_command.is_none() {
+                    let client = match self.config.ssh_command {
+                        Some(ref client) => format!("{} (from global setting)", client),
+                        None => "ssh (built-in)".to_string(),
+                    };
+                    fields.push(("ssh command".to_string(), client));
+                }
+            }
+        }
+
+        let mut data = serde_json::to_value(&host_config)?;
+        data["name"] = json!(profile);
+        out().record(&format!("Profile '{}'", profile), &fields, &data);
+        Ok(())
+    }
+
+    fn remove(&mut self, profile: &str) -> Result<()> {
+        if self.config.profiles.remove(profile).is_none() {
+            return Err(anyhow!("Profile '{}' not found", profile));
+        }
+
+        self.save_config()?;
+        out().success(&format!("Removed profile '{}'", profile));
+
+        Ok(())
+    }
+
+    fn hosts_add(&self, profile: &str, name: Option<String>) -> Result<()> {
+        let host_config = self.resolve(profile)?;
+        let ip = resolve_ip(&host_config)?;
+        let name = name.unwrap_or_else(|| profile.to_string());
+
+        let mut entries = hosts::read()?;
+        entries.retain(|entry| entry.name != name);
+        entries.push(hosts::Entry {
+            ip: ip.clone(),
+            name: name.clone(),
+            profile: profile.to_string(),
+        });
+        hosts::write(&entries)?;
+
+        out().success(&format!("Mapped '{}' → {} in /etc/hosts", name, ip));
+        Ok(())
+    }
+
+    fn hosts_remove(&self, profile: &str) -> Result<()> {
+        let mut entries = hosts::read()?;
+        let count = entries.len();
+        entries.retain(|entry| entry.profile != profile);
+        if entries.len() == count {
+            return Err(anyhow!("No /etc/hosts entry for profile '{}'", profile));
+        }
+        hosts::write(&entries)?;
+
+        out().success(&format!("Removed /etc/hosts entry for profile '{}'", profile));
+        Ok(())
+    }
+
+    /// Re-resolves every managed entry, so names of profiles whose address
+    /// changes (e.g. cloud instances) keep pointing at the right machine.
+    fn hosts_sync(&self) -> Result<()> {
+        let mut entries = hosts::read()?;
+        let mut changed = false;
+
+        for entry in &mut entries {
+            let ip = match self.resolve(&entry.profile).and_then(|c| resolve_ip(&c)) {
+                Ok(ip) => ip,
+                Err(e) => {
+                    eprintln!("{}", plain(&format!("Warning: keeping '{}' → {}: {:#}", entry.name, entry.ip, e)));
+                    continue;
+                }
+            };
+            if ip != entry.ip {
+                out().success(&format!("Updated '{}' → {} (was {})", entry.name, ip, entry.ip));
+                entry.ip = ip;
+                changed = true;
+            }
+        }
+
+        if changed {
+            hosts::write(&entries)?;
+        } else {
+            out().info("All /etc/hosts entries are up to date.");
+        }
+        Ok(())
+    }
+
+    fn hosts_list(&self) -> Result<()> {
+        let items: Vec<Item> = hosts::read()?
+            .into_iter()
+            .map(|entry| Item {
+                description: format!("{} (profile {})", entry.ip, entry.profile),
+                data: json!({ "name": entry.name, "ip": entry.ip, "profile": entry.profile }),
+                name: entry.name,
+            })
+            .collect();
+        out().list(
+            "Managed /etc/hosts entries",
+            &items,
+            "No /etc/hosts entries yet. Use 'masuk hosts add <profile>' to add one.",
+        );
+        Ok(())
+    }
+
+    fn set_policy(&mut self, tag: &str, settings: PolicyArgs) -> Result<()> {
+        if let Some(level) = tag.strip_prefix("risk:") {
+            Risk::from_str(level, false)
+                .map_err(|_| anyhow!("Unknown risk level '{}'. Use risk:low, risk:medium or risk:high.", level))?;
+        }
+
+        let PolicyArgs {
+            record,
+            log_dir,
+            webhook,
+            confirm,
+            no_fleet,
+        } = settings;
+        let policy = self.config.policies.entry(tag.to_string()).or_default();
+        if record.is_some() {
+            policy.record = record;
+        }
+        if log_dir.is_some() {
+            policy.log_dir = log_dir;
+        }
+        if webhook.is_some() {
+            policy.webhook = webhook;
+        }
+        if let Some(confirm) = confirm {
+            policy.confirm = confirm;
+        }
+        if let Some(no_fleet) = no_fleet {
+            policy.no_fleet = no_fleet;
+        }
+
+        self.save_config()?;
+        out().success(&format!("Updated policy for tag '{}'", tag));
+
+        Ok(())
+    }
+
+    fn list_policies(&self) -> Result<()> {
+        let mut policies: Vec<_> = self.config.policies.iter().collect();
+        policies.sort_by_key(|(tag, _)| *tag);
+
+        let mut items = Vec::new();
+        for (tag, policy) in policies {
+            let mut display = match policy.record {
+                Some(Recorder::Script) => "record with script".to_string(),
+                Some(Recorder::Asciinema) => "record with asciinema".to_string(),
+                None => "no recording".to_string(),
+            };
+            if let Some(ref dir) = policy.log_dir {
+                display.push_str(&format!(" (logs: {})", dir.display()));
+            }
+            if let Some(ref url) = policy.webhook {
+                display.push_str(&format!(", webhook: {}", url));
+            }
+            if policy.confirm {
+                display.push_str(", confirm");
+            }
+            if policy.no_fleet {
+                display.push_str(", no fleet commands");
+            }
+            let mut data = serde_json::to_value(policy)?;
+            data["tag"] = json!(tag);
+            items.push(Item {
+                name: tag.clone(),
+                description: display,
+                data,
+            });
+        }
+        out().list(
+            "Configured policies",
+            &items,
+            "No policies configured yet. Use 'masuk policy set <tag> --record script' to add one.",
+        );
+        Ok(())
+    }
+
+    fn set_defaults(&mut self, tag: Option<String>, settings: DefaultsArgs) -> Result<()> {
+        let defaults = match tag {
+            Some(ref tag) => self.config.tag_defaults.entry(tag.clone()).or_default(),
+            None => &mut self.config.defaults,
+        };
+        if let Some(user) = settings.user {
+            defaults.user = Some(user).filter(|u| !u.is_empty());
+        }
+        if let Some(port) = settings.port {
+            defaults.port = Some(port).filter(|p| *p != 0);
+        }
+        if !settings.keys.is_empty() {
+            defaults.keys = settings.keys.into_iter().filter(|k| !k.is_empty()).collect();
+        }
+        if let Some(agent) = settings.identity_agent {
+            defaults.identity_agent = Some(agent).filter(|a| !a.is_empty());
+        }
+        if let Some(jump) = settings.jump {
+            defaults.jump = Some(jump).filter(|j| !j.is_empty());
+        }
+        if let Some(secs) = settings.connect_timeout {
+            defaults.connect_timeout = Some(secs).filter(|s| *s != 0);
+        }
+        if let Some(interval) = settings.keepalive_interval {
+            defaults.keepalive_interval = Some(interval).filter(|i| *i != 0);
+        }
+        if let Some(count) = settings.keepalive_count {
+            defaults.keepalive_count = Some(count).filter(|c| *c != 0);
+        }
+        if settings.compression.is_some() {
+            defaults.compression = settings.compression;
+        }
+        if settings.address_family.is_some() {
+            defaults.address_family = settings.address_family;
+        }
+        if settings.host_key_checking.is_some() {
+            defaults.host_key_checking = settings.host_key_checking;
+        }
+
+        let display = defaults.describe();
+        if let Some(ref tag) = tag {
+            if self.config.tag_defaults[tag].is_empty() {
+                self.config.tag_defaults.remove(tag);
+            }
+        }
+        self.save_config()?;
+        let scope = match tag {
+            Some(tag) => format!("profiles tagged '{}'", tag),
+            None => "all profiles".to_string(),
+        };
+        if display.is_empty() {
+            out().success(&format!("No defaults left for {}", scope));
+        } else {
+            out().success(&format!("Defaults for {}: {}", scope, display));
+        }
+        Ok(())
+    }
+
+    fn show_defaults(&self) -> Result<()> {
+        let mut items = Vec::new();
+        let mut tags: Vec<_> = self.config.tag_defaults.iter().collect();
+        tags.sort_by_key(|(tag, _)| *tag);
+        for (tag, defaults) in tags {
+            let mut data = serde_json::to_value(defaults)?;
+            data["tag"] = json!(tag);
+            items.push(Item {
+                name: format!("tag {}", tag),
+                description: defaults.describe(),
+                data,
+            });
+        }
+        if !self.config.defaults.is_empty() {
+            items.push(Item {
+                name: "all profiles".to_string(),
+                description: self.config.defaults.describe(),
+                data: serde_json::to_value(&self.config.defaults)?,
+            });
+        }
+        out().list(
+            "Defaults, in the order they apply",
+            &items,
+            "No defaults set. Use 'masuk defaults set [--tag <tag>] ...' to add some.",
+        );
+        Ok(())
+    }
+
+    fn clear_defaults(&mut self, tag: Option<&str>) -> Result<()> {
+        match tag {
+            Some(tag) => {
+                if self.config.tag_defaults.remove(tag).is_none() {
+                    return Err(anyhow!("No defaults for tag '{}'", tag));
+                }
+            }
+            None => self.config.defaults = Defaults::default(),
+        }
+        self.save_config()?;
+        match tag {
+            Some(tag) => out().success(&format!("Removed the defaults for tag '{}'", tag)),
+            None => out().success("Removed the global defaults"),
+        }
+        Ok(())
+    }
+
+    fn remove_policy(&mut self, tag: &str) -> Result<()> {
+        if self.config.policies.remove(tag).is_none() {
+            return Err(anyhow!("No policy for tag '{}'", tag));
+        }
+
+        self.save_config()?;
+        out().success(&format!("Removed policy for tag '{}'", tag));
+
+        Ok(())
+    }
+}
+
+impl HostConfig {
+    /// `user@host:port`, leaving out whatever isn't set.
+    fn address(&self) -> String {
+        if let (Some(Backend::Serial), Some(device)) = (self.backend, &self.device) {
+            return match self.baud {
+                Some(baud) => format!("{} at {} baud", device, baud),
+                None => device.clone(),
+            };
+        }
+        let mut display = String::new();
+        if let Some(ref u) = self.user {
+            display.push_str(&format!("{}@", u));
+        }
+        display.push_str(&self.host);
+        if let Some(p) = self.port {
+            display.push_str(&format!(":{}", p));
+        }
+        if let (Some(Backend::Incus), Some(instance)) = (self.backend, &self.instance) {
+            return format!("incus {}", instance);
+        }
+        if let (Some(Backend::Vagrant), Some(dir)) = (self.backend, &self.vagrant_dir) {
+            return match self.instance {
+                Some(ref machine) => format!("vagrant {} in {}", machine, dir.display()),
+                None => format!("vagrant {}", dir.display()),
+            };
+        }
+        if let (Some(Backend::Gcloud), Some(instance)) = (self.backend, &self.instance) {
+            let mut display = match self.user {
+                Some(ref user) => format!("instance {}@{}", user, instance),
+                None => format!("instance {}", instance),
+            };
+            if let Some(ref zone) = self.zone {
+                display.push_str(&format!(" in {}", zone));
+            }
+            if let Some(ref project) = self.project {
+                display.push_str(&format!(" of {}", project));
+            }
+            if self.iap {
+                display.push_str(" through IAP");
+            }
+            return display;
+        }
+        if let (Some(Backend::AwsSsm), Some(instance_id)) = (self.backend, &self.instance_id) {
+            let mut display = format!("instance {}", instance_id);
+            if let Some(ref region) = self.region {
+                display.push_str(&format!(" in {}", region));
+            }
+            if let Some(ref aws_profile) = self.aws_profile {
+                display.push_str(&format!(" as {}", aws_profile));
+            }
+            return display;
+        }
+        if self.backend == Some(Backend::Kubernetes) {
+            let mut display = match (&self.pod, &self.selector) {
+                (Some(pod), _) => format!("pod {}", pod),
+                (None, Some(selector)) => format!("pod -l {}", selector),
+                (None, None) => "pod ?".to_string(),
+            };
+            if let Some(ref namespace) = self.namespace {
+                display.push_str(&format!(" in {}", namespace));
+            }
+            if let Some(ref context) = self.context {
+                display.push_str(&format!(" on {}", context));
+            }
+            return display;
+        }
+        if let (Some(Backend::Docker), Some(container)) = (self.backend, &self.container) {
+            if self.host.is_empty() {
+                return format!("container {}", container);
+            }
+            return format!("container {} on {}", container, display);
+        }
+        display
+    }
+
+    /// Marks the profile as changed now.
+    fn touch(&mut self) {
+        self.updated_at = Some(unix_time() as i64);
+    }
+
+    /// The address followed by the remaining settings, as shown by `add` and `ls`.
+    fn describe(&self) -> String {
+        let mut display = self.address();
+        if !self.keys.is_empty() {
+            display.push_str(&format!(" (key: {})", self.keys.join(", ")));
+        }
+        let hops: Vec<&str> = self.jump.iter().chain(&self.via).map(String::as_str).collect();
+        if !hops.is_empty() {
+            display.push_str(&format!(" via {}", hops.join(", ")));
+        }
+        if !self.tags.is_empty() {
+            display.push_str(&format!(" [{}]", self.tags.join(", ")));
+        }
+        display
+    }
+
+    /// Returns a copy with `${VAR}` placeholders replaced from the
+    /// environment, and a leading `~` in local paths. Only done at connect
+    /// time, the stored profile keeps them. `~` in the remote directory is
+    /// the remote home and stays for scp. The proxy command is left alone,
+    /// ssh runs it through a shell that expands both itself.
+    fn expanded(&self) -> Result<HostConfig> {
+        Ok(HostConfig {
+            host: expand_env(&self.host)?,
+            failover: self.failover.iter().map(|host| expand_env(host)).collect::<Result<_>>()?,
+            pool: self.pool.iter().map(|host| expand_env(host)).collect::<Result<_>>()?,
+            user: self.user.as_deref().map(expand_env).transpose()?,
+            env: self
+                .env
+                .iter()
+                .map(|(name, value)| Ok((name.clone(), expand_env(value)?)))
+                .collect::<Result<_>>()?,
+            keys: self
+                .keys
+                .iter()
+                .map(|key| expand_env(key).map(|key| expand_tilde(&key)))
+                .collect::<Result<_>>()?,
+            certificate_file: self
+                .certificate_file
+                .as_deref()
+                .map(|file| expand_env(file).map(|file| expand_tilde(&file)))
+                .transpose()?,
+            known_hosts_file: self
+                .known_hosts_file
+                .as_deref()
+                .map(|file| expand_env(file).map(|file| expand_tilde(&file)))
+                .transpose()?,
+            remote_dir: self.remote_dir.as_deref().map(expand_env).transpose()?,
+            local_dir: self
+                .local_dir
+                .as_deref()
+                .map(|dir| expand_env(dir).map(|dir| expand_tilde(&dir)))
+                .transpose()?,
+            identity_agent: self
+                .identity_agent
+                .as_deref()
+                .map(|socket| expand_env(socket).map(|socket| expand_tilde(&socket)))
+                .transpose()?,
+            vagrant_dir: self
+                .vagrant_dir
+                .as_deref()
+                .map(|dir| expand_env(&dir.to_string_lossy()).map(|dir| PathBuf::from(expand_tilde(&dir))))
+                .transpose()?,
+            ..self.clone()
+        })
+    }
+}
+
+/// Throwaway known_hosts file for `connect --sandbox`, removed on drop.
+struct Sandbox {
+    known_hosts: PathBuf,
+    _dir: TempDir,
+}
+
+impl Sandbox {
+    fn create() -> Result<Self> {
+        // In a fresh private directory, so nobody can have planted host keys
+        // in the file or a symlink in its place
+        let dir = TempDir::create("sandbox")?;
+        let known_hosts = dir.path.join("known_hosts");
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&known_hosts)
+            .context("Failed to create sandbox known_hosts file")?;
+        Ok(Sandbox { known_hosts, _dir: dir })
+    }
+
+    fn ssh_options(&self) -> Vec<String> {
+        vec![
+            "-o".to_string(),
+            format!("UserKnownHostsFile={}", self.known_hosts.display()),
+            "-o".to_string(),
+            "GlobalKnownHostsFile=/dev/null".to_string(),
+            "-o".to_string(),
+            "StrictHostKeyChecking=accept-new".to_string(),
+            "-o".to_string(),
+            "UpdateHostKeys=no".to_string(),
+            "-o".to_string(),
+            "ForwardAgent=no".to_string(),
+        ]
+    }
+}
+
+/// Whether ssh arguments turn agent forwarding on, with `-A` (also among
+/// other flags, like `-At`) or a `ForwardAgent` option other than `no`.
+fn forwards_agent(args: &[String]) -> bool {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let option = match arg.strip_prefix("-o") {
+            Some("") => args.next().map(String::as_str).unwrap_or_default(),
+            Some(option) => option,
+            None => {
+                let flags = arg.strip_prefix('-').unwrap_or_default();
+                if flags.contains('A') && flags.chars().all(|c| "46AaCfGgKkMNnqsTtVvXxYy".contains(c)) {
+                    return true;
+                }
+                continue;
+            }
+        };
+        let (name, value) = option.split_once(['=', ' ']).unwrap_or((option, ""));
+        if name.trim().eq_ignore_ascii_case("ForwardAgent") && !value.trim().eq_ignore_ascii_case("no") {
+            return true;
+        }
+    }
+    false
+}
+
+/// Private temporary directory, removed on drop unless kept.
+struct TempDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl TempDir {
+    fn create(purpose: &str) -> Result<Self> {
+        // mkdtemp picks a name nobody has taken yet and creates it 0700
+        let template = env::temp_dir().join(format!("masuk-{}-XXXXXX", purpose));
+        let mut template = std::ffi::CString::new(template.into_os_string().into_vec())
+            .context("Failed to create temporary directory")?
+            .into_bytes_with_nul();
+        if unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) }.is_null() {
+            return Err(std::io::Error::last_os_error()).context("Failed to create temporary directory");
+        }
+        template.pop();
+        let path = PathBuf::from(std::ffi::OsString::from_vec(template));
+        Ok(TempDir { path, keep: false })
+    }
+
+    /// Leaves the directory in place, e.g. so the user can recover a file.
+    fn keep(mut self) -> PathBuf {
+        self.keep = true;
+        self.path.clone()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+impl Recorder {
+    fn extension(self) -> &'static str {
+        match self {
+            Recorder::Script => "log",
+            Recorder::Asciinema => "cast",
+        }
+    }
+
+    /// Wraps a command line so that it runs under the recorder.
+    fn wrap(self, command: &[String], log_file: &std::path::Path) -> Vec<String> {
+        let log_file = log_file.to_string_lossy().into_owned();
+        match self {
+            // BSD script takes the command as trailing arguments
+            Recorder::Script if cfg!(target_os = "macos") => {
+                let mut wrapped = vec!["script".to_string(), "-q".to_string(), "-F".to_string(), log_file];
+                wrapped.extend(command.iter().cloned());
+                wrapped
+            }
+            Recorder::Script => vec![
+                "script".to_string(),
+                "-q".to_string(),
+                "-f".to_string(),
+                "-e".to_string(),
+                "-c".to_string(),
+                shell_join(command),
+                log_file,
+            ],
+            Recorder::Asciinema => vec![
+                "asciinema".to_string(),
+                "rec".to_string(),
+                "--quiet".to_string(),
+                "--overwrite".to_string(),
+                "-c".to_string(),
+                shell_join(command),
+                log_file,
+            ],
+        }
+    }
+}
+
+/// Error carrying the exit status of a child process, which masuk exits
+/// with instead of printing an error of its own.
+#[derive(Debug)]
+struct ChildExit(i32);
+
+impl std::fmt::Display for ChildExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command exited with status {}", self.0)
+    }
+}
+
+impl std::error::Error for ChildExit {}
+
+/// `telnet [-l user] host [port]` for a profile. Jump hosts would need an
+/// ssh session to run telnet on, so they are refused.
+fn telnet_command(host_config: &HostConfig) -> Result<Vec<String>> {
+    if host_config.jump.is_some() || !host_config.via.is_empty() {
+        return Err(anyhow!("telnet profiles can't use jump hosts"));
+    }
+    let mut command = vec!["telnet".to_string()];
+    if let Some(ref user) = host_config.user {
+        command.push("-l".to_string());
+        command.push(user.clone());
+    }
+    command.push(host_config.host.clone());
+    if let Some(port) = host_config.port {
+        command.push(port.to_string());
+    }
+    Ok(command)
+}
+
+/// Port on the host that remote programs send clipboard text to, the one
+/// the clipper tool uses.
+const DEFAULT_CLIPBOARD_PORT: u16 = 2224;
+
+/// Seconds every address of a profile with failover hosts gets to answer.
+const DEFAULT_FAILOVER_TIMEOUT: u64 = 2;
+
+/// How long `--wake` waits for a machine to open its SSH port.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Port of `masuk socks` when neither the command line nor the profile
+/// sets one, the usual SOCKS port.
+const DEFAULT_SOCKS_PORT: u16 = 1080;
+
+/// Opens a tunnel with `command`, an `ssh -f` that's the master of
+/// `socket`, and returns the pid of the process ssh forked into.
+fn start_tunnel(command: &[String], host_config: &HostConfig, socket: &std::path::Path) -> Result<Option<u32>> {
+    let sockets = socket.parent().context("Tunnel socket has no directory")?;
+    fs::create_dir_all(sockets).context("Failed to create tunnels directory")?;
+    fs::set_permissions(sockets, fs::Permissions::from_mode(0o700)).context("Failed to restrict tunnels directory")?;
+    let _ = fs::remove_file(socket);
+    let status = Command::new(&command[0])
+        .args(&command[1..])
+        .envs(&host_config.env)
+        .envs(askpass_env(host_config))
+        .status()
+        .context("Failed to execute SSH command")?;
+    interrupt::check()?;
+    if !status.success() {
+        return Err(ChildExit(exit_code(status)).into());
+    }
+    Ok(tunnel_pid(socket))
+}
+
+/// The pid of the ssh master behind `socket`, from `ssh -O check` printing
+/// "Master running (pid=1234)".
+fn tunnel_pid(socket: &std::path::Path) -> Option<u32> {
+    let check = Command::new("ssh")
+        .arg("-S")
+        .arg(socket)
+        .args(["-O", "check", "masuk"])
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&check.stderr)
+        .split("pid=")
+        .nth(1)
+        .and_then(|rest| rest.split(')').next())
+        .and_then(|pid| pid.parse().ok())
+}
+
+/// Identifies the current boot of the host behind the master at `socket`,
+/// by Linux's boot id or the BSDs' and macOS' boot time.
+fn boot_id(socket: &std::path::Path) -> Option<String> {
+    let output = Command::new("ssh")
+        .arg("-S")
+        .arg(socket)
+        .args(["-o", "ControlMaster=no", "masuk"])
+        .arg("cat /proc/sys/kernel/random/boot_id 2>/dev/null || sysctl -n kern.boottime 2>/dev/null")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !id.is_empty()).then_some(id)
+}
+
+/// Whether the ssh port of the profile accepts connections, `None` when
+/// it's only reached through jump hosts or a proxy.
+fn ssh_port_open(host_config: &HostConfig) -> Option<bool> {
+    use std::net::{TcpStream, ToSocketAddrs};
+
+    if host_config.jump.is_some()
+        || !host_config.via.is_empty()
+        || host_config.socks_proxy.is_some()
+        || host_config.proxy_command.is_some()
+    {
+        return None;
+    }
+    let addrs: Vec<_> = (host_config.host.as_str(), host_config.port.unwrap_or(22))
+        .to_socket_addrs()
+        .map(|addrs| addrs.collect())
+        .unwrap_or_default();
+    Some(addrs.iter().any(|addr| TcpStream::connect_timeout(addr, Duration::from_secs(1)).is_ok()))
+}
+
+/// Quotes a word for the command line of a systemd unit, where `%` and `$`
+/// are expanded too.
+fn systemd_quote(word: &str) -> String {
+    let word = word.replace('%', "%%").replace('$', "$$");
+    if !word.is_empty() && !word.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c)) {
+        return word;
+    }
+    format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Shows a desktop notification with notify-send, or osascript on macOS.
+/// Best effort, failures are ignored.
+fn desktop_notification(message: &str) {
+    let result = if cfg!(target_os = "macos") {
+        let script = format!("display notification \"{}\" with title \"masuk\"", message.replace('"', "\\\""));
+        Command::new("osascript").args(["-e", &script]).stdin(Stdio::null()).output()
+    } else {
+        Command::new("notify-send").args(["masuk", message]).stdin(Stdio::null()).output()
+    };
+    drop(result);
+}
+
+/// Whether a process with this pid exists.
+fn process_alive(pid: u32) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+/// Whether a file system is mounted at `path`.
+fn is_mounted(path: &std::path::Path) -> bool {
+    let path = path.display().to_string();
+    if let Ok(mounts) = fs::read_to_string("/proc/mounts") {
+        // Spaces in mount points are escaped as \040
+        return mounts
+            .lines()
+            .any(|line| line.split(' ').nth(1).is_some_and(|mountpoint| mountpoint.replace("\\040", " ") == path));
+    }
+    Command::new("mount")
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains(&format!(" on {} ", path)))
+}
+
+/// The `[user@]host` of a transfer, with IPv6 addresses in brackets so
+/// scp and rsync can tell them from the path.
+fn transfer_target(target: String) -> String {
+    match target.rsplit_once('@') {
+        Some((user, host)) if host.contains(':') => format!("{}@[{}]", user, host),
+        None if target.contains(':') => format!("[{}]", target),
+        _ => target,
+    }
+}
+
+/// Seconds `masuk tail -f` waits before reconnecting a dropped connection.
+const TAIL_RECONNECT_DELAY: u64 = 5;
+
+/// ANSI colors of the profile names in merged output: red, green, yellow,
+/// blue, magenta and cyan.
+const PREFIX_COLORS: &[u8] = &[31, 32, 33, 34, 35, 36];
+
+/// `tail -n <lines> [-F] -- <files>` for the remote shell. `-F` keeps
+/// following a log across rotation. Globs are left for the remote shell to
+/// expand.
+fn tail_command(files: &[String], lines: u32, follow: bool) -> String {
+    let mut command = format!("tail -n {}", lines);
+    if follow {
+        command.push_str(" -F");
+    }
+    command.push_str(" --");
+    for file in files {
+        let unglobbed = file.replace(['*', '?', '[', ']'], "");
+        let word = if file.contains(['*', '?']) && shell_quote(&unglobbed) == unglobbed {
+            file.clone()
+        } else {
+            shell_quote(file)
+        };
+        command.push(' ');
+        command.push_str(&word);
+    }
+    command
+}
+
+/// Shell started in containers of docker profiles: bash when the image has it.
+const CONTAINER_SHELL: &str = "command -v bash >/dev/null && exec bash || exec sh";
+
+/// `docker exec -i [-t] <container> sh -c <command>`, with the login shell
+/// as the command for interactive sessions. The profile's user is the ssh
+/// user for remote containers and the container user for local ones.
+fn docker_command(profile: &str, host_config: &HostConfig, remote: Option<&(String, bool)>) -> Result<Vec<String>> {
+    let container = host_config
+        .container
+        .clone()
+        .ok_or_else(|| anyhow!("Profile '{}' has no container, set one with --container", profile))?;
+    let mut command = vec!["docker".to_string(), "exec".to_string(), "-i".to_string()];
+    if remote.is_none_or(|(_, tty)| *tty) {
+        command.push("-t".to_string());
+    }
+    if let (true, Some(user)) = (host_config.host.is_empty(), &host_config.user) {
+        command.push("--user".to_string());
+        command.push(user.clone());
+    }
+    command.push(container);
+    command.push("sh".to_string());
+    command.push("-c".to_string());
+    command.push(remote.map_or(CONTAINER_SHELL.to_string(), |(remote_command, _)| remote_command.clone()));
+    Ok(command)
+}
+
+/// `incus exec <instance> -- sh -c <command>`, forcing a terminal or
+/// none to match the session.
+fn incus_command(profile: &str, host_config: &HostConfig, remote: Option<&(String, bool)>) -> Result<Vec<String>> {
+    let instance = host_config
+        .instance
+        .clone()
+        .ok_or_else(|| anyhow!("Profile '{}' has no instance, set one with --instance", profile))?;
+    let tty = remote.is_none_or(|(_, tty)| *tty);
+    let mut command: Vec<String> = vec!["incus".to_string(), "exec".to_string(), instance];
+    command.push(if tty { "--force-interactive" } else { "--force-noninteractive" }.to_string());
+    command.push("--".to_string());
+    command.push("sh".to_string());
+    command.push("-c".to_string());
+    command.push(remote.map_or(CONTAINER_SHELL.to_string(), |(remote_command, _)| remote_command.clone()));
+    Ok(command)
+}
+
+/// `vagrant ssh [machine]` run against the profile's Vagrantfile through
+/// VAGRANT_CWD, so it works from any directory.
+fn vagrant_command(profile: &str, host_config: &HostConfig, remote: Option<&(String, bool)>) -> Result<Vec<String>> {
+    let dir = host_config
+        .vagrant_dir
+        .as_ref()
+        .ok_or_else(|| anyhow!("Profile '{}' has no Vagrant project, set one with --vagrant-dir", profile))?;
+    let mut command: Vec<String> = vec![
+        "env".to_string(),
+        format!("VAGRANT_CWD={}", dir.display()),
+        "vagrant".to_string(),
+        "ssh".to_string(),
+    ];
+    if let Some(ref machine) = host_config.instance {
+        command.push(machine.clone());
+    }
+    if let Some((remote_command, tty)) = remote {
+        command.push("-c".to_string());
+        command.push(remote_command.clone());
+        if *tty {
+            command.push("--".to_string());
+            command.push("-t".to_string());
+        }
+    }
+    Ok(command)
+}
+
+/// `gcloud compute ssh [user@]<instance>` with the profile's project, zone
+/// and IAP setting. Commands are passed with `--command`, and ssh gets `-t`
+/// when they need a terminal.
+fn gcloud_command(profile: &str, host_config: &HostConfig, remote: Option<&(String, bool)>) -> Result<Vec<String>> {
+    let instance = host_config
+        .instance
+        .clone()
+        .ok_or_else(|| anyhow!("Profile '{}' has no instance, set one with --instance", profile))?;
+    let mut command: Vec<String> = ["gcloud", "compute", "ssh"].iter().map(|s| s.to_string()).collect();
+    command.push(match host_config.user {
+        Some(ref user) => format!("{}@{}", user, instance),
+        None => instance,
+    });
+    if let Some(ref project) = host_config.project {
+        command.push(format!("--project={}", project));
+    }
+    if let Some(ref zone) = host_config.zone {
+        command.push(format!("--zone={}", zone));
+    }
+    if host_config.iap {
+        command.push("--tunnel-through-iap".to_string());
+    }
+    if let Some(key) = host_config.keys.first() {
+        command.push(format!("--ssh-key-file={}", key));
+    }
+    if let Some((remote_command, tty)) = remote {
+        command.push(format!("--command={}", remote_command));
+        if *tty {
+            command.push("--".to_string());
+            command.push("-t".to_string());
+        }
+    }
+    Ok(command)
+}
+
+/// `aws ssm start-session --target <instance>` with the profile's AWS
+/// profile and region. Commands go through the AWS-StartInteractiveCommand
+/// document, which always runs them in a terminal.
+fn ssm_command(profile: &str, host_config: &HostConfig, remote: Option<&str>) -> Result<Vec<String>> {
+    let instance_id = host_config
+        .instance_id
+        .clone()
+        .ok_or_else(|| anyhow!("Profile '{}' has no instance, set one with --instance-id", profile))?;
+    let mut command: Vec<String> = ["aws", "ssm", "start-session", "--target"].iter().map(|s| s.to_string()).collect();
+    command.push(instance_id);
+    if let Some(ref aws_profile) = host_config.aws_profile {
+        command.push("--profile".to_string());
+        command.push(aws_profile.clone());
+    }
+    if let Some(ref region) = host_config.region {
+        command.push("--region".to_string());
+        command.push(region.clone());
+    }
+    if let Some(remote_command) = remote {
+        command.push("--document-name".to_string());
+        command.push("AWS-StartInteractiveCommand".to_string());
+        command.push("--parameters".to_string());
+        command.push(json!({ "command": [remote_command] }).to_string());
+    }
+    Ok(command)
+}
+
+/// `kubectl exec -i [-t] <pod> [-c <container>] -- sh -c <command>` with the
+/// profile's context and namespace. A selector is turned into the name of
+/// one of its running pods first.
+fn kubectl_command(profile: &str, host_config: &HostConfig, remote: Option<&(String, bool)>) -> Result<Vec<String>> {
+    let mut kubectl = vec!["kubectl".to_string()];
+    if let Some(ref context) = host_config.context {
+        kubectl.push(format!("--context={}", context));
+    }
+    if let Some(ref namespace) = host_config.namespace {
+        kubectl.push(format!("--namespace={}", namespace));
+    }
+
+    let pod = match (&host_config.pod, &host_config.selector) {
+        (Some(pod), _) => pod.clone(),
+        (None, Some(selector)) => {
+            let output = Command::new(&kubectl[0])
+                .args(&kubectl[1..])
+                .args(["get", "pods", "--field-selector=status.phase=Running", "-o", "jsonpath={.items[0].metadata.name}"])
+                .arg(format!("--selector={}", selector))
+                .stderr(Stdio::inherit())
+                .output()
+                .context("Failed to run 'kubectl'. Is it installed?")?;
+            let pod = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if !output.status.success() || pod.is_empty() {
+                return Err(anyhow!("No running pod matches '{}' for profile '{}'", selector, profile));
+            }
+            pod
+        }
+        (None, None) => return Err(anyhow!("Profile '{}' has no pod, set one with --pod or --selector", profile)),
+    };
+
+    let mut command = kubectl;
+    command.push("exec".to_string());
+    command.push("-i".to_string());
+    if remote.is_none_or(|(_, tty)| *tty) {
+        command.push("-t".to_string());
+    }
+    command.push(pod);
+    if let Some(ref container) = host_config.container {
+        command.push("-c".to_string());
+        command.push(container.clone());
+    }
+    command.push("--".to_string());
+    command.push("sh".to_string());
+    command.push("-c".to_string());
+    command.push(remote.map_or(CONTAINER_SHELL.to_string(), |(remote_command, _)| remote_command.clone()));
+    Ok(command)
+}
+
+/// `picocom -b <baud> <device>`, or `screen <device> <baud>` where picocom
+/// isn't installed.
+fn serial_command(profile: &str, host_config: &HostConfig) -> Result<Vec<String>> {
+    let device = host_config
+        .device
+        .clone()
+        .ok_or_else(|| anyhow!("Profile '{}' has no serial device, set one with --device", profile))?;
+    if in_path("picocom") {
+        let mut command = vec!["picocom".to_string()];
+        if let Some(baud) = host_config.baud {
+            command.push("-b".to_string());
+            command.push(baud.to_string());
+        }
+        command.push(device);
+        return Ok(command);
+    }
+    if in_path("screen") {
+        let mut command = vec!["screen".to_string(), device];
+        if let Some(baud) = host_config.baud {
+            command.push(baud.to_string());
+        }
+        return Ok(command);
+    }
+    Err(anyhow!("Serial consoles need picocom or screen, neither is installed"))
+}
+
+/// Whether `program` is an executable in PATH.
+fn in_path(program: &str) -> bool {
+    env::var_os("PATH").is_some_and(|paths| {
+        env::split_paths(&paths).any(|dir| {
+            fs::metadata(dir.join(program)).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+    })
+}
+
+/// Terminal emulators `masuk open` looks for when none is configured, with
+/// the arguments that make them run a command.
+const TERMINALS: &[&str] = &[
+    "kitty --title {profile}",
+    "alacritty --title {profile} -e",
+    "wezterm start --",
+    "foot --title {profile}",
+    "gnome-terminal --title {profile} --",
+    "konsole -e",
+    "xterm -T {profile} -e",
+];
+
+/// Fills in a terminal template: `{profile}` becomes the profile name and a
+/// `{}` word the command, which is appended when there's none.
+fn terminal_command(template: &str, profile: &str, command: &[String]) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut placed = false;
+    for word in template.split_whitespace() {
+        if word == "{}" {
+            words.extend(command.iter().cloned());
+            placed = true;
+        } else {
+            words.push(word.replace("{profile}", profile));
+        }
+    }
+    if !placed {
+        words.extend(command.iter().cloned());
+    }
+    words
+}
+
+/// Turns a complete ssh command into the mosh command that uses it for
+/// the handshake, e.g. `mosh --ssh='ssh -p 2222' user@host`. Options that
+/// need an ssh session of their own (forwardings, tty) are left out.
+fn mosh_command(host_config: &HostConfig, mut ssh: Vec<String>, remote: Option<&str>) -> Vec<String> {
+    let target = ssh.pop().unwrap_or_default();
+    ssh.retain(|arg| !matches!(arg.as_str(), "-A" | "-X" | "-Y" | "-t"));
+
+    let mut command = vec!["mosh".to_string(), format!("--ssh={}", shell_join(&ssh))];
+    if let Some(ref ports) = host_config.mosh_ports {
+        command.push(format!("--port={}", ports));
+    }
+    if let Some(ref server) = host_config.mosh_server {
+        command.push(format!("--server={}", server));
+    }
+    command.push(target);
+    if let Some(remote) = remote {
+        // mosh runs the command directly, not through the login shell
+        command.extend(["--".to_string(), "sh".to_string(), "-c".to_string(), remote.to_string()]);
+    }
+    command
+}
+
+/// Opens a tmux window with one pane per command, tiled. Inside tmux the
+/// window is added to the current session, otherwise a new session is
+/// started and attached.
+fn tmux_panes(name: &str, commands: &[String]) -> Result<()> {
+    let tmux = |args: &[&str]| -> Result<String> {
+        let output = Command::new("tmux")
+            .args(args)
+            .stderr(Stdio::inherit())
+            .output()
+            .context("Failed to run tmux. Is it installed?")?;
+        if !output.status.success() {
+            return Err(anyhow!("tmux {} failed", args[0]));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+
+    let inside = env::var_os("TMUX").is_some();
+    let session = format!("masuk-{}", std::process::id());
+    let window = if inside {
+        tmux(&["new-window", "-P", "-F", "#{window_id}", "-n", name, &commands[0]])?
+    } else {
+        tmux(&["new-session", "-d", "-P", "-F", "#{window_id}", "-s", &session, "-n", name, &commands[0]])?
+    };
+    for command in &commands[1..] {
+        tmux(&["split-window", "-t", &window, command])?;
+        // Retile after every split so there's room for the next one
+        tmux(&["select-layout", "-t", &window, "tiled"])?;
+    }
+
+    if !inside {
+        let status = Command::new("tmux")
+            .args(["attach-session", "-t", &session])
+            .status()
+            .context("Failed to run tmux. Is it installed?")?;
+        if !status.success() {
+            return Err(ChildExit(exit_code(status)).into());
+        }
+    }
+    Ok(())
+}
+
+/// Unit properties `masuk svc` reports.
+const UNIT_PROPERTIES: &str = "LoadState,ActiveState,SubState,UnitFileState,StateChangeTimestamp";
+
+/// Prepended to remote commands of profiles with a sudo password. It reads
+/// the password from the first line of stdin into a variable, so it never
+/// shows up in a process list, and makes `sudo` in the command use it.
+const SUDO_PREAMBLE: &str = "IFS= read -r MASUK_SUDO_PASSWORD; \
+sudo() { printf '%s\\n' \"$MASUK_SUDO_PASSWORD\" | command sudo -S -p '' \"$@\"; }; ";
+
+/// Environment that makes ssh ask masuk for the password or key passphrase
+/// of a profile that has one: masuk is run as SSH_ASKPASS and resolves the
+/// references itself (see [`askpass`]), so the secrets are never in the
+/// environment.
+fn askpass_env(host_config: &HostConfig) -> Vec<(&'static str, String)> {
+    let Ok(masuk) = env::current_exe() else {
+        return Vec::new();
+    };
+    let mut vars = Vec::new();
+    if let Some(ref reference) = host_config.password {
+        vars.push(("MASUK_ASKPASS", reference.clone()));
+    }
+    if let Some(ref reference) = host_config.key_passphrase {
+        vars.push(("MASUK_ASKPASS_PASSPHRASE", reference.clone()));
+    }
+    if let Some(ref reference) = host_config.totp {
+        vars.push(("MASUK_ASKPASS_TOTP", reference.clone()));
+    }
+    if !vars.is_empty() {
+        vars.push(("SSH_ASKPASS", masuk.display().to_string()));
+        vars.push(("SSH_ASKPASS_REQUIRE", "force".to_string()));
+    }
+    vars
+}
+
+/// Words in the prompts of PAM modules asking for an authenticator code.
+const TOTP_PROMPTS: &[&str] = &["verification code", "one-time", "otp", "authenticator", "token", "2fa"];
+
+/// masuk as SSH_ASKPASS: answers password, passphrase and authenticator
+/// code prompts with the secrets the references in the environment point
+/// to. Everything else ssh asks, like whether to trust a new host key, is
+/// passed on to the terminal.
+fn askpass(prompt: &str) -> Result<()> {
+    let asked = prompt.to_lowercase();
+    // "One-time password" is a code, not the password
+    if let (true, Ok(reference)) = (TOTP_PROMPTS.iter().any(|word| asked.contains(word)), env::var("MASUK_ASKPASS_TOTP")) {
+        let secret = masuk::secrets::Registry::default().resolve(&reference)?;
+        println!("{}", totp::Totp::parse(&secret)?.now().0);
+        return Ok(());
+    }
+    let reference = if asked.contains("passphrase") {
+        env::var("MASUK_ASKPASS_PASSPHRASE").ok()
+    } else if asked.contains("password") {
+        env::var("MASUK_ASKPASS").ok()
+    } else {
+        None
+    };
+    if let Some(reference) = reference {
+        println!("{}", masuk::secrets::Registry::default().resolve(&reference)?);
+        return Ok(());
+    }
+    let mut tty = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("No terminal to ask on")?;
+    tty.write_all(prompt.as_bytes())?;
+    tty.flush()?;
+    let mut answer = String::new();
+    std::io::BufRead::read_line(&mut std::io::BufReader::new(tty), &mut answer)?;
+    println!("{}", answer.trim_end_matches(['\r', '\n']));
+    Ok(())
+}
+
+/// Reads a line from the terminal without echoing it.
+fn read_password(prompt: &str) -> Result<String> {
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+
+    let mut saved = std::mem::MaybeUninit::<libc::termios>::uninit();
+    let is_tty = unsafe { libc::tcgetattr(libc::STDIN_FILENO, saved.as_mut_ptr()) } == 0;
+    if is_tty {
+        let mut silent = unsafe { saved.assume_init() };
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) };
+    }
+    let mut password = String::new();
+    let result = std::io::stdin().read_line(&mut password);
+    if is_tty {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved.as_ptr()) };
+        eprintln!();
+    }
+    result?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Runs a connection with its stderr passed through, noting whether ssh
+/// reported a timeout on the way.
+fn run_watching_timeout(
+    command: &mut Command,
+    input: Option<&str>,
+    prefix: Option<&str>,
+) -> std::io::Result<(std::process::ExitStatus, bool)> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    if input.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    if prefix.is_some() {
+        command.stdout(Stdio::piped());
+    }
+    let mut child = command.stderr(Stdio::piped()).spawn()?;
+    // Dropping stdin after writing gives the child EOF
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes())?;
+    }
+
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let mut forwarders = vec![forward_output(stderr, true, prefix, Some(Arc::clone(&timed_out)))];
+    if let Some(stdout) = child.stdout.take() {
+        forwarders.push(forward_output(stdout, false, prefix, None));
+    }
+
+    let status = child.wait()?;
+    // A ControlPersist master forked by ssh inherits the pipe and keeps it
+    // open, so only wait briefly for the rest of the output
+    let deadline = Instant::now() + Duration::from_millis(200);
+    while !forwarders.iter().all(|f| f.is_finished()) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    Ok((status, timed_out.load(Ordering::SeqCst)))
+}
+
+/// Copies a child's output to ours in a thread. With a prefix, output is
+/// forwarded line by line, each line starting with the prefix; otherwise
+/// as it arrives, so prompts without a newline show up. `timed_out` is set
+/// when ssh reports a timeout.
+fn forward_output(
+    reader: impl std::io::Read + Send + 'static,
+    to_stderr: bool,
+    prefix: Option<&str>,
+    timed_out: Option<std::sync::Arc<std::sync::atomic::AtomicBool>>,
+) -> std::thread::JoinHandle<()> {
+    use std::io::BufRead;
+
+    let prefix = prefix.map(str::to_string);
+    std::thread::spawn(move || {
+        let mut reader = std::io::BufReader::new(reader);
+        // Keep a little of the previous chunk, messages may be split
+        let mut tail = Vec::new();
+        let mut chunk = Vec::new();
+        loop {
+            chunk.clear();
+            let read = match prefix {
+                Some(_) => reader.read_until(b'\n', &mut chunk),
+                None => reader.fill_buf().map(|buffer| {
+                    chunk.extend_from_slice(buffer);
+                    buffer.len()
+                }),
+            };
+            match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if prefix.is_none() {
+                        reader.consume(n);
+                    }
+                }
+            }
+
+            let mut out: Vec<u8> = Vec::with_capacity(chunk.len() + 32);
+            if let Some(ref prefix) = prefix {
+                out.extend_from_slice(prefix.as_bytes());
+                out.extend_from_slice(&chunk);
+                if !chunk.ends_with(b"\n") {
+                    out.push(b'\n');
+                }
+            } else {
+                out.extend_from_slice(&chunk);
+            }
+            // Each write is a whole line, so lines of parallel hosts don't mix
+            let _ = if to_stderr {
+                std::io::stderr().lock().write_all(&out).and_then(|_| std::io::stderr().flush())
+            } else {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&out).and_then(|_| stdout.flush())
+            };
+
+            if let Some(ref timed_out) = timed_out {
+                tail.extend_from_slice(&chunk);
+                if String::from_utf8_lossy(&tail).contains("timed out") {
+                    timed_out.store(true, std::sync::atomic::Ordering::SeqCst);
+                }
+                let keep = tail.len().saturating_sub(64);
+                tail.drain(..keep);
+            }
+        }
+    })
+}
+
+/// A profile as an entry of a list, with all its settings as data.
+fn profile_item(profile: &str, host_config: &HostConfig) -> Item {
+    let mut data = serde_json::to_value(host_config).unwrap_or_default();
+    data["name"] = json!(profile);
+    Item {
+        name: profile.to_string(),
+        description: host_config.describe(),
+        data,
+    }
+}
+
+/// Next number of a xorshift generator, good enough for spreading load.
+fn random(state: &mut u64) -> u64 {
+    if *state == 0 {
+        *state = 0x9e37_79b9_7f4a_7c15;
+    }
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// Exit code of a child, using the shell convention of 128 + signal number
+/// for children killed by a signal.
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+fn format_duration(secs: u64) -> String {
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+/// Formats unix time as a UTC date, `YYYY-MM-DD`.
+fn format_date(time: i64) -> String {
+    // Howard Hinnant's days-to-civil algorithm
+    let z = time.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Orders names the way people read them: ignoring case and comparing runs
+/// of digits by value, so `web2` comes before `web10`.
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return std::cmp::Ordering::Equal,
+            (None, Some(_)) => return std::cmp::Ordering::Less,
+            (Some(_), None) => return std::cmp::Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+                        digits.push(c);
+                    }
+                    digits
+                };
+                let (x, y) = (take_number(&mut a), take_number(&mut b));
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                let order = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+                if order.is_ne() {
+                    return order;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let order = x.to_lowercase().cmp(y.to_lowercase()).then(x.cmp(&y));
+                if order.is_ne() {
+                    return order;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+/// Resolves the host of a profile to an IP address, preferring IPv4.
+fn resolve_ip(host_config: &HostConfig) -> Result<String> {
+    use std::net::ToSocketAddrs;
+
+    let addrs: Vec<_> = (host_config.host.as_str(), host_config.port.unwrap_or(22))
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve '{}'", host_config.host))?
+        .map(|addr| addr.ip())
+        .collect();
+    addrs
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| addrs.first())
+        .map(|ip| ip.to_string())
+        .ok_or_else(|| anyhow!("'{}' did not resolve to any address", host_config.host))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Name of the local user, as reported to webhooks.
+fn local_user() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("LOGNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// POSTs an event to each webhook with curl. Delivery problems are reported
+/// but never stop a connection.
+fn send_webhooks(urls: &[String], event: &serde_json::Value) {
+    for url in urls {
+        let result = Command::new("curl")
+            .args(["-fsS", "-m", "5", "-o", "/dev/null", "-X", "POST"])
+            .args(["-H", "Content-Type: application/json", "--data-binary", "@-"])
+            .arg(url)
+            .stdin(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(event.to_string().as_bytes())?;
+                }
+                child.wait()
+            });
+
+        match result {
+            Ok(status) if status.success() => {}
+            Ok(status) => eprintln!("Warning: webhook {} failed ({})", url, status),
+            Err(e) => eprintln!("Warning: could not call webhook {}: {}", url, e),
+        }
+    }
+}
+
+/// Runs a pre/post connect hook with `sh -c`, describing the profile in
+/// `MASUK_*` variables. Its output goes to stderr so it never mixes with the
+/// output of `masuk exec`.
+fn run_hook(
+    hook: &str,
+    profile: &str,
+    host_config: &HostConfig,
+    extra_env: &[(&str, String)],
+) -> Result<std::process::ExitStatus> {
+    let mut command = Command::new("sh");
+    command
+        .args(["-c", hook])
+        .env("MASUK_PROFILE", profile)
+        .env("MASUK_HOST", &host_config.host)
+        .env("MASUK_USER", host_config.user.as_deref().unwrap_or_default())
+        .env("MASUK_PORT", host_config.port.unwrap_or(22).to_string())
+        .env("MASUK_TAGS", host_config.tags.join(","))
+        .envs(extra_env.iter().map(|(name, value)| (name, value)))
+        .stdout(std::io::stderr());
+    command
+        .status()
+        .with_context(|| format!("Failed to run hook for '{}'", profile))
+}
+
+/// Replaces every `${VAR}` in `value` with the value of the environment
+/// variable, failing on unset variables rather than silently dropping them.
+fn expand_env(value: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated '${{' in '{}'", value))?;
+        let name = &after[..end];
+        let var = env::var(name)
+            .map_err(|_| anyhow!("Environment variable '{}' used in '{}' is not set", name, value))?;
+        expanded.push_str(&var);
+        rest = &after[end + 1..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Expands a leading `~` to the home directory, as a shell would have
+/// done had the path not come from the config file.
+fn expand_tilde(path: &str) -> String {
+    match (path.strip_prefix('~'), dirs::home_dir()) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{}", home.display(), rest)
+        }
+        _ => path.to_string(),
+    }
+}
+
+/// Wraps a command so it runs as the profile's local user and/or in a
+/// systemd scope. Recording wraps the result, so logs stay with the caller.
+fn isolate(host_config: &HostConfig, command: Vec<String>) -> Vec<String> {
+    let mut command = command;
+
+    if host_config.systemd_scope {
+        let mut wrapped: Vec<String> = ["systemd-run", "--user", "--scope", "--quiet", "--collect", "--"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        wrapped.extend(command);
+        command = wrapped;
+    }
+
+    if let Some(ref user) = host_config.local_user {
+        let mut wrapped: Vec<String> = vec!["sudo".to_string(), "-u".to_string(), user.clone(), "-H".to_string()];
+        // sudo resets the environment, keep what SendEnv should pass on
+        if !host_config.env.is_empty() {
+            let names: Vec<&str> = host_config.env.keys().map(String::as_str).collect();
+            wrapped.push(format!("--preserve-env={}", names.join(",")));
+        }
+        wrapped.push("--".to_string());
+        wrapped.extend(command);
+        command = wrapped;
+    }
+
+    command
+}
+
+/// Matches `text` against a shell-style pattern with `*` and `?`.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last `*` swallow one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Remote snippet printing `name=value` facts for `masuk bootstrap`.
+const FACTS_SCRIPT: &str = concat!(
+    "echo \"os=$(. /etc/os-release 2>/dev/null && echo \"$PRETTY_NAME\")\"; ",
+    "echo \"kernel=$(uname -sr)\"; ",
+    "echo \"arch=$(uname -m)\"; ",
+    "echo \"cpus=$(nproc 2>/dev/null || getconf _NPROCESSORS_ONLN)\"; ",
+    "awk '/^MemTotal:/ { print \"memory=\" int($2 / 1024) \" MB\" }' /proc/meminfo 2>/dev/null; ",
+    "df -h / 2>/dev/null | awk 'NR == 2 { print \"disk=\" $2 }'",
+);
+
+/// Asks a yes/no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> Result<bool> {
+    // On stderr, so it doesn't end up in piped output
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Listening TCP ports in `ss -ltn` output, mapped to the address to
+/// forward to. Servers bound to all addresses are reached via localhost.
+fn listening_ports(output: &str) -> BTreeMap<u16, String> {
+    let mut ports = BTreeMap::new();
+    for line in output.lines() {
+        let Some(local) = line.split_whitespace().nth(3) else {
+            continue;
+        };
+        let Some((address, port)) = local.rsplit_once(':') else {
+            continue;
+        };
+        let Ok(port) = port.parse::<u16>() else {
+            continue;
+        };
+        let address = match address {
+            "*" | "0.0.0.0" | "[::]" => "localhost".to_string(),
+            // Strip the interface of addresses like 127.0.0.53%lo
+            address => address.split('%').next().unwrap_or(address).to_string(),
+        };
+        let entry = ports.entry(port).or_insert_with(|| address.clone());
+        if address == "localhost" {
+            *entry = address;
+        }
+    }
+    ports
+}
+
+/// Parses durations like `30`, `5s`, `10m` or `1h` into seconds.
+fn parse_duration(value: &str) -> Result<u64, String> {
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let number: u64 = number.parse().map_err(|_| format!("invalid duration '{}'", value))?;
+    let factor = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return Err(format!("invalid duration '{}', use e.g. 30s, 5m or 1h", value)),
+    };
+    number.checked_mul(factor).ok_or_else(|| format!("invalid duration '{}'", value))
+}
+
+/// Parses transfer rates like `800K`, `2M` or `1G` (bytes per second) into
+/// KiB per second, the unit of rsync's `--bwlimit`. Plain numbers are KiB.
+fn parse_rate(value: &str) -> Result<u64, String> {
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let number: u64 = number.parse().map_err(|_| format!("invalid rate '{}'", value))?;
+    let factor = match unit.trim_end_matches(['B', 'b']).to_ascii_uppercase().as_str() {
+        "" | "K" => 1,
+        "M" => 1024,
+        "G" => 1024 * 1024,
+        _ => return Err(format!("invalid rate '{}', use e.g. 500K or 2M", value)),
+    };
+    if number == 0 {
+        return Err("the rate has to be more than 0".to_string());
+    }
+    // Has to fit in Kbit/s too, which is what scp counts in
+    number
+        .checked_mul(factor)
+        .filter(|&rate| rate <= u64::MAX / 8)
+        .ok_or_else(|| format!("invalid rate '{}'", value))
+}
+
+/// Parses a mosh port or port range, normalized to mosh's `lo:hi` form.
+fn parse_mosh_ports(value: &str) -> Result<String, String> {
+    let range = parse_port_range(&value.replace(':', "-"))?;
+    if range.start() == range.end() {
+        return Ok(range.start().to_string());
+    }
+    Ok(format!("{}:{}", range.start(), range.end()))
+}
+
+/// Parses `NAME=VALUE`.
+fn parse_env_var(value: &str) -> Result<(String, String), String> {
+    let (name, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", value))?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("invalid variable name '{}'", name));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Accepts `<scheme>://<path>` secret references, or "" for unsetting.
+fn parse_secret_reference(value: &str) -> Result<String, String> {
+    if value.is_empty() || masuk::secrets::parse_reference(value).is_some() {
+        Ok(value.to_string())
+    } else {
+        Err(format!("expected a secret reference like keyring://<service>/<account>, got '{}'", value))
+    }
+}
+
+/// Parses a MAC address into `aa:bb:cc:dd:ee:ff` form, or "" for unsetting.
+fn parse_mac(value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        return Ok(String::new());
+    }
+    let mac = wol::parse_mac(value).ok_or_else(|| format!("expected a MAC address like aa:bb:cc:dd:ee:ff, got '{}'", value))?;
+    Ok(mac.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(":"))
+}
+
+/// Parses `3000-3999` or a single port.
+fn parse_port_range(value: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = value.split_once('-').unwrap_or((value, value));
+    let start: u16 = start.trim().parse().map_err(|_| format!("invalid port '{}'", start))?;
+    let end: u16 = end.trim().parse().map_err(|_| format!("invalid port '{}'", end))?;
+    if start > end {
+        return Err(format!("range {} starts after it ends", value));
+    }
+    Ok(start..=end)
+}
+
+/// Parses a forwarding like ssh's `-L` and `-R`: `[BIND:]PORT:HOST:HOSTPORT`,
+/// or a lone `PORT` for the same port on localhost of the other side.
+fn parse_forward(value: &str) -> Result<String, String> {
+    if value.parse::<u16>().is_ok() {
+        return Ok(format!("{}:localhost:{}", value, value));
+    }
+    match split_forward(value) {
+        Some((_, port, host, host_port)) if !host.is_empty() && port.parse::<u16>().is_ok() && host_port.parse::<u16>().is_ok() => {
+            Ok(value.to_string())
+        }
+        _ => Err(format!("expected [BIND:]PORT:HOST:HOSTPORT, got '{}'", value)),
+    }
+}
+
+/// Like [`parse_forward`], or "" for clearing.
+fn parse_saved_forward(value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        return Ok(String::new());
+    }
+    parse_forward(value)
+}
+
+/// Parses `NAME=SPEC` with a [`parse_forward`] spec, or "" for clearing.
+fn parse_named_tunnel(value: &str) -> Result<(String, String), String> {
+    if value.is_empty() {
+        return Ok((String::new(), String::new()));
+    }
+    let (name, spec) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=PORT:HOST:PORT, got '{}'", value))?;
+    if name.is_empty() || name.starts_with('-') || name.contains(char::is_whitespace) {
+        return Err(format!("invalid tunnel name '{}'", name));
+    }
+    Ok((name.to_string(), parse_forward(spec)?))
+}
+
+/// The bind address, port, host and host port of a forwarding. Addresses
+/// may be IPv6 in brackets.
+fn split_forward(spec: &str) -> Option<(Option<&str>, &str, &str, &str)> {
+    let (rest, host_port) = spec.rsplit_once(':')?;
+    let split = if rest.ends_with(']') { rest.rfind(":[")? } else { rest.rfind(':')? };
+    let (rest, host) = (&rest[..split], &rest[split + 1..]);
+    let (bind, port) = match rest.rsplit_once(':') {
+        Some((bind, port)) => (Some(bind), port),
+        None => (None, rest),
+    };
+    Some((bind, port, host, host_port))
+}
+
+/// Where a forwarding listens and where it leads, e.g. `localhost:8080`
+/// and `db:5432`.
+fn forward_endpoints(spec: &str) -> (String, String) {
+    let (bind, port, host, host_port) = split_forward(spec).unwrap_or_default();
+    let bind = match bind {
+        None => "localhost",
+        Some("") => "*",
+        Some(bind) => bind,
+    };
+    (format!("{}:{}", bind, port), format!("{}:{}", host, host_port))
+}
+
+/// Percent-encodes everything but unreserved URL characters.
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// The config file of a context, `~/.config/masuk/contexts/<context>/config.json`.
+fn context_config(state_dir: &std::path::Path, context: &str) -> Result<PathBuf> {
+    if context.is_empty() || context.starts_with('.') || context.contains('/') {
+        return Err(anyhow!("Invalid context name '{}'", context));
+    }
+    Ok(state_dir.join("contexts").join(context).join("config.json"))
+}
+
+/// Whether `uname -sm` output describes the platform `os arch` (as in
+/// `std::env::consts`) this binary was built for.
+fn same_platform(uname: &str, local: &str) -> bool {
+    let uname = uname.trim().to_lowercase().replace("darwin", "macos").replace("arm64", "aarch64");
+    uname == local
+}
+
+/// Quotes a value for ssh_config when it contains spaces.
+fn config_quote(value: &str) -> String {
+    if value.contains(char::is_whitespace) {
+        format!("\"{}\"", value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Quotes a single word for POSIX shells, leaving plain words untouched.
+fn shell_quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@%+=:,./-_".contains(c));
+    if plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+fn shell_join(command: &[String]) -> String {
+    command
+        .iter()
+        .map(|word| shell_quote(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Parser)]
+#[command(name = "masuk")]
+#[command(about = "SSH host and port manager", long_about = None)]
+struct Cli {
+    /// Print results as JSON, one document per line
+    #[arg(long, global = true)]
+    json: bool,
+    /// Only print names in lists and nothing on success
+    #[arg(short = 'q', long, global = true, conflicts_with = "json")]
+    quiet: bool,
+    /// Plain ASCII output with words instead of symbols, for screen readers and dumb terminals (default with TERM=dumb)
+    #[arg(long, global = true)]
+    ascii: bool,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
 enum Commands {
     #[command(about = "Add a profile with host and optional user/port/key. Example: 'masuk add foobar -h 192.168.1.81 -u root -p 2222 -k ~/.ssh/id_rsa'")]
+    // -h is taken by --host, so help is only available as --help
+    #[command(disable_help_flag = true)]
+    Add {
+        /// Profile name
+        profile: String,
+        /// Host/IP address
+        #[arg(short = 'h', long, required_unless_present_any = ["device", "container", "pod", "selector", "instance_id", "instance", "vagrant_dir"])]
+        host: Option<String>,
+        #[command(flatten)]
+        settings: ProfileArgs,
+        /// Print help
+        #[arg(long, action = ArgAction::Help)]
+        help: Option<bool>,
+    },
+    #[command(about = "Change settings of an existing profile. Example: 'masuk set foobar -i ~/.ssh/id_ed25519'")]
+    #[command(disable_help_flag = true)]
+    Set {
+        /// Profile name
+        profile: String,
+        /// Host/IP address
+        #[arg(short = 'h', long)]
+        host: Option<String>,
+        #[command(flatten)]
+        settings: ProfileArgs,
+        /// Print help
+        #[arg(long, action = ArgAction::Help)]
+        help: Option<bool>,
+    },
+    #[command(about = "List all configured profiles")]
+    #[command(alias = "ls")]
+    List {
+        /// Group the profiles instead of listing them alphabetically
+        #[arg(long, value_enum)]
+        group_by: Option<GroupBy>,
+    },
+    #[command(about = "Show the settings of a profile. Example: 'masuk show foobar --resolved'")]
+    Show {
+        /// Profile name
+        profile: String,
+        /// Expand variables and show the full path through jump hosts
+        #[arg(long)]
+        resolved: bool,
+    },
+    #[command(about = "Remove a profile. Example: 'masuk remove foobar'")]
+    #[command(alias = "rm")]
+    Remove {
+        /// Profile name
+        profile: String,
+    },
+    #[command(about = "Pre-establish master connections so later connects are instant. Example: 'masuk warm --tag daily'")]
+    Warm {
+        /// Profiles to warm
+        profiles: Vec<String>,
+        /// Also warm every profile with this tag (repeatable)
+        #[arg(short = 't', long = "tag")]
+        tags: Vec<String>,
+        /// How long masters stay up without connections (ssh ControlPersist)
+        #[arg(long, default_value = "10m")]
+        persist: String,
+    },
+    #[command(about = "Keep one connection open for many commands. Example: 'sock=$(masuk session open foobar)'")]
+    Session {
+        #[command(subcommand)]
+        action: SessionAction,
+    },
+    #[command(about = "Inspect and stop master connections")]
+    Mux {
+        #[command(subcommand)]
+        action: MuxAction,
+    },
+    #[command(about = "Map profile names to their addresses in /etc/hosts (uses sudo)")]
+    Hosts {
+        #[command(subcommand)]
+        action: HostsAction,
+    },
+    #[command(about = "Set connection settings for profiles that don't set them, globally or per tag")]
+    Defaults {
+        #[command(subcommand)]
+        action: DefaultsAction,
+    },
+    #[command(about = "Manage per-tag connection policies")]
+    Policy {
+        #[command(subcommand)]
+        action: PolicyAction,
+    },
+    #[command(about = "Record the inventory and report drift since, for access reviews")]
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+    #[command(about = "Connect in a new terminal window, for launchers. Example: 'masuk open foobar'")]
+    Open {
+        /// Profile name
+        profile: String,
+        /// Options for 'masuk connect', e.g. --mosh
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    #[command(about = "Print a URL for a profile, for file managers and other tools. Example: 'masuk url foobar /var/www'")]
+    Url {
+        /// Profile name
+        profile: String,
+        /// Remote path to append
+        path: Option<String>,
+        /// URL scheme
+        #[arg(long, value_enum, default_value_t = UrlScheme::Sftp)]
+        scheme: UrlScheme,
+    },
+    #[command(about = "Copy files to or from a profile with scp. Example: 'masuk cp web1:/var/log/app.log .'")]
+    Cp {
+        /// Copy directories recursively
+        #[arg(short = 'r', long)]
+        recursive: bool,
+        /// Bandwidth limit in bytes per second, e.g. 500K or 2M
+        #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+        limit: Option<u64>,
+        /// Print the scp command instead of running it
+        #[arg(long)]
+        dry_run: bool,
+        /// Sources, then the destination; remote paths as <profile>:<path>
+        #[arg(value_name = "PATH", num_args = 2.., required = true)]
+        paths: Vec<String>,
+    },
+    #[command(about = "Copy files into the remote directory of a profile, or of all with --tag. Example: 'masuk push --tag web release.tar.gz'")]
+    Push {
+        /// Copy into the remote directory of every profile with this tag, at once (repeatable)
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Profile name (unless --tag is given), then the local files to copy
+        #[arg(value_name = "[PROFILE] FILE", required = true)]
+        args: Vec<String>,
+        /// Copy directories recursively
+        #[arg(short = 'r', long)]
+        recursive: bool,
+        /// Bandwidth limit in bytes per second, e.g. 500K or 2M
+        #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+        limit: Option<u64>,
+        /// Print the scp command instead of running it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    #[command(about = "Copy files of a profile into its local directory, or of all with --tag. Example: 'masuk pull web1 logs/app.log'")]
+    Pull {
+        /// Copy from every profile with this tag, at once, into a directory per profile (repeatable)
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Profile name (unless --tag is given), then the remote files, relative to the remote directory
+        #[arg(value_name = "[PROFILE] FILE", required = true)]
+        args: Vec<String>,
+        /// Copy directories recursively
+        #[arg(short = 'r', long)]
+        recursive: bool,
+        /// Bandwidth limit in bytes per second, e.g. 500K or 2M
+        #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+        limit: Option<u64>,
+        /// Print the scp command instead of running it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    #[command(about = "Sync files with a profile over rsync. Example: 'masuk rsync ./site web1:/srv/www -- -av --delete'")]
+    Rsync {
+        /// Bandwidth limit in bytes per second, e.g. 500K or 2M
+        #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+        limit: Option<u64>,
+        /// Show the progress of the whole transfer
+        #[arg(long)]
+        progress: bool,
+        /// Print the rsync command instead of running it (rsync's own dry run is -- -n)
+        #[arg(long)]
+        dry_run: bool,
+        /// Sources, then the destination; remote paths as <profile>:<path>
+        #[arg(value_name = "PATH", num_args = 2.., required = true)]
+        paths: Vec<String>,
+        /// Options for rsync, e.g. -av --delete
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    #[command(about = "Mount a directory of a profile with sshfs. Example: 'masuk mount web1 /srv/www'")]
+    Mount {
+        /// Profile name
+        #[arg(required_unless_present = "list")]
+        profile: Option<String>,
+        /// Remote directory (default: the home directory)
+        remote: Option<String>,
+        /// Where to mount it (default: ~/mnt/<profile>)
+        mountpoint: Option<PathBuf>,
+        /// List the mounts made with masuk instead
+        #[arg(long, conflicts_with_all = ["profile", "dry_run"])]
+        list: bool,
+        /// Print the sshfs command instead of running it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    #[command(about = "Unmount what 'masuk mount' mounted for a profile. Example: 'masuk umount web1'")]
+    Umount {
+        /// Profile name
+        profile: String,
+    },
+    #[command(about = "Open an interactive sftp session on a profile. Example: 'masuk sftp web1'")]
+    Sftp {
+        /// Profile name
+        profile: String,
+        /// Print the sftp command instead of running it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    #[command(about = "Edit a remote file in your local editor. Example: 'masuk edit-remote web1 /etc/nginx/nginx.conf --sudo'")]
+    EditRemote {
+        /// Profile name
+        profile: String,
+        /// Remote file to edit
+        path: String,
+        /// Read and write the file with sudo on the host (needs sudo without a password prompt)
+        #[arg(long)]
+        sudo: bool,
+        /// Write the changes without asking
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+    #[command(about = "Forward remote ports to localhost as servers start listening. Example: 'masuk forward foobar --auto 3000-3999'")]
+    Forward {
+        /// Profile name
+        profile: String,
+        /// Remote ports to watch, e.g. 3000-3999
+        #[arg(long, value_parser = parse_port_range)]
+        auto: RangeInclusive<u16>,
+        /// Seconds between checks for new servers
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
+    #[command(about = "Forward local ports through a profile. Example: 'masuk tunnel db1 -L 5432:localhost:5432'")]
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Tunnel {
+        #[command(subcommand)]
+        action: Option<TunnelAction>,
+        /// Profile name
+        #[arg(required = true)]
+        profile: Option<String>,
+        #[command(flatten)]
+        options: TunnelOptions,
+    },
+    #[command(about = "Open a SOCKS proxy through a profile, for browsers. Example: 'masuk socks bastion --background'")]
+    Socks {
+        /// Profile name
+        profile: String,
+        /// Local port of the proxy (default: the profile's socks port, or 1080)
+        #[arg(long)]
+        port: Option<u16>,
+        /// Keep the proxy open in the background
+        #[arg(short = 'b', long)]
+        background: bool,
+        /// Print the ssh command instead of running it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    #[command(about = "Create profiles from other sources")]
+    Import {
+        #[command(subcommand)]
+        source: ImportSource,
+    },
+    #[command(about = "Store the sudo password of a profile in the keychain, for 'masuk exec'. Example: 'masuk sudo-password web1'")]
+    SudoPassword {
+        /// Profile name
+        profile: String,
+    },
+    #[command(about = "Store passwords and key passphrases of a profile in the keychain. Example: 'masuk secret set router1'")]
+    Secret {
+        #[command(subcommand)]
+        action: SecretAction,
+    },
+    #[command(about = "Print profiles in other formats")]
+    Export {
+        #[command(subcommand)]
+        format: ExportFormat,
+    },
+    #[command(about = "Set up a new host: wait for it, install your key, run a script, gather facts. Example: 'masuk bootstrap vm1 --copy-id --script setup.sh'")]
+    Bootstrap {
+        /// Profile name
+        profile: String,
+        #[command(flatten)]
+        options: BootstrapOptions,
+    },
+    #[command(about = "Copy profiles onto a jump host so the same names work there. Example: 'masuk seed bastion --tag lab'")]
+    Seed {
+        /// Profile of the host to copy the profiles to
+        profile: String,
+        /// Profiles to copy, all of them when none are given
+        profiles: Vec<String>,
+        /// Copy every profile with this tag (repeatable)
+        #[arg(short = 't', long = "tag")]
+        tags: Vec<String>,
+        /// Write an ssh_config fragment for plain ssh instead of masuk's config
+        #[arg(long)]
+        ssh_config: bool,
+        /// Also install this masuk binary in ~/.local/bin on the host
+        #[arg(long, conflicts_with = "ssh_config")]
+        install: bool,
+    },
+    #[command(about = "Check that hosts have the tools and disk space their profiles expect. Example: 'masuk preflight --tag dev'")]
+    Preflight {
+        /// Profiles to check
+        profiles: Vec<String>,
+        /// Also check every profile with this tag (repeatable)
+        #[arg(short = 't', long = "tag")]
+        tags: Vec<String>,
+    },
+    #[command(about = "Manage known host keys")]
+    Hostkey {
+        #[command(subcommand)]
+        action: HostkeyAction,
+    },
+    #[command(about = "Switch masuk to another set of profiles per directory. Example: 'eval \"$(masuk env export acme)\"' in .envrc")]
+    Env {
+        #[command(subcommand)]
+        action: EnvAction,
+    },
+    #[command(about = "Change global settings. Example: 'masuk config set ssh-command autossh -M 0'")]
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    #[command(about = "Print the current authenticator code of a profile. Example: 'masuk otp bastion --copy'")]
+    Otp {
+        /// Profile name
+        profile: String,
+        /// Copy the code to the clipboard instead of printing it
+        #[arg(short = 'c', long)]
+        copy: bool,
+    },
+    #[command(about = "Wake a sleeping machine with Wake-on-LAN. Example: 'masuk wake nas'")]
+    Wake {
+        /// Profile name
+        profile: String,
+    },
+    #[command(about = "Print the exact command 'masuk <profile>' would run. Example: 'masuk which foobar'")]
+    Which {
+        /// Profile name
+        profile: String,
+        #[command(flatten)]
+        options: ConnectOptions,
+    },
+    #[command(about = "Run a command on a profile and exit with its status. Example: 'masuk exec foobar -- df -h'")]
+    Exec {
+        /// Profile name
+        #[arg(required_unless_present = "tags")]
+        profile: Option<String>,
+        /// Allocate a tty, for interactive commands like top or sudo
+        #[arg(short = 't', long, conflicts_with = "tags")]
+        tty: bool,
+        /// Run on every profile with this tag, in parallel (repeatable)
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// With --tag, time between starting on one host and the next, e.g. 2s
+        #[arg(long, value_parser = parse_duration, requires = "tags")]
+        stagger: Option<u64>,
+        /// With --tag, delay each start by a random time up to this (default: the --stagger)
+        #[arg(long, value_parser = parse_duration, num_args = 0..=1, require_equals = true, requires = "tags")]
+        jitter: Option<Option<u64>>,
+        #[command(flatten)]
+        options: ConnectOptions,
+        /// Command to run
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    #[command(about = "Show the end of remote log files, merged across hosts with --tag. Example: 'masuk tail foobar /var/log/syslog -f'")]
+    Tail {
+        /// Follow the files as they grow, reconnecting when the connection drops
+        #[arg(short = 'f', long)]
+        follow: bool,
+        /// Number of lines to show from the end of each file
+        #[arg(short = 'n', long, default_value_t = 10)]
+        lines: u32,
+        /// Tail on every profile with this tag, in parallel (repeatable)
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        #[command(flatten)]
+        options: ConnectOptions,
+        /// Profile name (unless --tag is given), then the files to tail
+        #[arg(value_name = "[PROFILE] FILE", required = true)]
+        args: Vec<String>,
+    },
+    #[command(about = "Show or restart a systemd unit on one or more hosts. Example: 'masuk svc --tag web restart nginx'")]
+    Svc {
+        /// Run on every profile with this tag, in parallel (repeatable)
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Don't ask for confirmation when a policy wants it
+        #[arg(short = 'y', long)]
+        yes: bool,
+        /// Profile (unless --tag is given), then status or restart, then the unit
+        #[arg(value_name = "[PROFILE] ACTION UNIT", num_args = 2..=3, required = true)]
+        args: Vec<String>,
+    },
+    #[command(about = "Connect to a profile. 'masuk <profile>' is a shortcut for this")]
+    Connect {
+        /// Profile name, or a pattern like 'web-*' to connect to several
+        profile: String,
+        /// Connect to all matching profiles one after another
+        #[arg(long)]
+        sequential: bool,
+        /// Open all matching profiles side by side in tmux panes
+        #[arg(long, conflicts_with = "sequential")]
+        panes: bool,
+        #[command(flatten)]
+        options: ConnectOptions,
+    },
+}
+
+/// What `masuk svc` does with a unit.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SvcAction {
+    Status,
+    Restart,
+}
+
+/// One-off options for a single connection.
+#[derive(Args, Clone)]
+struct ConnectOptions {
+    /// Log in as this user instead of the profile's
+    #[arg(short = 'u', long)]
+    user: Option<String>,
+    /// Connect to this port instead of the profile's
+    #[arg(short = 'p', long)]
+    port: Option<u16>,
+    /// Connect to this host instead of the profile's, e.g. another node behind the same jump host
+    #[arg(long)]
+    host: Option<String>,
+    /// Use this backend for the session instead of the profile's
+    #[arg(long, value_enum, alias = "protocol")]
+    backend: Option<Backend>,
+    /// Use mosh for this session (same as --backend mosh)
+    #[arg(long, conflicts_with = "backend")]
+    mosh: bool,
+    /// Use a throwaway known_hosts file and disable agent forwarding, for untrusted one-off hosts
+    #[arg(long)]
+    sandbox: bool,
+    /// Forward the SSH agent for this connection (ssh -A)
+    #[arg(short = 'A', long, conflicts_with = "sandbox")]
+    forward_agent: bool,
+    /// Print a one-line summary with duration and exit status when the session ends
+    #[arg(long)]
+    summary: bool,
+    /// Enable X11 forwarding for this connection (ssh -X)
+    #[arg(short = 'X', long)]
+    x11: bool,
+    /// Enable trusted X11 forwarding for this connection (ssh -Y)
+    #[arg(short = 'Y', long)]
+    trusted_x11: bool,
+    /// Override compression for this connection (-C, or --compression=false)
+    #[arg(short = 'C', long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    compression: Option<bool>,
+    /// Connect over IPv4 only for this connection
+    #[arg(short = '4', conflicts_with = "ipv6")]
+    ipv4: bool,
+    /// Connect over IPv6 only for this connection
+    #[arg(short = '6')]
+    ipv6: bool,
+    /// Open a plain shell instead of running the profile's remote command
+    #[arg(long)]
+    no_command: bool,
+    /// Seconds to wait for the connection before giving up
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u32>,
+    /// Reconnect with exponential backoff when the connection drops (--retry=false to disable)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    retry: Option<bool>,
+    /// Let remote programs copy into the local clipboard for this session (--clipboard=false to disable)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    clipboard: Option<bool>,
+    /// Reconnect at most this many times (default 5, implies --retry)
+    #[arg(long, value_name = "N")]
+    max_retries: Option<u32>,
+    /// Delay before the first reconnect, doubled each time, e.g. 5s or 1m (implies --retry)
+    #[arg(long, value_parser = parse_duration)]
+    backoff: Option<u64>,
+    /// Verbose ssh output for debugging, repeat for more (-vv, -vvv). Also prints the full command line
+    #[arg(short = 'v', long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Skip the profile's preflight checks
+    #[arg(long)]
+    no_preflight: bool,
+    /// Wake the machine with Wake-on-LAN and wait for its SSH port first
+    #[arg(long)]
+    wake: bool,
+    /// Print the command line instead of running it
+    #[arg(long)]
+    dry_run: bool,
+    /// Copy the command line to the clipboard instead of running it
+    #[arg(long, conflicts_with = "dry_run")]
+    copy: bool,
+    /// Don't ask for confirmation when a policy wants it
+    #[arg(short = 'y', long)]
+    yes: bool,
+    /// Put in front of every line of output, for commands on several hosts
+    #[arg(skip)]
+    output_prefix: Option<String>,
+}
+
+impl ConnectOptions {
+    /// Applies the overrides to a resolved copy of the profile.
+    fn apply(&self, host_config: &mut HostConfig) {
+        if let Some(ref user) = self.user {
+            host_config.user = Some(user.clone());
+        }
+        if self.port.is_some() {
+            host_config.port = self.port;
+        }
+        if let Some(ref host) = self.host {
+            host_config.host = host.clone();
+        }
+        if self.backend.is_some() {
+            host_config.backend = self.backend;
+        }
+        if self.mosh {
+            host_config.backend = Some(Backend::Mosh);
+        }
+        if let Some(clipboard) = self.clipboard {
+            host_config.clipboard = clipboard;
+        }
+        if self.forward_agent {
+            host_config.forward_agent = true;
+        }
+        if self.x11 {
+            host_config.x11 = true;
+        }
+        if self.trusted_x11 {
+            host_config.trusted_x11 = true;
+        }
+        if self.compression.is_some() {
+            host_config.compression = self.compression;
+        }
+        if self.timeout.is_some() {
+            host_config.connect_timeout = self.timeout;
+        }
+        if self.max_retries.is_some() || self.backoff.is_some() {
+            host_config.retry = true;
+        }
+        if let Some(retry) = self.retry {
+            host_config.retry = retry;
+        }
+        if self.max_retries.is_some() {
+            host_config.max_retries = self.max_retries;
+        }
+        if self.backoff.is_some() {
+            host_config.retry_backoff = self.backoff;
+        }
+        if self.ipv4 {
+            host_config.address_family = Some(AddressFamily::Inet);
+        } else if self.ipv6 {
+            host_config.address_family = Some(AddressFamily::Inet6);
+        }
+        // A sandboxed connection never exposes the agent
+        if self.sandbox {
+            host_config.forward_agent = false;
+        }
+    }
+}
+
+/// Profile settings shared by `add` and `set`. Options that are not given
+/// leave the stored value untouched.
+#[derive(Args, Clone)]
+struct ProfileArgs {
+    /// SSH user (optional)
+    #[arg(short = 'u', long)]
+    user: Option<String>,
+    /// SSH port (optional, omit to use SSH default)
+    #[arg(short = 'p', long)]
+    port: Option<u16>,
+    /// SSH identity file (optional, passed to ssh with -i). Repeat to try several in order
+    #[arg(short = 'k', long = "key", visible_alias = "identity", visible_short_alias = 'i')]
+    keys: Vec<String>,
+    /// SSH certificate for the key, e.g. ~/.ssh/id_ed25519-cert.pub ("" to unset)
+    #[arg(long, value_name = "FILE")]
+    certificate: Option<String>,
+    /// known_hosts file for the host, e.g. one trusting a host CA with @cert-authority ("" to unset)
+    #[arg(long, value_name = "FILE")]
+    known_hosts: Option<String>,
+    /// Socket of the ssh agent to use instead of $SSH_AUTH_SOCK, or "none" ("" to unset)
+    #[arg(long, value_name = "SOCKET")]
+    identity_agent: Option<String>,
+    /// Remote directory for 'masuk push' and 'masuk pull' (default: the home directory, "" to unset)
+    #[arg(long, value_name = "PATH")]
+    remote_dir: Option<String>,
+    /// Local directory 'masuk pull' copies into (default: the current one, "" to unset)
+    #[arg(long, value_name = "PATH")]
+    local_dir: Option<String>,
+    /// Role of Vault's SSH secrets engine to get credentials from before connecting ("" to unset)
+    #[arg(long, value_name = "ROLE")]
+    vault_role: Option<String>,
+    /// Mount path of Vault's SSH secrets engine (default ssh, "" to unset)
+    #[arg(long, value_name = "PATH")]
+    vault_mount: Option<String>,
+    /// Whether Vault signs the key or hands out one-time passwords (default sign)
+    #[arg(long, value_enum)]
+    vault_mode: Option<VaultMode>,
+    /// Tag used for grouping and policies (repeatable, replaces existing tags)
+    #[arg(short = 't', long = "tag")]
+    tags: Vec<String>,
+    /// Jump host as [user@]host[:port] or the name of another profile
+    #[arg(short = 'J', long)]
+    jump: Option<String>,
+    /// Additional hop after the jump host, same format (repeatable, replaces existing hops)
+    #[arg(long)]
+    via: Vec<String>,
+    /// Other address of the host, tried in order when it doesn't answer (repeatable, replaces existing ones, "" to clear)
+    #[arg(long, value_name = "HOST")]
+    failover: Vec<String>,
+    /// Seconds each address gets to answer (default 2)
+    #[arg(long, value_parser = parse_duration)]
+    failover_timeout: Option<u64>,
+    /// Identical machine to spread connections over along with the host (repeatable, replaces existing ones, "" to clear)
+    #[arg(long, value_name = "HOST")]
+    pool: Vec<String>,
+    /// How connections pick a machine of the pool (default round-robin)
+    #[arg(long, value_enum)]
+    strategy: Option<PoolStrategy>,
+    /// Extra ssh arguments, split on whitespace (repeatable, replaces existing ones). Example: --extra-arg "-o ServerAliveInterval=30"
+    #[arg(long = "extra-arg", allow_hyphen_values = true)]
+    extra_args: Vec<String>,
+    /// Forward the SSH agent (ssh -A). Use --forward-agent=false to turn it off again
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    forward_agent: Option<bool>,
+    /// Enable X11 forwarding (ssh -X). Use --x11=false to turn it off again
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    x11: Option<bool>,
+    /// Use trusted X11 forwarding (ssh -Y) instead of -X
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    trusted_x11: Option<bool>,
+    /// Compress the connection (ssh -C). --compression=false forces it off
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    compression: Option<bool>,
+    /// Seconds between keep-alive messages (ssh ServerAliveInterval)
+    #[arg(long, value_name = "SECONDS")]
+    keepalive_interval: Option<u32>,
+    /// Unanswered keep-alives before giving up (ssh ServerAliveCountMax)
+    #[arg(long, value_name = "COUNT")]
+    keepalive_count: Option<u32>,
+    /// Run ssh as this local user (via sudo), for isolation on shared workstations
+    #[arg(long)]
+    local_user: Option<String>,
+    /// Run ssh in a transient systemd user scope (systemd-run --user --scope)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    systemd_scope: Option<bool>,
+    /// Share one master connection between sessions, kept for 10 minutes after the last one
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    multiplex: Option<bool>,
+    /// IP version to connect with: inet (IPv4), inet6 (IPv6) or any
+    #[arg(long, value_enum)]
+    address_family: Option<AddressFamily>,
+    /// Host key checking: strict, accept-new (learn new hosts) or off (ephemeral VMs)
+    #[arg(long, value_enum)]
+    host_key_checking: Option<HostKeyChecking>,
+    /// Client to run instead of ssh, e.g. "/opt/homebrew/bin/ssh" or "autossh -M 0"
+    #[arg(long)]
+    ssh_command: Option<String>,
+    /// Terminal emulator for 'masuk open', e.g. "wezterm start --" ("" to unset)
+    #[arg(long)]
+    terminal: Option<String>,
+    /// Seconds to wait for the connection before giving up (ssh ConnectTimeout)
+    #[arg(long, value_name = "SECS")]
+    connect_timeout: Option<u32>,
+    /// Reconnect with exponential backoff when the connection drops
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    retry: Option<bool>,
+    /// Reconnect at most this many times (default 5)
+    #[arg(long, value_name = "N")]
+    max_retries: Option<u32>,
+    /// Delay before the first reconnect, doubled each time, e.g. 5s (default)
+    #[arg(long, value_parser = parse_duration)]
+    retry_backoff: Option<u64>,
+    /// Risk level, applying the policy 'risk:<level>'
+    #[arg(long, value_enum)]
+    risk: Option<Risk>,
+    /// What carries the profile's sessions
+    #[arg(long, value_enum, alias = "protocol")]
+    backend: Option<Backend>,
+    /// UDP port or range mosh may use, e.g. 60000:60010 (for firewalls that only open those)
+    #[arg(long, value_name = "LO:HI", value_parser = parse_mosh_ports)]
+    mosh_ports: Option<String>,
+    /// Path of mosh-server on the host, e.g. /usr/local/bin/mosh-server
+    #[arg(long, value_name = "PATH")]
+    mosh_server: Option<String>,
+    /// Serial device for a serial console, e.g. /dev/ttyUSB0 (implies --backend serial)
+    #[arg(long, value_name = "PATH")]
+    device: Option<String>,
+    /// Line speed of the serial device, e.g. 115200
+    #[arg(long, value_name = "RATE")]
+    baud: Option<u32>,
+    /// Container to open a shell in, on --host over ssh or locally without one (implies --backend docker, unless it's a pod's)
+    #[arg(long, value_name = "NAME")]
+    container: Option<String>,
+    /// Pod to open a shell in with kubectl exec, e.g. api-7f9c or deploy/api (implies --backend kubernetes)
+    #[arg(long, value_name = "NAME")]
+    pod: Option<String>,
+    /// Label selector picking a running pod instead of --pod, e.g. app=api (implies --backend kubernetes)
+    #[arg(long, value_name = "LABELS")]
+    selector: Option<String>,
+    /// GCP instance for gcloud compute ssh (implies --backend gcloud), or the instance of an incus profile or machine of a vagrant one
+    #[arg(long, value_name = "NAME")]
+    instance: Option<String>,
+    /// Directory with the Vagrantfile to run vagrant ssh against (implies --backend vagrant)
+    #[arg(long, value_name = "DIR")]
+    vagrant_dir: Option<PathBuf>,
+    /// GCP project of the instance ("" to unset)
+    #[arg(long)]
+    project: Option<String>,
+    /// GCP zone of the instance, e.g. europe-west1-b ("" to unset)
+    #[arg(long)]
+    zone: Option<String>,
+    /// Tunnel through Identity-Aware Proxy, for instances without an external address
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    iap: Option<bool>,
+    /// EC2 instance to open an SSM session to, e.g. i-0abc123 (implies --backend aws-ssm)
+    #[arg(long, value_name = "ID")]
+    instance_id: Option<String>,
+    /// Named AWS CLI profile for the SSM session ("" to unset)
+    #[arg(long, value_name = "NAME")]
+    aws_profile: Option<String>,
+    /// AWS region of the instance ("" to unset)
+    #[arg(long)]
+    region: Option<String>,
+    /// kubectl context for the pod ("" to unset)
+    #[arg(long, value_name = "NAME")]
+    context: Option<String>,
+    /// Namespace of the pod ("" to unset)
+    #[arg(long, short = 'n', value_name = "NAME")]
+    namespace: Option<String>,
+    /// Open a SOCKS proxy on this local port while connected (ssh -D, 0 to unset)
+    #[arg(long, value_name = "PORT")]
+    socks_port: Option<u16>,
+    /// Connect through a SOCKS proxy: host:port, a local port, or a profile with --socks-port ("" to unset)
+    #[arg(long, value_name = "PROXY")]
+    socks_proxy: Option<String>,
+    /// Connect through this command, e.g. 'nc -X 5 -x 127.0.0.1:9050 %h %p' for Tor ("" to unset)
+    #[arg(long, value_name = "COMMAND")]
+    proxy_command: Option<String>,
+    /// Ports to knock on before connecting, e.g. 7000,8000,9000 (0 to unset)
+    #[arg(long, value_name = "PORTS", value_delimiter = ',')]
+    knock: Vec<u16>,
+    /// Knock with TCP connection attempts (default) or UDP packets
+    #[arg(long, value_enum, value_name = "PROTOCOL")]
+    knock_protocol: Option<knock::Protocol>,
+    /// Milliseconds to wait after every knock (default 100)
+    #[arg(long, value_name = "MS")]
+    knock_delay: Option<u64>,
+    /// MAC address for Wake-on-LAN, e.g. aa:bb:cc:dd:ee:ff ("" to unset)
+    #[arg(long, value_name = "MAC", value_parser = parse_mac)]
+    wol_mac: Option<String>,
+    /// Broadcast address for the magic packet, optionally with a port (default 255.255.255.255:9, "" to unset)
+    #[arg(long, value_name = "ADDRESS")]
+    wol_broadcast: Option<String>,
+    /// Let remote programs copy into the local clipboard by sending text to a forwarded port
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    clipboard: Option<bool>,
+    /// Port on the host for the clipboard relay (default 2224)
+    #[arg(long, value_name = "PORT")]
+    clipboard_port: Option<u16>,
+    /// Named local forwarding for 'masuk tunnel <profile> <name>', NAME=[BIND:]PORT:HOST:HOSTPORT (repeatable, replaces existing ones, "" to clear)
+    #[arg(long = "tunnel", value_name = "NAME=SPEC", value_parser = parse_named_tunnel)]
+    tunnels: Vec<(String, String)>,
+    /// Remote forwarding 'masuk tunnel' opens by default, [BIND:]PORT:HOST:HOSTPORT or PORT (repeatable, replaces existing ones, "" to clear)
+    #[arg(long = "remote-forward", value_name = "SPEC", value_parser = parse_saved_forward)]
+    remote_forwards: Vec<String>,
+    /// Command the host should have, warned about on connect when missing (repeatable, replaces existing ones)
+    #[arg(long = "require", value_name = "COMMAND")]
+    requires: Vec<String>,
+    /// Warn on connect when less than this percentage of / is free
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(0..=100))]
+    min_disk_free: Option<u8>,
+    /// Command to run on connect instead of the login shell, e.g. "tmux new -A -s main" ("" to unset)
+    #[arg(long)]
+    remote_command: Option<String>,
+    /// Secret reference to the login password, for hosts that only take passwords, e.g. keyring://masuk/router1 ("" to unset)
+    #[arg(long, value_name = "REFERENCE", value_parser = parse_secret_reference)]
+    password: Option<String>,
+    /// Secret reference to the passphrase of the key, e.g. bw://ssh-work#passphrase ("" to unset)
+    #[arg(long, value_name = "REFERENCE", value_parser = parse_secret_reference)]
+    key_passphrase: Option<String>,
+    /// Secret reference to the TOTP secret for authenticator code prompts, e.g. keyring://masuk-totp/bastion ("" to unset)
+    #[arg(long, value_name = "REFERENCE", value_parser = parse_secret_reference)]
+    totp: Option<String>,
+    /// Secret reference to the sudo password fed to 'sudo -S' by 'masuk exec', e.g. keyring://masuk-sudo/web1 ("" to unset)
+    #[arg(long, value_name = "REFERENCE", value_parser = parse_secret_reference)]
+    sudo_password: Option<String>,
+    /// Local shell command to run before connecting; failing cancels the connection ("" to unset)
+    #[arg(long)]
+    pre_connect: Option<String>,
+    /// Local shell command to run after the session ends ("" to unset)
+    #[arg(long)]
+    post_connect: Option<String>,
+    /// Environment variable sent to the host, NAME=VALUE (repeatable, replaces existing ones)
+    #[arg(short = 'e', long = "env", value_parser = parse_env_var)]
+    env: Vec<(String, String)>,
+}
+
+impl ProfileArgs {
+    fn apply(self, host_config: &mut HostConfig) {
+        if self.user.is_some() {
+            host_config.user = self.user;
+        }
+        if self.port.is_some() {
+            host_config.port = self.port;
+        }
+        if !self.keys.is_empty() {
+            host_config.keys = self.keys.into_iter().filter(|k| !k.is_empty()).collect();
+        }
+        if let Some(certificate) = self.certificate {
+            host_config.certificate_file = Some(certificate).filter(|c| !c.is_empty());
+        }
+        if let Some(known_hosts) = self.known_hosts {
+            host_config.known_hosts_file = Some(known_hosts).filter(|k| !k.is_empty());
+        }
+        if let Some(agent) = self.identity_agent {
+            host_config.identity_agent = Some(agent).filter(|a| !a.is_empty());
+        }
+        if let Some(dir) = self.remote_dir {
+            host_config.remote_dir = Some(dir).filter(|d| !d.is_empty());
+        }
+        if let Some(dir) = self.local_dir {
+            host_config.local_dir = Some(dir).filter(|d| !d.is_empty());
+        }
+        if let Some(role) = self.vault_role {
+            host_config.vault_role = Some(role).filter(|r| !r.is_empty());
+        }
+        if let Some(mount) = self.vault_mount {
+            host_config.vault_mount = Some(mount).filter(|m| !m.is_empty());
+        }
+        if self.vault_mode.is_some() {
+            host_config.vault_mode = self.vault_mode;
+        }
+        if !self.tags.is_empty() {
+            host_config.tags = self.tags;
+        }
+        if self.jump.is_some() {
+            host_config.jump = self.jump;
+        }
+        if !self.via.is_empty() {
+            host_config.via = self.via;
+        }
+        if !self.failover.is_empty() {
+            host_config.failover = self.failover.into_iter().filter(|host| !host.is_empty()).collect();
+        }
+        if self.failover_timeout.is_some() {
+            host_config.failover_timeout = self.failover_timeout;
+        }
+        if !self.pool.is_empty() {
+            host_config.pool = self.pool.into_iter().filter(|host| !host.is_empty()).collect();
+        }
+        if self.strategy.is_some() {
+            host_config.strategy = self.strategy;
+        }
+        if let Some(forward_agent) = self.forward_agent {
+            host_config.forward_agent = forward_agent;
+        }
+        if let Some(x11) = self.x11 {
+            host_config.x11 = x11;
+        }
+        if let Some(trusted_x11) = self.trusted_x11 {
+            host_config.trusted_x11 = trusted_x11;
+        }
+        if self.compression.is_some() {
+            host_config.compression = self.compression;
+        }
+        if self.keepalive_interval.is_some() {
+            host_config.keepalive_interval = self.keepalive_interval;
+        }
+        if self.keepalive_count.is_some() {
+            host_config.keepalive_count = self.keepalive_count;
+        }
+        if self.local_user.is_some() {
+            host_config.local_user = self.local_user;
+        }
+        if let Some(systemd_scope) = self.systemd_scope {
+            host_config.systemd_scope = systemd_scope;
+        }
+        if let Some(multiplex) = self.multiplex {
+            host_config.multiplex = multiplex;
+        }
+        if self.address_family.is_some() {
+            host_config.address_family = self.address_family;
+        }
+        if self.host_key_checking.is_some() {
+            host_config.host_key_checking = self.host_key_checking;
+        }
+        if self.ssh_command.is_some() {
+            host_config.ssh_command = self.ssh_command;
+        }
+        if let Some(terminal) = self.terminal {
+            host_config.terminal = Some(terminal).filter(|t| !t.is_empty());
+        }
+        if self.connect_timeout.is_some() {
+            host_config.connect_timeout = self.connect_timeout;
+        }
+        if let Some(retry) = self.retry {
+            host_config.retry = retry;
+        }
+        if self.max_retries.is_some() {
+            host_config.max_retries = self.max_retries;
+        }
+        if self.retry_backoff.is_some() {
+            host_config.retry_backoff = self.retry_backoff;
+        }
+        if self.risk.is_some() {
+            host_config.risk = self.risk;
+        }
+        if self.backend.is_some() {
+            host_config.backend = self.backend;
+        }
+        if self.mosh_ports.is_some() {
+            host_config.mosh_ports = self.mosh_ports;
+        }
+        if self.mosh_server.is_some() {
+            host_config.mosh_server = self.mosh_server;
+        }
+        if self.device.is_some() {
+            host_config.device = self.device;
+            if self.backend.is_none() {
+                host_config.backend = Some(Backend::Serial);
+            }
+        }
+        if self.baud.is_some() {
+            host_config.baud = self.baud;
+        }
+        if let Some(port) = self.socks_port {
+            host_config.socks_port = Some(port).filter(|p| *p != 0);
+        }
+        if let Some(proxy) = self.socks_proxy {
+            host_config.socks_proxy = Some(proxy).filter(|p| !p.is_empty());
+        }
+        if let Some(proxy_command) = self.proxy_command {
+            host_config.proxy_command = Some(proxy_command).filter(|c| !c.is_empty());
+        }
+        if !self.knock.is_empty() {
+            host_config.knock = self.knock.into_iter().filter(|p| *p != 0).collect();
+        }
+        if self.knock_protocol.is_some() {
+            host_config.knock_protocol = self.knock_protocol;
+        }
+        if self.knock_delay.is_some() {
+            host_config.knock_delay = self.knock_delay;
+        }
+        if let Some(mac) = self.wol_mac {
+            host_config.wol_mac = Some(mac).filter(|m| !m.is_empty());
+        }
+        if let Some(broadcast) = self.wol_broadcast {
+            host_config.wol_broadcast = Some(broadcast).filter(|b| !b.is_empty());
+        }
+        if let Some(clipboard) = self.clipboard {
+            host_config.clipboard = clipboard;
+        }
+        if self.clipboard_port.is_some() {
+            host_config.clipboard_port = self.clipboard_port;
+        }
+        if self.container.is_some() {
+            host_config.container = self.container;
+            let pod = self.pod.is_some() || self.selector.is_some() || host_config.backend == Some(Backend::Kubernetes);
+            if self.backend.is_none() && !pod {
+                host_config.backend = Some(Backend::Docker);
+            }
+        }
+        if (self.pod.is_some() || self.selector.is_some()) && self.backend.is_none() {
+            host_config.backend = Some(Backend::Kubernetes);
+        }
+        if self.pod.is_some() {
+            host_config.pod = self.pod;
+        }
+        if self.selector.is_some() {
+            host_config.selector = self.selector;
+        }
+        if let Some(dir) = self.vagrant_dir {
+            // VAGRANT_CWD is read relative to wherever masuk runs later
+            host_config.vagrant_dir = Some(fs::canonicalize(&dir).unwrap_or(dir));
+            if self.backend.is_none() {
+                host_config.backend = Some(Backend::Vagrant);
+            }
+        }
+        if self.instance.is_some() {
+            host_config.instance = self.instance;
+            let named = matches!(host_config.backend, Some(Backend::Incus | Backend::Vagrant));
+            if self.backend.is_none() && !named {
+                host_config.backend = Some(Backend::Gcloud);
+            }
+        }
+        if let Some(project) = self.project {
+            host_config.project = Some(project).filter(|p| !p.is_empty());
+        }
+        if let Some(zone) = self.zone {
+            host_config.zone = Some(zone).filter(|z| !z.is_empty());
+        }
+        if let Some(iap) = self.iap {
+            host_config.iap = iap;
+        }
+        if self.instance_id.is_some() {
+            host_config.instance_id = self.instance_id;
+            if self.backend.is_none() {
+                host_config.backend = Some(Backend::AwsSsm);
+            }
+        }
+        if let Some(aws_profile) = self.aws_profile {
+            host_config.aws_profile = Some(aws_profile).filter(|p| !p.is_empty());
+        }
+        if let Some(region) = self.region {
+            host_config.region = Some(region).filter(|r| !r.is_empty());
+        }
+        if let Some(context) = self.context {
+            host_config.context = Some(context).filter(|c| !c.is_empty());
+        }
+        if let Some(namespace) = self.namespace {
+            host_config.namespace = Some(namespace).filter(|n| !n.is_empty());
+        }
+        if !self.tunnels.is_empty() {
+            host_config.tunnels = self.tunnels.into_iter().filter(|(name, _)| !name.is_empty()).collect();
+        }
+        if !self.remote_forwards.is_empty() {
+            host_config.remote_forwards = self.remote_forwards.into_iter().filter(|spec| !spec.is_empty()).collect();
+        }
+        if !self.requires.is_empty() {
+            host_config.requires = self.requires;
+        }
+        if self.min_disk_free.is_some() {
+            host_config.min_disk_free = self.min_disk_free;
+        }
+        if !self.env.is_empty() {
+            host_config.env = self.env.into_iter().collect();
+        }
+        if let Some(password) = self.password {
+            host_config.password = Some(password).filter(|r| !r.is_empty());
+        }
+        if let Some(passphrase) = self.key_passphrase {
+            host_config.key_passphrase = Some(passphrase).filter(|r| !r.is_empty());
+        }
+        if let Some(totp) = self.totp {
+            host_config.totp = Some(totp).filter(|r| !r.is_empty());
+        }
+        if let Some(sudo_password) = self.sudo_password {
+            host_config.sudo_password = Some(sudo_password).filter(|r| !r.is_empty());
+        }
+        if let Some(pre_connect) = self.pre_connect {
+            host_config.pre_connect = Some(pre_connect).filter(|c| !c.is_empty());
+        }
+        if let Some(post_connect) = self.post_connect {
+            host_config.post_connect = Some(post_connect).filter(|c| !c.is_empty());
+        }
+        if let Some(remote_command) = self.remote_command {
+            host_config.remote_command = Some(remote_command).filter(|c| !c.is_empty());
+        }
+        if !self.extra_args.is_empty() {
+            host_config.extra_args = self
+                .extra_args
+                .iter()
+                .flat_map(|arg| arg.split_whitespace())
+                .map(String::from)
+                .collect();
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum SecretAction {
+    #[command(about = "Ask for the login password of a profile and store it. Example: 'masuk secret set router1'")]
+    Set {
+        /// Profile name
+        profile: String,
+        /// Store the sudo password instead, like 'masuk sudo-password'
+        #[arg(long)]
+        sudo: bool,
+        /// Store the passphrase of the profile's key instead
+        #[arg(long, conflicts_with = "sudo")]
+        passphrase: bool,
+        /// Store the TOTP secret (base32 or otpauth:// URI) instead
+        #[arg(long, conflicts_with_all = ["sudo", "passphrase"])]
+        totp: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ExportFormat {
+    #[command(about = "Print profiles as JSON, as read by 'masuk import json'. Example: 'masuk export json -t prod > prod.json'")]
+    Json {
+        /// Profiles to export, all of them when none are given
+        profiles: Vec<String>,
+        /// Export every profile with this tag (repeatable)
+        #[arg(short = 't', long = "tag")]
+        tags: Vec<String>,
+    },
+    #[command(about = "Print profiles as ssh_config Host blocks for ssh, scp, git and other tools. Example: 'masuk export ssh-config --output ~/.ssh/config.d/masuk'")]
+    SshConfig {
+        /// Profiles to export, all of them when none are given
+        profiles: Vec<String>,
+        /// Export every profile with this tag (repeatable)
+        #[arg(short = 't', long = "tag")]
+        tags: Vec<String>,
+        /// Write the Host blocks to this file instead of printing them
+        #[arg(short = 'o', long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ImportSource {
+    #[command(about = "Create a profile for every A/AAAA record of a zone (via AXFR). Example: 'masuk import dns-zone example.com --filter \"web*\"'")]
+    DnsZone {
+        /// Zone to transfer
+        zone: String,
+        /// Name server to ask, defaults to the system resolver
+        #[arg(long)]
+        server: Option<String>,
+        #[command(flatten)]
+        options: ImportOptions,
+    },
+    #[command(about = "Import profiles from JSON, '-' reads stdin. Example: 'terraform output -json | jq ... | masuk import json -'")]
+    Json {
+        /// File to read, or - for stdin
+        file: String,
+        #[command(flatten)]
+        options: ImportOptions,
+    },
+    #[command(about = "Import hosts from Terraform state or outputs, tagged with the workspace. Example: 'masuk import terraform --map host=private_ip'")]
+    Terraform {
+        /// State file or saved 'terraform output -json', - for stdin. Defaults to running 'terraform output -json' here
+        file: Option<String>,
+        /// Attribute path for a profile field, e.g. name=tags.Name or host=private_ip (repeatable)
+        #[arg(long = "map")]
+        mappings: Vec<String>,
+        /// Workspace to tag the profiles with, detected when not given
+        #[arg(long)]
+        workspace: Option<String>,
+        #[command(flatten)]
+        options: ImportOptions,
+    },
+    #[command(about = "Create a profile for every Host entry of an ssh_config. Example: 'masuk import ssh-config --prefix work-'")]
+    SshConfig {
+        /// File to read, or - for stdin (default: ~/.ssh/config)
+        file: Option<String>,
+        /// Put this in front of every profile name, e.g. work-
+        #[arg(long, default_value = "")]
+        prefix: String,
+        #[command(flatten)]
+        options: ImportOptions,
+    },
+    #[command(about = "Import profiles from CSV with columns name,host[,user,port,key,tags], '-' reads stdin")]
+    Csv {
+        /// File to read, or - for stdin
+        file: String,
+        #[command(flatten)]
+        options: ImportOptions,
+    },
+}
+
+#[derive(Args)]
+struct TunnelOptions {
+    /// Names of tunnels saved in the profile with --tunnel
+    #[arg(value_name = "TUNNEL")]
+    names: Vec<String>,
+    /// Local port forwarded to the remote side, [BIND:]PORT:HOST:HOSTPORT or just PORT (repeatable)
+    #[arg(short = 'L', long = "local", value_name = "SPEC", value_parser = parse_forward)]
+    local: Vec<String>,
+    /// Remote port forwarded to this side, [BIND:]PORT:HOST:HOSTPORT or just PORT (repeatable, default: the profile's remote forwards)
+    #[arg(short = 'R', long = "remote", value_name = "SPEC", value_parser = parse_forward)]
+    remote: Vec<String>,
+    /// Local port of a SOCKS proxy reaching everything the remote side can (repeatable)
+    #[arg(short = 'D', long = "dynamic", value_name = "PORT")]
+    dynamic: Vec<u16>,
+    /// Let other machines connect to the local forwards (ssh GatewayPorts)
+    #[arg(long)]
+    gateway_ports: bool,
+    /// Keep the tunnel open in the background
+    #[arg(short = 'b', long)]
+    background: bool,
+    /// Reopen the tunnel when the connection drops, waiting longer after each failed attempt
+    #[arg(long)]
+    persist: bool,
+    /// Show a desktop notification when a persistent tunnel goes down and comes back
+    #[arg(long, requires = "persist")]
+    notify: bool,
+    /// Run the tunnel as a service that starts at login (systemd user unit, launchd agent on macOS)
+    #[arg(long, conflicts_with = "background")]
+    install_service: bool,
+    /// Remove the service installed with --install-service for the same profile and tunnels
+    #[arg(long, conflicts_with_all = ["background", "install_service"])]
+    remove_service: bool,
+    /// Print the ssh command instead of running it
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct BootstrapOptions {
+    /// Script to run on the host (with sh, as the profile's user)
+    #[arg(long)]
+    script: Option<PathBuf>,
+    /// Install your public key with ssh-copy-id first
+    #[arg(long)]
+    copy_id: bool,
+    /// Seconds to wait for the host to accept connections
+    #[arg(long, default_value_t = 300)]
+    wait: u64,
+    /// Tag added once the host is set up
+    #[arg(long, default_value = "provisioned")]
+    tag: String,
+}
+
+/// Options shared by all import sources.
+#[derive(Args)]
+struct ImportOptions {
+    /// Only import profiles whose name matches this glob
+    #[arg(long)]
+    filter: Option<String>,
+    /// Replace profiles that already exist
+    #[arg(long)]
+    overwrite: bool,
+    /// Settings applied to every imported profile
+    #[command(flatten)]
+    settings: ProfileArgs,
+}
+
+#[derive(Subcommand)]
+enum HostkeyAction {
+    #[command(about = "Replace the known host key after a planned reinstall. Example: 'masuk hostkey rollover foobar'")]
+    Rollover {
+        /// Profile name
+        profile: String,
+        /// Trust the new key without asking
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum EnvAction {
+    #[command(about = "Print MASUK_CONTEXT and MASUK_CONFIG exports for a shell or direnv")]
+    Export {
+        /// Context with its own profiles in ~/.config/masuk/contexts/<CONTEXT> (default: the current one)
+        #[arg(conflicts_with = "config")]
+        context: Option<String>,
+        /// Use this config file instead, e.g. one kept in the project
+        #[arg(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    #[command(about = "Show all settings")]
+    Show,
+    #[command(about = "Change a setting")]
+    Set {
+        setting: Setting,
+        /// New value
+        #[arg(required = true, num_args = 1.., allow_hyphen_values = true)]
+        value: Vec<String>,
+    },
+    #[command(about = "Reset a setting to its default")]
+    Unset { setting: Setting },
+}
+
+#[derive(Subcommand)]
+enum SessionAction {
+    #[command(about = "Open a connection reused by later masuk commands and print its control socket")]
+    Open {
+        /// Profile name
+        profile: String,
+        /// Close the session after it has been idle this long (ssh ControlPersist), default is never
+        #[arg(long, default_value = "yes")]
+        persist: String,
+    },
+    #[command(about = "Close a session")]
+    Close {
+        /// Profile name
+        profile: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum MuxAction {
+    #[command(about = "List profiles with a running master connection")]
+    #[command(alias = "ls")]
+    List,
+    #[command(about = "Close master connections. Example: 'masuk mux stop db1'")]
+    Stop {
+        /// Profiles whose master connection to close
+        #[arg(required_unless_present = "all")]
+        profiles: Vec<String>,
+        /// Close every master connection
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum TunnelAction {
+    #[command(about = "List tunnels running in the background")]
+    #[command(alias = "ls")]
+    List,
+    #[command(about = "Reopen background tunnels that aren't running anymore, e.g. after a restart. Example: 'masuk tunnel restore'")]
+    Restore {
+        /// Ids or profiles of the tunnels to reopen (default: all that are down)
+        targets: Vec<String>,
+    },
+    #[command(about = "Stop background tunnels. Example: 'masuk tunnel stop 2' or 'masuk tunnel stop db1'")]
+    Stop {
+        /// Ids or profiles of the tunnels to stop
+        #[arg(required_unless_present = "all")]
+        targets: Vec<String>,
+        /// Stop every background tunnel
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum HostsAction {
+    #[command(about = "Add or update the entry for a profile. Example: 'masuk hosts add web1'")]
     Add {
         /// Profile name
         profile: String,
-        /// Host/IP address
-        #[arg(short = 'h', long)]
-        host: String,
-        /// SSH user (optional)
-        #[arg(short = 'u', long)]
-        user: Option<String>,
-        /// SSH port (optional, omit to use SSH default)
-        #[arg(short = 'p', long)]
-        port: Option<u16>,
-        /// SSH key path (optional, will be used with -i flag)
-        #[arg(short = 'k', long)]
-        key: Option<String>,
+        /// Name to map instead of the profile name
+        #[arg(long)]
+        name: Option<String>,
     },
-    #[command(about = "List all configured profiles")]
+    #[command(about = "Re-resolve all entries and update changed addresses")]
+    Sync,
+    #[command(about = "List entries managed by masuk")]
     #[command(alias = "ls")]
     List,
-    #[command(about = "Remove a profile. Example: 'masuk remove foobar'")]
+    #[command(about = "Remove the entries for a profile")]
     #[command(alias = "rm")]
     Remove {
         /// Profile name
         profile: String,
     },
-    #[command(external_subcommand)]
-    External(Vec<String>),
+}
+
+#[derive(Subcommand)]
+enum PolicyAction {
+    #[command(about = "Create or update the policy for a tag. Example: 'masuk policy set prod --record asciinema'")]
+    Set {
+        /// Tag the policy applies to, 'risk:<level>' for profiles of that risk, or '*' for every profile
+        tag: String,
+        #[command(flatten)]
+        settings: PolicyArgs,
+    },
+    #[command(about = "List all configured policies")]
+    #[command(alias = "ls")]
+    List,
+    #[command(about = "Remove the policy for a tag")]
+    #[command(alias = "rm")]
+    Remove {
+        /// Tag the policy applies to
+        tag: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DefaultsAction {
+    #[command(about = "Change defaults. Example: 'masuk defaults set --tag prod -J bastion -u deploy'")]
+    Set {
+        /// Only for profiles with this tag (default: all profiles)
+        #[arg(long)]
+        tag: Option<String>,
+        #[command(flatten)]
+        settings: DefaultsArgs,
+    },
+    #[command(about = "Show the global and per-tag defaults")]
+    #[command(alias = "ls")]
+    Show,
+    #[command(about = "Remove all defaults, globally or of a tag")]
+    Clear {
+        /// Only the defaults of this tag
+        #[arg(long)]
+        tag: Option<String>,
+    },
+}
+
+/// Settings of `masuk defaults set`.
+#[derive(Args)]
+struct DefaultsArgs {
+    /// SSH user ("" to unset)
+    #[arg(short = 'u', long)]
+    user: Option<String>,
+    /// SSH port (0 to unset)
+    #[arg(short = 'p', long)]
+    port: Option<u16>,
+    /// SSH identity file, repeat for several ("" to unset)
+    #[arg(short = 'k', long = "key", visible_alias = "identity", visible_short_alias = 'i')]
+    keys: Vec<String>,
+    /// Socket of the ssh agent to use, or "none" ("" to unset)
+    #[arg(long, value_name = "SOCKET")]
+    identity_agent: Option<String>,
+    /// Jump host as [user@]host[:port] or the name of a profile ("" to unset)
+    #[arg(short = 'J', long)]
+    jump: Option<String>,
+    /// Seconds to wait for the connection (0 to unset)
+    #[arg(long, value_name = "SECS")]
+    connect_timeout: Option<u32>,
+    /// Seconds between keep-alive messages (0 to unset)
+    #[arg(long, value_name = "SECONDS")]
+    keepalive_interval: Option<u32>,
+    /// Unanswered keep-alives before giving up (0 to unset)
+    #[arg(long, value_name = "COUNT")]
+    keepalive_count: Option<u32>,
+    /// Compress connections (--compression=false forces it off)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    compression: Option<bool>,
+    #[arg(long, value_enum)]
+    address_family: Option<AddressFamily>,
+    #[arg(long, value_enum)]
+    host_key_checking: Option<HostKeyChecking>,
+}
+
+#[derive(Subcommand)]
+enum SnapshotAction {
+    #[command(about = "Save all profiles, tags and pinned host keys as they are now")]
+    Create {
+        /// Snapshot name (default: today's date)
+        name: Option<String>,
+    },
+    #[command(about = "List saved snapshots")]
+    #[command(alias = "ls")]
+    List,
+    #[command(about = "Report profiles added, removed or changed since a snapshot. Example: 'masuk snapshot compare 2026-07-01 --markdown'")]
+    Compare {
+        /// Snapshot to compare from
+        from: String,
+        /// Snapshot to compare to (default: the current inventory)
+        to: Option<String>,
+        /// Print a Markdown report
+        #[arg(long)]
+        markdown: bool,
+    },
+}
+
+/// Rules of a policy set with `masuk policy set`.
+#[derive(Args)]
+struct PolicyArgs {
+    /// Force session recording with the given tool (no opt-out)
+    #[arg(long)]
+    record: Option<Recorder>,
+    /// Directory for session logs (default: ~/.config/masuk/sessions)
+    #[arg(long)]
+    log_dir: Option<PathBuf>,
+    /// URL that receives a JSON POST on connect and disconnect
+    #[arg(long)]
+    webhook: Option<String>,
+    /// Ask before connecting or running commands (--confirm=false to stop asking)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    confirm: Option<bool>,
+    /// Refuse commands on several profiles at once, like 'exec --tag'
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    no_fleet: Option<bool>,
+}
+
+/// Whether `name` is a subcommand (or alias) rather than a profile name.
+fn is_subcommand(name: &str) -> bool {
+    name == "help"
+        || Cli::command()
+            .get_subcommands()
+            .any(|c| c.get_name() == name || c.get_all_aliases().any(|alias| alias == name))
 }
 
 fn main() -> Result<()> {
-    // Check if we have args and if the first arg might be a profile name
-    let args: Vec<String> = env::args().collect();
+    interrupt::install();
+
+    let result = run();
+    if let Err(ref e) = result {
+        if e.is::<interrupt::Interrupted>() {
+            eprintln!("Interrupted");
+            std::process::exit(130);
+        }
+        if let Some(ChildExit(code)) = e.downcast_ref() {
+            std::process::exit(*code);
+        }
+    }
+    result
+}
+
+fn run() -> Result<()> {
+    let mut args: Vec<String> = env::args().collect();
 
-    // If we have exactly 2 args (program name + one arg) and it doesn't match known commands,
-    // treat it as a direct connection
-    if args.len() == 2 {
-        let potential_profile = &args[1];
-        let known_commands = ["add", "list", "ls", "remove", "rm", "help", "--help", "-h"];
+    // Started by ssh as SSH_ASKPASS, with the prompt as the only argument
+    if let [_, prompt] = args.as_slice() {
+        let asking = ["MASUK_ASKPASS", "MASUK_ASKPASS_PASSPHRASE", "MASUK_ASKPASS_TOTP"]
+            .iter()
+            .any(|name| env::var_os(name).is_some());
+        if asking && !prompt.starts_with('-') && !is_subcommand(prompt) {
+            return askpass(prompt);
+        }
+    }
 
-        if !known_commands.contains(&potential_profile.as_str()) {
-            let masuk = Masuk::new()?;
-            return masuk.connect(potential_profile);
+    // `masuk <profile> [options]` is shorthand for `masuk connect <profile> [options]`
+    if let Some(first) = args.get(1) {
+        if !first.starts_with('-') && !is_subcommand(first) {
+            args.insert(1, "connect".to_string());
         }
     }
 
-    let cli = Cli::parse();
+    let raw_args = args.clone();
+    let cli = Cli::parse_from(args);
+    if cli.json {
+        render::init(Box::new(render::Json));
+    } else if cli.quiet {
+        render::init(Box::new(render::Quiet));
+    }
+    if cli.ascii || env::var("TERM").is_ok_and(|term| term == "dumb") {
+        render::use_ascii();
+    }
     let mut masuk = Masuk::new()?;
 
     match cli.command {
-        Commands::Add { profile, host, user, port, key } => {
-            masuk.add(&profile, &host, user, port, key)?;
+        Commands::Add { profile, host, settings, .. } => {
+            masuk.add(&profile, host.as_deref().unwrap_or_default(), settings)?;
         }
-        Commands::List => {
-            masuk.list()?;
+        Commands::Set { profile, host, settings, .. } => {
+            masuk.set(&profile, host, settings)?;
+        }
+        Commands::List { group_by } => {
+            masuk.list(group_by)?;
+        }
+        Commands::Show { profile, resolved } => {
+            masuk.show(&profile, resolved)?;
         }
         Commands::Remove { profile } => {
             masuk.remove(&profile)?;
         }
-        Commands::External(args) => {
-            if let Some(profile) = args.first() {
-                masuk.connect(profile)?;
+        Commands::Warm { profiles, tags, persist } => {
+            masuk.warm(&profiles, &tags, &persist)?;
+        }
+        Commands::Session { action } => match action {
+            SessionAction::Open { profile, persist } => {
+                masuk.session_open(&profile, &persist)?;
+            }
+            SessionAction::Close { profile } => {
+                masuk.session_close(&profile)?;
+            }
+        },
+        Commands::Mux { action } => match action {
+            MuxAction::List => {
+                masuk.mux_list()?;
+            }
+            MuxAction::Stop { profiles, all } => {
+                masuk.mux_stop(&profiles, all)?;
+            }
+        },
+        Commands::Hosts { action } => match action {
+            HostsAction::Add { profile, name } => {
+                masuk.hosts_add(&profile, name)?;
+            }
+            HostsAction::Sync => {
+                masuk.hosts_sync()?;
+            }
+            HostsAction::List => {
+                masuk.hosts_list()?;
+            }
+            HostsAction::Remove { profile } => {
+                masuk.hosts_remove(&profile)?;
+            }
+        },
+        Commands::Policy { action } => match action {
+            PolicyAction::Set { tag, settings } => {
+                masuk.set_policy(&tag, settings)?;
+            }
+            PolicyAction::List => {
+                masuk.list_policies()?;
+            }
+            PolicyAction::Remove { tag } => {
+                masuk.remove_policy(&tag)?;
+            }
+        },
+        Commands::Defaults { action } => match action {
+            DefaultsAction::Set { tag, settings } => {
+                masuk.set_defaults(tag, settings)?;
+            }
+            DefaultsAction::Show => {
+                masuk.show_defaults()?;
+            }
+            DefaultsAction::Clear { tag } => {
+                masuk.clear_defaults(tag.as_deref())?;
+            }
+        },
+        Commands::Snapshot { action } => match action {
+            SnapshotAction::Create { name } => {
+                masuk.snapshot_create(name)?;
+            }
+            SnapshotAction::List => {
+                masuk.snapshot_list()?;
+            }
+            SnapshotAction::Compare { from, to, markdown } => {
+                masuk.snapshot_compare(&from, to.as_deref(), markdown)?;
+            }
+        },
+        Commands::Open { profile, args } => {
+            masuk.open(&profile, &args)?;
+        }
+        Commands::Url { profile, path, scheme } => {
+            masuk.url(&profile, path.as_deref(), scheme)?;
+        }
+        Commands::Cp {
+            recursive,
+            limit,
+            dry_run,
+            paths,
+        } => {
+            masuk.cp(&paths, recursive, limit, dry_run)?;
+        }
+        Commands::Push {
+            tags,
+            mut args,
+            recursive,
+            limit,
+            dry_run,
+        } => {
+            let profiles = if tags.is_empty() {
+                vec![args.remove(0)]
+            } else {
+                masuk.select_profiles(&[], &tags)?
+            };
+            if args.is_empty() {
+                return Err(anyhow!("Name the files to copy"));
+            }
+            masuk.push(&profiles, &args, recursive, limit, dry_run)?;
+        }
+        Commands::Pull {
+            tags,
+            mut args,
+            recursive,
+            limit,
+            dry_run,
+        } => {
+            let profiles = if tags.is_empty() {
+                vec![args.remove(0)]
+            } else {
+                masuk.select_profiles(&[], &tags)?
+            };
+            if args.is_empty() {
+                return Err(anyhow!("Name the files to copy"));
+            }
+            masuk.pull(&profiles, &args, recursive, limit, dry_run)?;
+        }
+        Commands::Rsync {
+            limit,
+            progress,
+            dry_run,
+            paths,
+            args,
+        } => {
+            masuk.rsync(&paths, &args, limit, progress, dry_run)?;
+        }
+        Commands::Mount {
+            profile,
+            remote,
+            mountpoint,
+            list,
+            dry_run,
+        } => match profile {
+            Some(profile) if !list => masuk.mount(&profile, remote.as_deref(), mountpoint, dry_run)?,
+            _ => masuk.list_mounts()?,
+        },
+        Commands::Umount { profile } => {
+            masuk.umount(&profile)?;
+        }
+        Commands::Sftp { profile, dry_run } => {
+            masuk.sftp(&profile, dry_run)?;
+        }
+        Commands::EditRemote { profile, path, sudo, yes } => {
+            masuk.edit_remote(&profile, &path, sudo, yes)?;
+        }
+        Commands::Forward { profile, auto, interval } => {
+            masuk.forward_auto(&profile, auto, interval)?;
+        }
+        Commands::Tunnel { action, profile, options } => match (action, profile) {
+            (Some(TunnelAction::List), _) => masuk.tunnel_list()?,
+            (Some(TunnelAction::Restore { targets }), _) => masuk.tunnel_restore(&targets)?,
+            (Some(TunnelAction::Stop { targets, all }), _) => masuk.tunnel_stop(&targets, all)?,
+            (None, Some(profile)) => masuk.tunnel(&profile, &options)?,
+            (None, None) => unreachable!("clap requires a profile"),
+        },
+        Commands::Socks {
+            profile,
+            port,
+            background,
+            dry_run,
+        } => {
+            let port = match port {
+                Some(port) => port,
+                None => masuk.resolve(&profile)?.socks_port.unwrap_or(DEFAULT_SOCKS_PORT),
+            };
+            let options = TunnelOptions {
+                names: Vec::new(),
+                local: Vec::new(),
+                remote: Vec::new(),
+                dynamic: vec![port],
+                gateway_ports: false,
+                background,
+                persist: false,
+                notify: false,
+                install_service: false,
+                remove_service: false,
+                dry_run,
+            };
+            masuk.tunnel(&profile, &options)?;
+        }
+        Commands::SudoPassword { profile } => {
+            masuk.store_sudo_password(&profile)?;
+        }
+        Commands::Secret { action } => match action {
+            SecretAction::Set {
+                profile,
+                sudo,
+                passphrase,
+                totp,
+            } => {
+                if sudo {
+                    masuk.store_sudo_password(&profile)?;
+                } else if passphrase {
+                    masuk.store_key_passphrase(&profile)?;
+                } else if totp {
+                    masuk.store_totp(&profile)?;
+                } else {
+                    masuk.store_password(&profile)?;
+                }
+            }
+        },
+        Commands::Export { format } => match format {
+            ExportFormat::Json { profiles, tags } => {
+                masuk.export_json(&profiles, &tags)?;
+            }
+            ExportFormat::SshConfig { profiles, tags, output } => {
+                masuk.export_ssh_config(&profiles, &tags, output.as_deref())?;
+            }
+        },
+        Commands::Import { source } => match source {
+            ImportSource::DnsZone { zone, server, options } => {
+                let found = import::dns_zone(&zone, server.as_deref())?;
+                masuk.import(found, options)?;
+            }
+            ImportSource::Json { file, options } => {
+                let found = import::json(&import::read_input(&file)?)?;
+                masuk.import(found, options)?;
+            }
+            ImportSource::Terraform {
+                file,
+                mappings,
+                workspace,
+                options,
+            } => {
+                let map = import::TerraformMap::parse(&mappings)?;
+                let (input, workspace) = match file {
+                    Some(file) => (import::read_input(&file)?, workspace.or_else(|| import::terraform_workspace(&file))),
+                    None => import::terraform_outputs()?,
+                };
+                let mut found = import::terraform(&input, &map)?;
+                let tag = workspace.unwrap_or_else(|| "default".to_string());
+                for entry in &mut found {
+                    entry.config.tags.push(tag.clone());
+                }
+                masuk.import(found, options)?;
+            }
+            ImportSource::SshConfig { file, prefix, options } => {
+                let file = match file {
+                    Some(file) => file,
+                    None => dirs::home_dir()
+                        .context("Could not find home directory")?
+                        .join(".ssh/config")
+                        .display()
+                        .to_string(),
+                };
+                let (found, skipped) = import::ssh_config(&file, &prefix)?;
+                if !skipped.is_empty() {
+                    out().info(&format!("Skipped patterns and Match blocks, they aren't hosts: {}", skipped.join(", ")));
+                }
+                masuk.import(found, options)?;
+            }
+            ImportSource::Csv { file, options } => {
+                let found = import::csv(&import::read_input(&file)?)?;
+                masuk.import(found, options)?;
+            }
+        },
+        Commands::Bootstrap { profile, options } => {
+            masuk.bootstrap(&profile, options)?;
+        }
+        Commands::Seed {
+            profile,
+            profiles,
+            tags,
+            ssh_config,
+            install,
+        } => {
+            masuk.seed(&profile, &profiles, &tags, ssh_config, install)?;
+        }
+        Commands::Preflight { profiles, tags } => {
+            masuk.preflight(&profiles, &tags)?;
+        }
+        Commands::Hostkey { action } => match action {
+            HostkeyAction::Rollover { profile, yes } => {
+                masuk.hostkey_rollover(&profile, yes)?;
+            }
+        },
+        Commands::Env { action } => match action {
+            EnvAction::Export { context, config } => {
+                masuk.env_export(context.as_deref(), config)?;
+            }
+        },
+        Commands::Config { action } => match action {
+            ConfigAction::Show => {
+                masuk.show_settings()?;
+            }
+            ConfigAction::Set { setting, value } => {
+                masuk.set_setting(setting, Some(value.join(" ")))?;
+            }
+            ConfigAction::Unset { setting } => {
+                masuk.set_setting(setting, None)?;
+            }
+        },
+        Commands::Otp { profile, copy } => {
+            masuk.otp(&profile, copy)?;
+        }
+        Commands::Wake { profile } => {
+            masuk.wake_profile(&profile)?;
+        }
+        Commands::Which { profile, mut options } => {
+            options.dry_run = true;
+            masuk.connect(&profile, &options)?;
+        }
+        Commands::Exec {
+            profile,
+            tty,
+            tags,
+            stagger,
+            jitter,
+            options,
+            command,
+        } => {
+            if tags.is_empty() {
+                masuk.exec(&profile.unwrap_or_default(), &options, &command, tty)?;
             } else {
-                return Err(anyhow!("No profile specified"));
+                let profiles = masuk.select_profiles(&Vec::from_iter(profile), &tags)?;
+                let stagger = stagger.unwrap_or(0);
+                let jitter = jitter.map(|max| max.unwrap_or(stagger)).unwrap_or(0);
+                masuk.exec_group(&profiles, &options, &command, stagger, jitter)?;
+            }
+        }
+        Commands::Tail {
+            follow,
+            lines,
+            tags,
+            options,
+            mut args,
+        } => {
+            let profiles = if tags.is_empty() {
+                vec![args.remove(0)]
+            } else {
+                masuk.select_profiles(&[], &tags)?
+            };
+            if args.is_empty() {
+                return Err(anyhow!("Name the files to tail, e.g. /var/log/syslog"));
+            }
+            masuk.tail(&profiles, &args, lines, follow, &options)?;
+        }
+        Commands::Svc { tags, yes, mut args } => {
+            let unit = args.pop().unwrap_or_default();
+            let action = SvcAction::from_str(&args.pop().unwrap_or_default(), true)
+                .map_err(|_| anyhow!("Unknown action, use status or restart"))?;
+            if args.is_empty() && tags.is_empty() {
+                return Err(anyhow!("Name a profile or select some with --tag"));
+            }
+            let profiles = masuk.select_profiles(&args, &tags)?;
+            masuk.svc(&profiles, action, &unit, yes)?;
+        }
+        Commands::Connect {
+            profile,
+            sequential,
+            panes,
+            options,
+        } => {
+            if profile.contains(['*', '?']) {
+                // Everything after the pattern is handed to the masuk in each pane
+                let mut rest = raw_args.iter().skip(2);
+                let mut args: Vec<String> = rest.by_ref().take_while(|arg| **arg != profile).cloned().collect();
+                args.extend(rest.cloned());
+                args.retain(|arg| arg != "--panes" && arg != "--sequential");
+                masuk.connect_many(&profile, &options, sequential, panes, &args)?;
+            } else {
+                masuk.connect(&profile, &options)?;
             }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A masuk with `profiles` and nothing on disk, so commands come out
+    /// without control sockets.
+    fn masuk(profiles: &[(&str, HostConfig)]) -> Masuk {
+        Masuk {
+            config: Config {
+                profiles: profiles.iter().map(|(name, host_config)| (name.to_string(), host_config.clone())).collect(),
+                ..Config::default()
+            },
+            config_path: PathBuf::from("/nonexistent/masuk/config.json"),
+            state_dir: PathBuf::from("/nonexistent/masuk"),
+        }
+    }
+
+    fn host(host: &str) -> HostConfig {
+        HostConfig {
+            host: host.to_string(),
+            ..HostConfig::default()
+        }
+    }
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn ssh_command_of_a_bare_host() {
+        let masuk = masuk(&[]);
+        assert_eq!(masuk.ssh_command("web", &host("10.0.0.1")).unwrap(), words(&["ssh", "10.0.0.1"]));
+    }
+
+    #[test]
+    fn ssh_command_with_user_port_and_keys() {
+        let host_config = HostConfig {
+            user: Some("deploy".to_string()),
+            port: Some(2222),
+            keys: words(&["~/.ssh/a", "~/.ssh/b"]),
+            ..host("10.0.0.1")
+        };
+        assert_eq!(
+            masuk(&[]).ssh_command("web", &host_config).unwrap(),
+            words(&["ssh", "-p", "2222", "-i", "~/.ssh/a", "-i", "~/.ssh/b", "-o", "IdentitiesOnly=yes", "deploy@10.0.0.1"])
+        );
+    }
+
+    #[test]
+    fn ssh_command_jumps_through_profiles() {
+        let bastion = HostConfig {
+            user: Some("ops".to_string()),
+            port: Some(2200),
+            ..host("bastion.example.com")
+        };
+        let web = HostConfig {
+            jump: Some("bastion".to_string()),
+            via: words(&["10.0.0.254"]),
+            ..host("10.0.0.1")
+        };
+        let masuk = masuk(&[("bastion", bastion), ("web", web.clone())]);
+        assert_eq!(
+            masuk.ssh_command("web", &web).unwrap(),
+            words(&["ssh", "-J", "ops@bastion.example.com:2200,10.0.0.254", "10.0.0.1"])
+        );
+    }
+
+    #[test]
+    fn ssh_command_detects_jump_loops() {
+        let a = HostConfig {
+            jump: Some("b".to_string()),
+            ..host("a.example.com")
+        };
+        let b = HostConfig {
+            jump: Some("a".to_string()),
+            ..host("b.example.com")
+        };
+        let masuk = masuk(&[("a", a.clone()), ("b", b)]);
+        assert!(masuk.ssh_command("a", &a).is_err());
+    }
+
+    #[test]
+    fn ssh_command_refuses_serial_consoles() {
+        let host_config = HostConfig {
+            backend: Some(Backend::Serial),
+            ..host("")
+        };
+        assert!(masuk(&[]).ssh_command("console", &host_config).is_err());
+    }
+
+    #[test]
+    fn transfer_command_uses_scp_options() {
+        let host_config = HostConfig {
+            user: Some("deploy".to_string()),
+            port: Some(2222),
+            forward_agent: true,
+            ..host("10.0.0.1")
+        };
+        let (command, target) = masuk(&[]).scp_command("web", &host_config).unwrap();
+        assert_eq!(command, words(&["scp", "-P", "2222"]));
+        assert_eq!(target, "deploy@10.0.0.1");
+    }
+
+    #[test]
+    fn transfer_command_passes_another_client() {
+        let host_config = HostConfig {
+            ssh_command: Some("tsh-ssh".to_string()),
+            ..host("10.0.0.1")
+        };
+        let (command, _) = masuk(&[]).sftp_command("web", &host_config).unwrap();
+        assert_eq!(command, words(&["sftp", "-S", "tsh-ssh"]));
+    }
+
+    #[test]
+    fn mosh_command_wraps_ssh() {
+        let host_config = HostConfig {
+            mosh_ports: Some("60000:60010".to_string()),
+            ..host("10.0.0.1")
+        };
+        let ssh = words(&["ssh", "-p", "2222", "-A", "-t", "me@10.0.0.1"]);
+        assert_eq!(
+            mosh_command(&host_config, ssh, Some("uptime")),
+            words(&["mosh", "--ssh=ssh -p 2222", "--port=60000:60010", "me@10.0.0.1", "--", "sh", "-c", "uptime"])
+        );
+    }
+
+    /// A masuk where the global defaults and those of the tags `a` and `b`
+    /// all set the user, and only some set the port and key.
+    fn masuk_with_defaults() -> Masuk {
+        let mut masuk = masuk(&[]);
+        masuk.config.defaults = Defaults {
+            user: Some("global".to_string()),
+            port: Some(2200),
+            keys: words(&["~/.ssh/global"]),
+            ..Defaults::default()
+        };
+        masuk.config.tag_defaults.insert(
+            "a".to_string(),
+            Defaults {
+                user: Some("tag-a".to_string()),
+                ..Defaults::default()
+            },
+        );
+        masuk.config.tag_defaults.insert(
+            "b".to_string(),
+            Defaults {
+                user: Some("tag-b".to_string()),
+                port: Some(2202),
+                ..Defaults::default()
+            },
+        );
+        masuk
+    }
+
+    #[test]
+    fn profile_settings_come_before_defaults() {
+        let stored = HostConfig {
+            user: Some("own".to_string()),
+            port: Some(22),
+            keys: words(&["~/.ssh/own"]),
+            tags: words(&["a", "b"]),
+            ..host("10.0.0.1")
+        };
+        let masuk = masuk_with_defaults();
+        let (host_config, sources) = masuk.with_defaults("web", &stored);
+        assert_eq!(host_config.user.as_deref(), Some("own"));
+        assert_eq!(host_config.port, Some(22));
+        assert_eq!(host_config.keys, words(&["~/.ssh/own"]));
+        assert!(sources.is_empty());
+    }
+
+    #[test]
+    fn tag_defaults_come_before_global_defaults() {
+        let stored = HostConfig {
+            tags: words(&["b", "a"]),
+            ..host("10.0.0.1")
+        };
+        let masuk = masuk_with_defaults();
+        let (host_config, sources) = masuk.with_defaults("web", &stored);
+        // Tags apply in alphabetical order, not in the order they were given
+        assert_eq!(host_config.user.as_deref(), Some("tag-a"));
+        assert_eq!(host_config.port, Some(2202));
+        assert_eq!(host_config.keys, words(&["~/.ssh/global"]));
+        assert_eq!(sources["user"], "tag 'a'");
+        assert_eq!(sources["port"], "tag 'b'");
+        assert_eq!(sources["key"], "global defaults");
+    }
+
+    #[test]
+    fn global_defaults_apply_without_tags() {
+        let masuk = masuk_with_defaults();
+        let (host_config, sources) = masuk.with_defaults("web", &host("10.0.0.1"));
+        assert_eq!(host_config.user.as_deref(), Some("global"));
+        assert_eq!(host_config.port, Some(2200));
+        assert_eq!(sources["user"], "global defaults");
+    }
+
+    #[test]
+    fn unset_settings_stay_unset() {
+        let stored = HostConfig {
+            tags: words(&["a"]),
+            ..host("10.0.0.1")
+        };
+        let (host_config, sources) = masuk_with_defaults().with_defaults("web", &stored);
+        assert_eq!(host_config.jump, None);
+        assert_eq!(host_config.identity_agent, None);
+        assert_eq!(host_config.connect_timeout, None);
+        assert_eq!(host_config.keepalive_interval, None);
+        assert_eq!(host_config.compression, None);
+        assert!(host_config.host_key_checking.is_none());
+        assert_eq!(sources.len(), 3);
+
+        let (host_config, sources) = masuk(&[]).with_defaults("web", &stored);
+        assert_eq!(host_config.user, None);
+        assert_eq!(host_config.port, None);
+        assert!(host_config.keys.is_empty());
+        assert!(sources.is_empty());
+    }
+
+    #[test]
+    fn default_jump_host_skips_itself() {
+        let mut masuk = masuk(&[]);
+        masuk.config.defaults.jump = Some("bastion".to_string());
+        assert_eq!(masuk.with_defaults("bastion", &host("bastion.example.com")).0.jump, None);
+        assert_eq!(masuk.with_defaults("web", &host("10.0.0.1")).0.jump.as_deref(), Some("bastion"));
+    }
+
+    #[test]
+    fn sandbox_known_hosts_is_private() {
+        let sandbox = Sandbox::create().unwrap();
+        let dir = sandbox.known_hosts.parent().unwrap().to_path_buf();
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        assert_eq!(fs::metadata(&sandbox.known_hosts).unwrap().permissions().mode() & 0o777, 0o600);
+        // Every sandbox gets a directory of its own
+        let other = Sandbox::create().unwrap();
+        assert_ne!(other.known_hosts, sandbox.known_hosts);
+        drop(sandbox);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn finds_agent_forwarding_in_ssh_arguments() {
+        assert!(forwards_agent(&words(&["-A"])));
+        assert!(forwards_agent(&words(&["-v", "-At"])));
+        assert!(forwards_agent(&words(&["-oForwardAgent=yes"])));
+        assert!(forwards_agent(&words(&["-o", "forwardagent yes"])));
+        assert!(forwards_agent(&words(&["-o", "ForwardAgent=/tmp/agent.sock"])));
+        assert!(!forwards_agent(&words(&["-o", "ForwardAgent=no", "-a"])));
+        assert!(!forwards_agent(&words(&["-p", "2222", "-i", "-A.pem"])));
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("500"), Ok(500));
+        assert_eq!(parse_rate("800K"), Ok(800));
+        assert_eq!(parse_rate("2M"), Ok(2048));
+        assert_eq!(parse_rate("2mb"), Ok(2048));
+        assert_eq!(parse_rate("1G"), Ok(1024 * 1024));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("5T").is_err());
+        assert!(parse_rate("99999999999999G").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30"), Ok(30));
+        assert_eq!(parse_duration("5s"), Ok(5));
+        assert_eq!(parse_duration("10m"), Ok(600));
+        assert_eq!(parse_duration("1h"), Ok(3600));
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("99999999999999999h").is_err());
+    }
+
+    #[test]
+    fn parses_port_ranges() {
+        assert_eq!(parse_port_range("3000-3999"), Ok(3000..=3999));
+        assert_eq!(parse_port_range("8080"), Ok(8080..=8080));
+        assert!(parse_port_range("4000-3000").is_err());
+        assert!(parse_port_range("1-70000").is_err());
+    }
+
+    #[test]
+    fn parses_named_tunnels() {
+        assert_eq!(
+            parse_named_tunnel("grafana=3000:localhost:3000"),
+            Ok(("grafana".to_string(), "3000:localhost:3000".to_string()))
+        );
+        assert_eq!(parse_named_tunnel("pg=5432"), Ok(("pg".to_string(), "5432:localhost:5432".to_string())));
+        assert_eq!(parse_named_tunnel(""), Ok((String::new(), String::new())));
+        assert!(parse_named_tunnel("3000:localhost:3000").is_err());
+        assert!(parse_named_tunnel("-x=3000").is_err());
+        assert!(parse_named_tunnel("pg=localhost:5432").is_err());
+    }
+
+    #[test]
+    fn quotes_shell_words() {
+        assert_eq!(shell_quote("plain-word_1.txt"), "plain-word_1.txt");
+        assert_eq!(shell_quote("user@host:/srv"), "user@host:/srv");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+    }
+}
@@ -0,0 +1,407 @@
+//! Progress of file transfers to or from many hosts at once.
+//!
+//! Every scp runs on a pseudo-terminal of its own, so it keeps drawing its
+//! progress meter, which is read back instead of reaching the terminal.
+//! The meters become one bar per host and one for all of them, redrawn in
+//! place on stderr. Without a terminal, a line is printed as each host
+//! finishes.
+
+use crate::render::plain;
+use std::fs::{self, File};
+use std::io::{IsTerminal, Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 24;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// One host's transfer: its name and the scp command, plus the bytes to
+/// copy when they are known up front (pushing local files).
+pub struct Transfer {
+    pub name: String,
+    pub command: Command,
+    pub size: Option<u64>,
+}
+
+/// How a transfer ended.
+pub struct Outcome {
+    pub name: String,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Why it failed: the last thing scp said, or its exit status.
+    pub error: Option<String>,
+}
+
+/// What is known about a running transfer.
+struct State {
+    name: String,
+    size: Option<u64>,
+    /// Bytes of the files already copied.
+    done: u64,
+    /// The file scp is copying, how much of it and what percentage.
+    file: String,
+    current: u64,
+    percent: u64,
+    /// The last line that wasn't a meter, usually an error.
+    message: String,
+    started: Instant,
+    finished: Option<(Duration, Option<String>)>,
+}
+
+impl State {
+    fn bytes(&self) -> u64 {
+        self.done + self.current
+    }
+
+    /// The size when known, otherwise what the meter of the current file
+    /// suggests, which is exact for a single file.
+    fn total(&self) -> Option<u64> {
+        // What a failed transfer didn't copy won't be copied anymore
+        if let Some((_, Some(_))) = self.finished {
+            return Some(self.bytes());
+        }
+        match self.size {
+            Some(size) => Some(size.max(self.bytes())),
+            None if self.percent > 0 => Some(self.done + self.current * 100 / self.percent),
+            None => None,
+        }
+    }
+
+    fn rate(&self) -> u64 {
+        let elapsed = match self.finished {
+            Some((elapsed, _)) => elapsed,
+            None => self.started.elapsed(),
+        };
+        (self.bytes() as f64 / elapsed.as_secs_f64().max(0.001)) as u64
+    }
+
+    /// Takes in what scp printed, split on carriage returns and newlines.
+    fn update(&mut self, line: &str) {
+        let Some((file, percent, amount)) = parse_meter(line) else {
+            if !line.trim().is_empty() {
+                self.message = line.trim().to_string();
+            }
+            return;
+        };
+        if file != self.file {
+            self.done += self.current;
+            self.file = file;
+        }
+        self.current = amount;
+        self.percent = percent;
+    }
+}
+
+/// Runs all transfers at once, showing their progress until every one of
+/// them has ended.
+pub fn run(transfers: Vec<Transfer>) -> Vec<Outcome> {
+    let width = transfers.iter().map(|transfer| transfer.name.len()).max().unwrap_or(0).max(3);
+    let states: Vec<Arc<Mutex<State>>> = transfers
+        .iter()
+        .map(|transfer| {
+            Arc::new(Mutex::new(State {
+                name: transfer.name.clone(),
+                size: transfer.size,
+                done: 0,
+                file: String::new(),
+                current: 0,
+                percent: 0,
+                message: String::new(),
+                started: Instant::now(),
+                finished: None,
+            }))
+        })
+        .collect();
+
+    let handles: Vec<_> = transfers
+        .into_iter()
+        .zip(&states)
+        .map(|(transfer, state)| {
+            let state = Arc::clone(state);
+            thread::spawn(move || watch(transfer.command, &state))
+        })
+        .collect();
+
+    let terminal = std::io::stderr().is_terminal();
+    let mut reported = vec![false; states.len()];
+    let mut drawn = 0;
+    loop {
+        let finished = states.iter().all(|state| state.lock().unwrap().finished.is_some());
+        if terminal {
+            drawn = redraw(&states, width, drawn);
+        } else {
+            for (state, reported) in states.iter().zip(reported.iter_mut()) {
+                let state = state.lock().unwrap();
+                if !*reported && state.finished.is_some() {
+                    eprintln!("{}", plain(&line(&state, width)));
+                    *reported = true;
+                }
+            }
+        }
+        if finished {
+            break;
+        }
+        thread::sleep(REDRAW_INTERVAL);
+    }
+    for handle in handles {
+        handle.join().expect("transfer thread panicked");
+    }
+
+    states
+        .iter()
+        .map(|state| {
+            let state = state.lock().unwrap();
+            let (elapsed, error) = state.finished.clone().unwrap_or_default();
+            Outcome {
+                name: state.name.clone(),
+                bytes: state.bytes(),
+                elapsed,
+                error,
+            }
+        })
+        .collect()
+}
+
+/// Runs `command` on a pseudo-terminal and feeds its output to `state`
+/// until it exits.
+fn watch(mut command: Command, state: &Mutex<State>) {
+    let result = (|| -> std::io::Result<_> {
+        let (master, terminal) = open_pty()?;
+        let child = command
+            .stdin(Stdio::null())
+            .stdout(terminal.try_clone()?)
+            .stderr(terminal)
+            .spawn()?;
+        Ok((master, child))
+    })();
+    let (mut master, mut child) = match result {
+        Ok(running) => running,
+        Err(e) => {
+            state.lock().unwrap().finished = Some((Duration::ZERO, Some(format!("Failed to run scp: {}", e))));
+            return;
+        }
+    };
+    // The terminal side was handed to the child and closed here with the
+    // command, so reading ends (with EIO on Linux) once scp exits
+    drop(command);
+
+    let mut buffer = [0u8; 4096];
+    let mut pending = String::new();
+    while let Ok(read) = master.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+        pending.push_str(&String::from_utf8_lossy(&buffer[..read]));
+        while let Some(end) = pending.find(['\r', '\n']) {
+            let line: String = pending.drain(..=end).collect();
+            state.lock().unwrap().update(&line[..line.len() - 1]);
+        }
+    }
+    let status = child.wait();
+
+    let mut state = state.lock().unwrap();
+    if !pending.is_empty() {
+        state.update(&pending);
+    }
+    let error = match status {
+        Ok(status) if status.success() => None,
+        _ if !state.message.is_empty() => Some(state.message.clone()),
+        Ok(status) => Some(format!("scp exited with {}", status)),
+        Err(e) => Some(format!("Failed to wait for scp: {}", e)),
+    };
+    if error.is_none() {
+        // The meter of the last file may not have reached 100% on screen
+        if let Some(size) = state.size {
+            state.done = size;
+            state.current = 0;
+        }
+    }
+    state.finished = Some((state.started.elapsed(), error));
+}
+
+/// A pseudo-terminal wide enough for scp to show file names in its meter.
+fn open_pty() -> std::io::Result<(File, File)> {
+    let mut master = 0;
+    let mut terminal = 0;
+    let size = libc::winsize {
+        ws_row: 24,
+        ws_col: 120,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let result = unsafe { libc::openpty(&mut master, &mut terminal, std::ptr::null_mut(), std::ptr::null(), &size) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let (master, terminal) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(terminal)) };
+    Ok((File::from(master), File::from(terminal)))
+}
+
+/// Reads a line of scp's progress meter, e.g.
+/// `app.tar.gz   45%   12MB   3.1MB/s   00:04 ETA`, into the file name,
+/// the percentage and the bytes copied so far.
+pub fn parse_meter(line: &str) -> Option<(String, u64, u64)> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let at = words.iter().rposition(|word| {
+        word.strip_suffix('%').is_some_and(|number| number.parse::<u64>().is_ok_and(|percent| percent <= 100))
+    })?;
+    let percent = words[at].trim_end_matches('%').parse().ok()?;
+    let amount = parse_amount(words.get(at + 1)?)?;
+    if at == 0 || !words.get(at + 2)?.ends_with("/s") {
+        return None;
+    }
+    Some((words[..at].join(" "), percent, amount))
+}
+
+/// Parses an amount as scp prints it: `512`, `1024KB`, `12MB`.
+fn parse_amount(word: &str) -> Option<u64> {
+    let word = word.trim_end_matches('B');
+    let (number, unit) = word.split_at(word.find(|c: char| !c.is_ascii_digit()).unwrap_or(word.len()));
+    let number: u64 = number.parse().ok()?;
+    let power = match unit {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        "P" => 5,
+        _ => return None,
+    };
+    Some(number * 1024u64.pow(power))
+}
+
+/// The total size of local files and, recursively, directories. `None`
+/// when one of them can't be read, scp will fail on it anyway.
+pub fn local_size(paths: &[String]) -> Option<u64> {
+    fn size(path: &std::path::Path) -> Option<u64> {
+        let metadata = fs::metadata(path).ok()?;
+        if !metadata.is_dir() {
+            return Some(metadata.len());
+        }
+        let mut total = 0;
+        for entry in fs::read_dir(path).ok()? {
+            total += size(&entry.ok()?.path())?;
+        }
+        Some(total)
+    }
+    paths.iter().map(|path| size(path.as_ref())).sum()
+}
+
+/// Bytes with a binary unit, e.g. `12.3 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_eta(secs: u64) -> String {
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Draws the bars again over the `drawn` lines drawn last time, returning
+/// how many lines it drew.
+fn redraw(states: &[Arc<Mutex<State>>], width: usize, drawn: usize) -> usize {
+    let mut text = String::new();
+    if drawn > 0 {
+        text.push_str(&format!("\x1b[{}A", drawn));
+    }
+    let states: Vec<_> = states.iter().map(|state| state.lock().unwrap()).collect();
+    for state in &states {
+        text.push_str(&format!("\x1b[2K{}\n", plain(&line(state, width))));
+    }
+
+    let bytes: u64 = states.iter().map(|state| state.bytes()).sum();
+    let total: Option<u64> = states.iter().map(|state| state.total()).sum();
+    let started = states.iter().map(|state| state.started).min().unwrap_or_else(Instant::now);
+    let rate = (bytes as f64 / started.elapsed().as_secs_f64().max(0.001)) as u64;
+    let running = states.iter().filter(|state| state.finished.is_none()).count();
+    let eta = match total {
+        Some(total) if running > 0 && rate > 0 => Some(total.saturating_sub(bytes) / rate),
+        _ => None,
+    };
+    text.push_str(&format!("\x1b[2K{}\n", meter(&format!("{:width$}", "all", width = width), bytes, total, rate, eta)));
+
+    let mut stderr = std::io::stderr().lock();
+    let _ = stderr.write_all(text.as_bytes());
+    let _ = stderr.flush();
+    states.len() + 1
+}
+
+/// The line of one host: its bar while running, the outcome once done.
+fn line(state: &State, width: usize) -> String {
+    let name = format!("{:width$}", state.name, width = width);
+    match state.finished {
+        Some((elapsed, None)) => format!(
+            "{}  ✓ {} in {}s ({}/s)",
+            name,
+            format_bytes(state.bytes()),
+            elapsed.as_secs(),
+            format_bytes(state.rate())
+        ),
+        Some((_, Some(ref error))) => format!("{}  ✗ {}", name, error),
+        None => {
+            let rate = state.rate();
+            let eta = state.total().filter(|_| rate > 0).map(|total| total.saturating_sub(state.bytes()) / rate);
+            meter(&name, state.bytes(), state.total(), rate, eta)
+        }
+    }
+}
+
+fn meter(name: &str, bytes: u64, total: Option<u64>, rate: u64, eta: Option<u64>) -> String {
+    let fraction = match total {
+        Some(total) if total > 0 => (bytes as f64 / total as f64).min(1.0),
+        _ => 0.0,
+    };
+    let filled = (fraction * BAR_WIDTH as f64) as usize;
+    let mut text = format!(
+        "{}  [{}{}] {:>3}%  {:>10}  {:>10}/s",
+        name,
+        "#".repeat(filled),
+        ".".repeat(BAR_WIDTH - filled),
+        (fraction * 100.0) as u64,
+        format_bytes(bytes),
+        format_bytes(rate)
+    );
+    if let Some(eta) = eta {
+        text.push_str(&format!("  ETA {}", format_eta(eta)));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_scp_meters() {
+        assert_eq!(
+            parse_meter("release.tar.gz                 45%   12MB   3.1MB/s   00:04 ETA"),
+            Some(("release.tar.gz".to_string(), 45, 12 * 1024 * 1024))
+        );
+        assert_eq!(
+            parse_meter("notes 100%.txt                100%  512     1.2KB/s   00:00"),
+            Some(("notes 100%.txt".to_string(), 100, 512))
+        );
+        assert_eq!(parse_meter("ssh: connect to host 10.0.0.1 port 22: Connection refused"), None);
+        assert_eq!(parse_meter("disk 95% full"), None);
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
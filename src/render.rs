@@ -0,0 +1,213 @@
+//! Output of masuk's commands.
+//!
+//! Commands describe what they have to say (a success, a list, a record)
+//! and the [`Renderer`] chosen with the global `--json` and `--quiet` flags
+//! decides what that looks like. Output of remote commands and sessions is
+//! passed through untouched.
+//!
+//! With `--ascii` (or `TERM=dumb`) the symbols masuk uses are spelled out,
+//! see [`plain`].
+
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+/// An entry of a list, e.g. a profile in `masuk ls`.
+pub struct Item {
+    pub name: String,
+    /// Shown after the name by the human renderer.
+    pub description: String,
+    /// Emitted by the JSON renderer, should include the name.
+    pub data: Value,
+}
+
+pub trait Renderer: Send + Sync {
+    /// A completed action, e.g. a profile was added.
+    fn success(&self, message: &str);
+
+    /// Progress and side notes nobody has to parse.
+    fn info(&self, message: &str);
+
+    /// A list of items under a title. `empty` is said when there are none.
+    fn list(&self, title: &str, items: &[Item], empty: &str);
+
+    /// Lists under group names, e.g. profiles by tag.
+    fn groups(&self, groups: &[(String, Vec<Item>)]);
+
+    /// Details of a single thing as labelled fields, with `data` as the
+    /// machine-readable version.
+    fn record(&self, title: &str, fields: &[(String, String)], data: &Value);
+
+    /// Rows under column headers, e.g. unit states per host, with `data`
+    /// as the machine-readable version.
+    fn table(&self, headers: &[&str], rows: &[Vec<String>], data: &Value);
+}
+
+/// The default output, for people.
+pub struct Human;
+
+impl Renderer for Human {
+    fn success(&self, message: &str) {
+        println!("{}", plain(&format!("✓ {}", message)));
+    }
+
+    fn info(&self, message: &str) {
+        println!("{}", plain(message));
+    }
+
+    fn list(&self, title: &str, items: &[Item], empty: &str) {
+        if items.is_empty() {
+            println!("{}", plain(empty));
+            return;
+        }
+        println!("\n{}:\n", plain(title));
+        for item in items {
+            println!("{}", plain(&format!("  {} → {}", item.name, item.description)));
+        }
+        println!();
+    }
+
+    fn groups(&self, groups: &[(String, Vec<Item>)]) {
+        println!();
+        for (name, items) in groups {
+            let count = if items.len() == 1 { "1 profile".to_string() } else { format!("{} profiles", items.len()) };
+            println!("{} ({}):\n", plain(name), count);
+            for item in items {
+                println!("{}", plain(&format!("  {} → {}", item.name, item.description)));
+            }
+            println!();
+        }
+    }
+
+    fn record(&self, title: &str, fields: &[(String, String)], _data: &Value) {
+        println!("\n{}:\n", plain(title));
+        for (label, value) in fields {
+            println!("  {}: {}", label, plain(value));
+        }
+        println!();
+    }
+
+    fn table(&self, headers: &[&str], rows: &[Vec<String>], _data: &Value) {
+        let mut widths: Vec<usize> = headers.iter().map(|header| header.chars().count()).collect();
+        for row in rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let line = |cells: Vec<&str>| {
+            let padded: Vec<String> = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            println!("  {}", plain(padded.join("  ").trim_end()));
+        };
+        println!();
+        line(headers.to_vec());
+        for row in rows {
+            line(row.iter().map(String::as_str).collect());
+        }
+        println!();
+    }
+}
+
+/// One JSON document per line on stdout, for scripts.
+pub struct Json;
+
+impl Renderer for Json {
+    fn success(&self, message: &str) {
+        println!("{}", json!({ "ok": true, "message": message }));
+    }
+
+    fn info(&self, _message: &str) {}
+
+    fn list(&self, _title: &str, items: &[Item], _empty: &str) {
+        let items: Vec<&Value> = items.iter().map(|item| &item.data).collect();
+        println!("{}", Value::from(items.into_iter().cloned().collect::<Vec<_>>()));
+    }
+
+    fn groups(&self, groups: &[(String, Vec<Item>)]) {
+        let groups: serde_json::Map<String, Value> = groups
+            .iter()
+            .map(|(name, items)| (name.clone(), items.iter().map(|item| item.data.clone()).collect()))
+            .collect();
+        println!("{}", Value::Object(groups));
+    }
+
+    fn record(&self, _title: &str, _fields: &[(String, String)], data: &Value) {
+        println!("{}", data);
+    }
+
+    fn table(&self, _headers: &[&str], _rows: &[Vec<String>], data: &Value) {
+        println!("{}", data);
+    }
+}
+
+/// Only what scripts loop over: names of listed items, one per line, and
+/// table rows as tab-separated cells.
+pub struct Quiet;
+
+impl Renderer for Quiet {
+    fn success(&self, _message: &str) {}
+
+    fn info(&self, _message: &str) {}
+
+    fn list(&self, _title: &str, items: &[Item], _empty: &str) {
+        for item in items {
+            println!("{}", item.name);
+        }
+    }
+
+    fn groups(&self, groups: &[(String, Vec<Item>)]) {
+        for (name, items) in groups {
+            for item in items {
+                println!("{}\t{}", name, item.name);
+            }
+        }
+    }
+
+    fn record(&self, _title: &str, _fields: &[(String, String)], _data: &Value) {}
+
+    fn table(&self, _headers: &[&str], rows: &[Vec<String>], _data: &Value) {
+        for row in rows {
+            println!("{}", row.join("\t"));
+        }
+    }
+}
+
+static RENDERER: OnceLock<Box<dyn Renderer>> = OnceLock::new();
+static ASCII: AtomicBool = AtomicBool::new(false);
+
+/// Symbols in masuk's output and what they become in ASCII mode. Signals
+/// become words so screen readers announce them.
+const SYMBOLS: &[(&str, &str)] = &[("✓", "OK:"), ("✗", "FAILED:"), ("⚠", "WARNING:"), ("→", "->"), ("─", "-")];
+
+/// Spells out symbols for the rest of the run.
+pub fn use_ascii() {
+    ASCII.store(true, Ordering::Relaxed);
+}
+
+/// `text` as it should reach the terminal: unchanged, or with the symbols
+/// in [`SYMBOLS`] replaced in ASCII mode. Messages printed directly instead
+/// of through a renderer go through this too.
+pub fn plain(text: &str) -> Cow<'_, str> {
+    if !ASCII.load(Ordering::Relaxed) || text.is_ascii() {
+        return Cow::Borrowed(text);
+    }
+    let mut text = text.to_string();
+    for (symbol, replacement) in SYMBOLS {
+        text = text.replace(symbol, replacement);
+    }
+    Cow::Owned(text)
+}
+
+/// Picks the renderer for the rest of the run. Only the first call counts.
+pub fn init(renderer: Box<dyn Renderer>) {
+    let _ = RENDERER.set(renderer);
+}
+
+/// The renderer picked with [`init`], [`Human`] if none was.
+pub fn out() -> &'static dyn Renderer {
+    RENDERER.get_or_init(|| Box::new(Human)).as_ref()
+}
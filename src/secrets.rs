@@ -0,0 +1,497 @@
+//! Secret references and the providers that resolve them.
+//!
+//! Profiles never store secrets themselves, only references of the form
+//! `<scheme>://<path>`, e.g. `pass://servers/router1`. A [`Registry`] maps
+//! the scheme to a [`SecretsProvider`]. The built-in providers shell out to
+//! the matching command line tool, and schemes nobody registered are handed
+//! to a `masuk-secret-<scheme>` executable on `PATH`, so custom secret
+//! stores can be integrated without patching masuk.
+
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// A secret store that can be addressed with `<scheme>://<path>` references.
+pub trait SecretsProvider {
+    /// Scheme handled by this provider, without `://`.
+    fn scheme(&self) -> &str;
+
+    /// Fetches the secret stored under `path`.
+    fn get(&self, path: &str) -> Result<String>;
+
+    /// Stores `secret` under `path`. Read-only providers keep the default.
+    fn set(&self, path: &str, secret: &str) -> Result<()> {
+        let _ = (path, secret);
+        Err(anyhow!("Storing secrets is not supported by the '{}' provider", self.scheme()))
+    }
+}
+
+/// Splits `scheme://path`, returning `None` for values that aren't references.
+pub fn parse_reference(reference: &str) -> Option<(&str, &str)> {
+    let (scheme, path) = reference.split_once("://")?;
+    let valid = !scheme.is_empty()
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then_some((scheme, path))
+}
+
+/// Set of providers keyed by scheme.
+pub struct Registry {
+    providers: Vec<Box<dyn SecretsProvider>>,
+}
+
+impl Registry {
+    /// A registry without any providers. Unknown schemes still fall back to
+    /// `masuk-secret-<scheme>` executables.
+    pub fn empty() -> Self {
+        Registry {
+            providers: Vec::new(),
+        }
+    }
+
+    /// Registers a provider. A later registration for the same scheme
+    /// replaces the earlier one, which lets plugins override built-ins.
+    pub fn register(&mut self, provider: Box<dyn SecretsProvider>) {
+        self.providers.retain(|p| p.scheme() != provider.scheme());
+        self.providers.push(provider);
+    }
+
+    /// Looks up the secret a reference points to.
+    pub fn resolve(&self, reference: &str) -> Result<String> {
+        let (scheme, path) = parse_reference(reference)
+            .ok_or_else(|| anyhow!("'{}' is not a secret reference (expected <scheme>://<path>)", reference))?;
+        self.with_provider(scheme, |provider| provider.get(path))
+            .with_context(|| format!("Failed to resolve secret '{}'", reference))
+    }
+
+    /// Writes a secret to the location a reference points to.
+    pub fn store(&self, reference: &str, secret: &str) -> Result<()> {
+        let (scheme, path) = parse_reference(reference)
+            .ok_or_else(|| anyhow!("'{}' is not a secret reference (expected <scheme>://<path>)", reference))?;
+        self.with_provider(scheme, |provider| provider.set(path, secret))
+            .with_context(|| format!("Failed to store secret '{}'", reference))
+    }
+
+    fn with_provider<T>(&self, scheme: &str, f: impl FnOnce(&dyn SecretsProvider) -> Result<T>) -> Result<T> {
+        match self.providers.iter().find(|p| p.scheme() == scheme) {
+            Some(provider) => f(provider.as_ref()),
+            None => f(&Plugin::new(scheme)),
+        }
+    }
+}
+
+impl Default for Registry {
+    /// A registry with all built-in providers.
+    fn default() -> Self {
+        let mut registry = Registry::empty();
+        registry.register(Box::new(Keyring));
+        registry.register(Box::new(Pass));
+        registry.register(Box::new(Gopass));
+        let sessions = dirs::runtime_dir().or_else(dirs::cache_dir).map(|dir| dir.join("masuk"));
+        registry.register(Box::new(match sessions {
+            Some(ref dir) => OnePassword::with_session_cache(dir.join("op-session")),
+            None => OnePassword::new(),
+        }));
+        registry.register(Box::new(match sessions {
+            Some(ref dir) => Bitwarden::with_session_cache(dir.join("bw-session")),
+            None => Bitwarden::new(),
+        }));
+        registry.register(Box::new(Vault));
+        registry.register(Box::new(VaultSshOtp));
+        registry
+    }
+}
+
+/// OS keychain: `keyring://<service>/<account>`.
+///
+/// Uses `security` on macOS and `secret-tool` (libsecret) elsewhere.
+pub struct Keyring;
+
+impl Keyring {
+    fn split(path: &str) -> Result<(&str, &str)> {
+        path.split_once('/')
+            .filter(|(service, account)| !service.is_empty() && !account.is_empty())
+            .ok_or_else(|| anyhow!("Keyring references look like keyring://<service>/<account>"))
+    }
+
+    /// Quotes a word for a command line of `security -i`.
+    fn quote(word: &str) -> String {
+        format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+impl SecretsProvider for Keyring {
+    fn scheme(&self) -> &str {
+        "keyring"
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        let (service, account) = Keyring::split(path)?;
+        if cfg!(target_os = "macos") {
+            run("security", &["find-generic-password", "-s", service, "-a", account, "-w"], None)
+        } else {
+            run("secret-tool", &["lookup", "service", service, "account", account], None)
+        }
+    }
+
+    fn set(&self, path: &str, secret: &str) -> Result<()> {
+        let (service, account) = Keyring::split(path)?;
+        if cfg!(target_os = "macos") {
+            // Given as arguments, the secret would show up in ps, so the
+            // command goes to the interactive mode on stdin instead
+            if secret.contains(['\n', '\r']) {
+                return Err(anyhow!("The keychain can't store secrets with line breaks"));
+            }
+            let command = format!(
+                "add-generic-password -U -s {} -a {} -w {}\n",
+                Keyring::quote(service),
+                Keyring::quote(account),
+                Keyring::quote(secret)
+            );
+            run("security", &["-i"], Some(&command))?;
+        } else {
+            let label = format!("masuk {}/{}", service, account);
+            run(
+                "secret-tool",
+                &["store", "--label", &label, "service", service, "account", account],
+                Some(secret),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The standard unix password manager: `pass://<entry>`. Only the first
+/// line of the entry is used, following the pass convention.
+pub struct Pass;
+
+impl SecretsProvider for Pass {
+    fn scheme(&self) -> &str {
+        "pass"
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        let entry = run("pass", &["show", path], None)?;
+        Ok(entry.lines().next().unwrap_or_default().to_string())
+    }
+
+    fn set(&self, path: &str, secret: &str) -> Result<()> {
+        run("pass", &["insert", "--multiline", "--force", path], Some(secret))?;
+        Ok(())
+    }
+}
+
+/// gopass, the team-friendly pass: `gopass://<entry>`. Like with pass,
+/// only the password in the first line is used.
+pub struct Gopass;
+
+impl SecretsProvider for Gopass {
+    fn scheme(&self) -> &str {
+        "gopass"
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        run("gopass", &["show", "--password", path], None)
+    }
+
+    fn set(&self, path: &str, secret: &str) -> Result<()> {
+        run("gopass", &["insert", "--force", path], Some(secret))?;
+        Ok(())
+    }
+}
+
+/// 1Password CLI: `op://<vault>/<item>/<field>`, passed to `op read` as is.
+///
+/// With a session cache, masuk signs in with `op signin --raw` once and
+/// reuses the session token until op expires it, instead of every
+/// connection asking to unlock 1Password again. Service account tokens and
+/// `OP_SESSION_*` variables in the environment take precedence.
+pub struct OnePassword {
+    session_cache: Option<PathBuf>,
+}
+
+impl OnePassword {
+    /// Leaves signing in to op, which may ask on every read.
+    pub fn new() -> Self {
+        OnePassword { session_cache: None }
+    }
+
+    /// Keeps the session token in the file at `path`, readable only by the
+    /// user.
+    pub fn with_session_cache(path: PathBuf) -> Self {
+        OnePassword {
+            session_cache: Some(path),
+        }
+    }
+
+    fn signed_in_by_environment() -> bool {
+        env::vars_os().any(|(name, _)| {
+            let name = name.to_string_lossy();
+            name == "OP_SERVICE_ACCOUNT_TOKEN" || name.starts_with("OP_SESSION_")
+        })
+    }
+
+    fn read(reference: &str, session: Option<&str>) -> Result<String> {
+        let mut args = vec!["read", "--no-newline", reference];
+        if let Some(session) = session {
+            args.extend(["--session", session]);
+        }
+        run("op", &args, None)
+    }
+}
+
+impl Default for OnePassword {
+    fn default() -> Self {
+        OnePassword::new()
+    }
+}
+
+impl SecretsProvider for OnePassword {
+    fn scheme(&self) -> &str {
+        "op"
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        let reference = format!("op://{}", path);
+        let cache = match self.session_cache {
+            Some(ref cache) if !OnePassword::signed_in_by_environment() => cache,
+            _ => return OnePassword::read(&reference, None),
+        };
+
+        if let Ok(token) = fs::read_to_string(cache) {
+            if let Ok(secret) = OnePassword::read(&reference, Some(token.trim())) {
+                return Ok(secret);
+            }
+            // Expired or signed out, sign in again below
+            let _ = fs::remove_file(cache);
+        }
+        let token = run("op", &["signin", "--raw"], None)?;
+        // Empty with the desktop app integration, which keeps its own session
+        if token.is_empty() {
+            return OnePassword::read(&reference, None);
+        }
+        save_session(cache, &token)?;
+        OnePassword::read(&reference, Some(&token))
+    }
+}
+
+/// Bitwarden CLI, also for Vaultwarden servers: `bw://<item>#<field>`, the
+/// item being a name or id and the field defaulting to `password`. Besides
+/// `username`, `password`, `totp`, `notes` and `uri`, custom fields of the
+/// item can be named.
+///
+/// With a session cache, the vault is unlocked with `bw unlock --raw` once
+/// and the session key reused until it stops working. A `BW_SESSION`
+/// variable in the environment takes precedence.
+pub struct Bitwarden {
+    session_cache: Option<PathBuf>,
+}
+
+impl Bitwarden {
+    /// Leaves unlocking to the caller, through `BW_SESSION`.
+    pub fn new() -> Self {
+        Bitwarden { session_cache: None }
+    }
+
+    /// Keeps the session key in the file at `path`, readable only by the
+    /// user.
+    pub fn with_session_cache(path: PathBuf) -> Self {
+        Bitwarden {
+            session_cache: Some(path),
+        }
+    }
+
+    fn read(item: &str, field: &str, session: Option<&str>) -> Result<String> {
+        let builtin = ["username", "password", "totp", "notes", "uri"].contains(&field);
+        let mut args = vec!["get", if builtin { field } else { "item" }, item];
+        if let Some(session) = session {
+            args.extend(["--session", session]);
+        }
+        let output = run("bw", &args, None)?;
+        if builtin {
+            return Ok(output);
+        }
+        let item: serde_json::Value =
+            serde_json::from_str(&output).context("Failed to parse the item printed by 'bw get item'")?;
+        item["fields"]
+            .as_array()
+            .and_then(|fields| fields.iter().find(|f| f["name"] == field))
+            .and_then(|f| f["value"].as_str())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("The item has no field '{}'", field))
+    }
+}
+
+impl Default for Bitwarden {
+    fn default() -> Self {
+        Bitwarden::new()
+    }
+}
+
+impl SecretsProvider for Bitwarden {
+    fn scheme(&self) -> &str {
+        "bw"
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        let (item, field) = path.split_once('#').unwrap_or((path, "password"));
+        let cache = match self.session_cache {
+            Some(ref cache) if env::var_os("BW_SESSION").is_none() => cache,
+            _ => return Bitwarden::read(item, field, None),
+        };
+
+        if let Ok(token) = fs::read_to_string(cache) {
+            if let Ok(secret) = Bitwarden::read(item, field, Some(token.trim())) {
+                return Ok(secret);
+            }
+            // Locked again since, unlock below
+            let _ = fs::remove_file(cache);
+        }
+        let token = run("bw", &["unlock", "--raw"], None)?;
+        save_session(cache, &token)?;
+        Bitwarden::read(item, field, Some(&token))
+    }
+}
+
+/// HashiCorp Vault KV store: `vault://<path>#<field>`, the field defaulting
+/// to `password`.
+pub struct Vault;
+
+impl SecretsProvider for Vault {
+    fn scheme(&self) -> &str {
+        "vault"
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        let (path, field) = path.split_once('#').unwrap_or((path, "password"));
+        run("vault", &["kv", "get", &format!("-field={}", field), path], None)
+    }
+
+    fn set(&self, path: &str, secret: &str) -> Result<()> {
+        let (path, field) = path.split_once('#').unwrap_or((path, "password"));
+        run("vault", &["kv", "put", path, &format!("{}=-", field)], Some(secret))?;
+        Ok(())
+    }
+}
+
+/// One-time passwords from Vault's SSH secrets engine:
+/// `vault-ssh-otp://<mount>/<role>#[<user>@]<ip>`. Every lookup creates a
+/// new password, valid for a single login to the host at `ip`.
+pub struct VaultSshOtp;
+
+impl SecretsProvider for VaultSshOtp {
+    fn scheme(&self) -> &str {
+        "vault-ssh-otp"
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        let (role, target) = path
+            .split_once('#')
+            .filter(|(role, target)| role.contains('/') && !target.is_empty())
+            .ok_or_else(|| anyhow!("Vault OTP references look like vault-ssh-otp://<mount>/<role>#[<user>@]<ip>"))?;
+        let (mount, role) = role.rsplit_once('/').expect("checked above");
+        let creds = format!("{}/creds/{}", mount, role);
+        let mut args = vec!["write".to_string(), "-field=key".to_string(), creds];
+        match target.split_once('@') {
+            Some((user, ip)) => args.extend([format!("ip={}", ip), format!("username={}", user)]),
+            None => args.push(format!("ip={}", target)),
+        }
+        run("vault", &args.iter().map(String::as_str).collect::<Vec<_>>(), None)
+    }
+}
+
+/// Provider backed by an external `masuk-secret-<scheme>` executable.
+///
+/// The executable is called as `masuk-secret-<scheme> get <path>` and must
+/// print the secret on stdout, or as `masuk-secret-<scheme> set <path>`
+/// with the secret on stdin.
+pub struct Plugin {
+    scheme: String,
+}
+
+impl Plugin {
+    pub fn new(scheme: &str) -> Self {
+        Plugin {
+            scheme: scheme.to_string(),
+        }
+    }
+
+    fn program(&self) -> String {
+        format!("masuk-secret-{}", self.scheme)
+    }
+}
+
+impl SecretsProvider for Plugin {
+    fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        run(&self.program(), &["get", path], None)
+    }
+
+    fn set(&self, path: &str, secret: &str) -> Result<()> {
+        run(&self.program(), &["set", path], Some(secret))?;
+        Ok(())
+    }
+}
+
+/// Writes a session token of a password manager to `path`, readable only by
+/// the user.
+fn save_session(path: &PathBuf, token: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    file.write_all(token.as_bytes())?;
+    Ok(())
+}
+
+/// Runs a helper tool and returns its stdout without the trailing newline.
+/// Stderr is left attached so the tool can prompt for unlocking.
+fn run(program: &str, args: &[&str], input: Option<&str>) -> Result<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::inherit() })
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run '{}'. Is it installed?", program))?;
+
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin
+            .write_all(input.as_bytes())
+            .with_context(|| format!("Failed to pass secret to '{}'", program))?;
+    }
+
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("Failed to run '{}'", program))?;
+    if !output.status.success() {
+        return Err(anyhow!("'{}' exited with {}", program, output.status));
+    }
+
+    let mut stdout = String::from_utf8(output.stdout)
+        .with_context(|| format!("'{}' returned a secret that is not valid UTF-8", program))?;
+    if stdout.ends_with('\n') {
+        stdout.pop();
+        if stdout.ends_with('\r') {
+            stdout.pop();
+        }
+    }
+    Ok(stdout)
+}
@@ -0,0 +1,195 @@
+//! Inventory snapshots and the drift between them.
+//!
+//! A snapshot is a copy of all profiles at one point in time, kept in
+//! `~/.config/masuk/snapshots/<name>.json`. Comparing two of them (or one
+//! with the current inventory) lists the profiles that were added, removed
+//! or changed, for access reviews.
+
+use crate::HostConfig;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// Unix time the snapshot was taken.
+    pub taken_at: i64,
+    pub profiles: BTreeMap<String, HostConfig>,
+}
+
+impl Snapshot {
+    pub fn load(dir: &Path, name: &str) -> Result<Snapshot> {
+        let path = dir.join(format!("{}.json", name));
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Snapshot '{}' not found. Use 'masuk snapshot ls' to see available snapshots.", name))?;
+        serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir).context("Failed to create snapshots directory")?;
+        let path = dir.join(format!("{}.json", self.name));
+        let content = serde_json::to_string_pretty(self).context("Failed to serialize snapshot")?;
+        fs::write(&path, content).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// All snapshots in `dir`, oldest first.
+pub fn list(dir: &Path) -> Result<Vec<Snapshot>> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(Vec::new());
+    };
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let content = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+            let snapshot: Snapshot =
+                serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))?;
+            snapshots.push(snapshot);
+        }
+    }
+    snapshots.sort_by(|a, b| a.taken_at.cmp(&b.taken_at).then_with(|| a.name.cmp(&b.name)));
+    Ok(snapshots)
+}
+
+/// A setting that differs between two copies of a profile.
+pub struct Change {
+    pub setting: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+/// What changed in the inventory between two points in time.
+pub struct Drift {
+    pub added: Vec<(String, HostConfig)>,
+    pub removed: Vec<(String, HostConfig)>,
+    pub changed: Vec<(String, Vec<Change>)>,
+}
+
+impl Drift {
+    pub fn between(before: &BTreeMap<String, HostConfig>, after: &BTreeMap<String, HostConfig>) -> Result<Drift> {
+        let mut drift = Drift {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+        for (name, host_config) in after {
+            if !before.contains_key(name) {
+                drift.added.push((name.clone(), host_config.clone()));
+            }
+        }
+        for (name, old) in before {
+            let Some(new) = after.get(name) else {
+                drift.removed.push((name.clone(), old.clone()));
+                continue;
+            };
+            let changes = changes(old, new)?;
+            if !changes.is_empty() {
+                drift.changed.push((name.clone(), changes));
+            }
+        }
+        Ok(drift)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let profile = |(name, host_config): &(String, HostConfig)| {
+            let mut data = serde_json::to_value(host_config).unwrap_or_default();
+            data["name"] = json!(name);
+            data
+        };
+        json!({
+            "added": self.added.iter().map(profile).collect::<Vec<_>>(),
+            "removed": self.removed.iter().map(profile).collect::<Vec<_>>(),
+            "changed": self.changed.iter().map(|(name, changes)| json!({
+                "name": name,
+                "settings": changes
+                    .iter()
+                    .map(|change| (change.setting.clone(), json!({ "before": change.before, "after": change.after })))
+                    .collect::<serde_json::Map<_, _>>(),
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    /// A report with one table per kind of change, for pasting into a review.
+    pub fn to_markdown(&self, title: &str) -> String {
+        let mut report = format!("# {}\n", title);
+        if self.is_empty() {
+            report.push_str("\nNo profiles were added, removed or changed.\n");
+            return report;
+        }
+        for (heading, profiles) in [("Added", &self.added), ("Removed", &self.removed)] {
+            if profiles.is_empty() {
+                continue;
+            }
+            report.push_str(&format!("\n## {} ({})\n\n| Profile | Address | Tags | Host key |\n|---|---|---|---|\n", heading, profiles.len()));
+            for (name, host_config) in profiles {
+                report.push_str(&format!(
+                    "| {} | {} | {} | {} |\n",
+                    cell(name),
+                    cell(&host_config.address()),
+                    cell(&host_config.tags.join(", ")),
+                    cell(host_config.host_key_fingerprint.as_deref().unwrap_or("")),
+                ));
+            }
+        }
+        if !self.changed.is_empty() {
+            report.push_str(&format!("\n## Changed ({})\n\n| Profile | Setting | Before | After |\n|---|---|---|---|\n", self.changed.len()));
+            for (name, changes) in &self.changed {
+                for change in changes {
+                    report.push_str(&format!(
+                        "| {} | {} | {} | {} |\n",
+                        cell(name),
+                        cell(&change.setting),
+                        cell(&show(&change.before)),
+                        cell(&show(&change.after)),
+                    ));
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Settings that differ, ignoring when the profile was last touched.
+fn changes(old: &HostConfig, new: &HostConfig) -> Result<Vec<Change>> {
+    let (Value::Object(old), Value::Object(new)) = (serde_json::to_value(old)?, serde_json::to_value(new)?) else {
+        return Err(anyhow!("Profiles didn't serialize to objects"));
+    };
+    let mut settings: Vec<&String> = old.keys().chain(new.keys()).filter(|key| *key != "updated_at").collect();
+    settings.sort();
+    settings.dedup();
+    Ok(settings
+        .into_iter()
+        .filter_map(|setting| {
+            let before = old.get(setting).cloned().unwrap_or(Value::Null);
+            let after = new.get(setting).cloned().unwrap_or(Value::Null);
+            (before != after).then(|| Change {
+                setting: setting.clone(),
+                before,
+                after,
+            })
+        })
+        .collect())
+}
+
+/// A setting's value as text, without quotes around strings.
+pub fn show(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items.iter().map(show).collect::<Vec<_>>().join(", "),
+        other => other.to_string(),
+    }
+}
+
+fn cell(text: &str) -> String {
+    text.replace('|', "\\|").replace('\n', " ")
+}
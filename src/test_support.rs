@@ -0,0 +1,271 @@
+//! A throwaway OpenSSH server for end-to-end tests, behind the
+//! `test-support` feature.
+//!
+//! [`SshServer::start`] runs the system's `sshd` in the foreground on a free
+//! port of 127.0.0.1, with host and client keys made for it in a private
+//! directory. Only the current user can log in, and only with the client
+//! key. The known_hosts file it hands out pins the host key, so tests
+//! connect with strict host key checking and never touch `~/.ssh`. The
+//! server is stopped and its directory removed on drop.
+//!
+//! Where no sshd is installed, `start` fails with
+//! [`io::ErrorKind::NotFound`], which tests can take as a reason to skip.
+//! Run as root, sshd also needs its privilege separation directory
+//! (usually `/run/sshd`). `MASUK_TEST_SSHD` picks another sshd binary.
+
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const HOST: &str = "127.0.0.1";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running sshd, see the module documentation.
+pub struct SshServer {
+    child: Option<Child>,
+    dir: PathBuf,
+    port: u16,
+    user: String,
+}
+
+impl SshServer {
+    /// Starts a server and waits until it accepts connections.
+    pub fn start() -> io::Result<SshServer> {
+        let sshd = find_sshd()?;
+        let user = current_user()?;
+        let dir = private_dir()?;
+        // Removes the directory from here on, also when starting fails
+        let mut server = SshServer {
+            child: None,
+            dir,
+            port: free_port()?,
+            user,
+        };
+
+        keygen(&server.dir.join("host_ed25519"))?;
+        keygen(&server.identity())?;
+        fs::copy(server.dir.join("id_ed25519.pub"), server.dir.join("authorized_keys"))?;
+        let host_key = fs::read_to_string(server.dir.join("host_ed25519.pub"))?;
+        fs::write(server.known_hosts(), format!("[{}]:{} {}", HOST, server.port, host_key))?;
+
+        let config = server.dir.join("sshd_config");
+        fs::write(&config, server.config())?;
+        let child = Command::new(&sshd)
+            .arg("-D")
+            .arg("-f")
+            .arg(&config)
+            .arg("-E")
+            .arg(server.log())
+            .stdin(Stdio::null())
+            .spawn()?;
+        server.child = Some(child);
+        server.wait_until_ready()?;
+        Ok(server)
+    }
+
+    /// Address the server listens on.
+    pub fn host(&self) -> &str {
+        HOST
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The user that may log in, the one running the tests.
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Private key the server accepts.
+    pub fn identity(&self) -> PathBuf {
+        self.dir.join("id_ed25519")
+    }
+
+    /// known_hosts file with the server's host key.
+    pub fn known_hosts(&self) -> PathBuf {
+        self.dir.join("known_hosts")
+    }
+
+    /// The server's private directory, removed with it, e.g. for a `HOME`
+    /// or files to copy. Being on the same machine, tests see the files
+    /// written over ssh here directly.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// What sshd logged so far, to show when a test fails.
+    pub fn log_contents(&self) -> String {
+        fs::read_to_string(self.log()).unwrap_or_default()
+    }
+
+    /// Options for running `ssh` against the server, to be followed by
+    /// [`SshServer::destination`]. Neither `~/.ssh/config` nor an agent
+    /// are used.
+    pub fn ssh_args(&self) -> Vec<String> {
+        let mut args = vec!["-F".to_string(), "/dev/null".to_string(), "-p".to_string(), self.port.to_string()];
+        args.extend(["-i".to_string(), self.identity().display().to_string()]);
+        for option in [
+            "IdentitiesOnly=yes".to_string(),
+            "IdentityAgent=none".to_string(),
+            "BatchMode=yes".to_string(),
+            "StrictHostKeyChecking=yes".to_string(),
+            format!("UserKnownHostsFile={}", self.known_hosts().display()),
+        ] {
+            args.extend(["-o".to_string(), option]);
+        }
+        args
+    }
+
+    /// `user@host` of the server.
+    pub fn destination(&self) -> String {
+        format!("{}@{}", self.user, HOST)
+    }
+
+    /// Arguments of `masuk add <profile> ...` for a profile of the server.
+    /// Like [`SshServer::ssh_args`], it ignores `~/.ssh/config`.
+    pub fn add_args(&self, profile: &str) -> Vec<String> {
+        [
+            "add",
+            profile,
+            "--host",
+            HOST,
+            "--user",
+            &self.user,
+            "--port",
+            &self.port.to_string(),
+            "--key",
+            &self.identity().display().to_string(),
+            "--known-hosts",
+            &self.known_hosts().display().to_string(),
+            "--host-key-checking",
+            "strict",
+            "--extra-arg",
+            "-F /dev/null",
+        ]
+        .map(str::to_string)
+        .to_vec()
+    }
+
+    fn log(&self) -> PathBuf {
+        self.dir.join("sshd.log")
+    }
+
+    fn config(&self) -> String {
+        let path = |name: &str| format!("\"{}\"", self.dir.join(name).display());
+        format!(
+            "Port {port}\n\
+             ListenAddress {host}\n\
+             HostKey {host_key}\n\
+             AuthorizedKeysFile {authorized_keys}\n\
+             PidFile none\n\
+             AllowUsers {user}\n\
+             PermitRootLogin prohibit-password\n\
+             PubkeyAuthentication yes\n\
+             PasswordAuthentication no\n\
+             KbdInteractiveAuthentication no\n\
+             UsePAM no\n\
+             StrictModes no\n\
+             AllowTcpForwarding yes\n\
+             AcceptEnv MASUK_*\n\
+             Subsystem sftp internal-sftp\n\
+             LogLevel VERBOSE\n",
+            port = self.port,
+            host = HOST,
+            host_key = path("host_ed25519"),
+            authorized_keys = path("authorized_keys"),
+            user = self.user,
+        )
+    }
+
+    fn wait_until_ready(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        loop {
+            if let Some(status) = self.child.as_mut().expect("sshd was started").try_wait()? {
+                return Err(io::Error::other(format!("sshd exited with {}: {}", status, self.log_contents().trim())));
+            }
+            if TcpStream::connect((HOST, self.port)).is_ok() {
+                return Ok(());
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("sshd didn't listen on port {}: {}", self.port, self.log_contents().trim()),
+                ));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for SshServer {
+    fn drop(&mut self) {
+        if let Some(ref mut child) = self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// `MASUK_TEST_SSHD`, or sshd from `PATH` or the usual sbin directories.
+/// It has to be an absolute path, sshd doesn't start otherwise.
+fn find_sshd() -> io::Result<PathBuf> {
+    if let Some(sshd) = env::var_os("MASUK_TEST_SSHD").filter(|sshd| !sshd.is_empty()) {
+        return Ok(PathBuf::from(sshd));
+    }
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .chain(["/usr/sbin", "/usr/local/sbin", "/sbin", "/opt/homebrew/sbin"].map(PathBuf::from))
+        .filter(|dir| dir.is_absolute())
+        .map(|dir| dir.join("sshd"))
+        .find(|sshd| sshd.is_file())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "sshd is not installed"))
+}
+
+fn current_user() -> io::Result<String> {
+    let output = Command::new("id").arg("-un").stderr(Stdio::inherit()).output()?;
+    let user = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || user.is_empty() {
+        return Err(io::Error::other("Failed to find out the current user"));
+    }
+    Ok(user)
+}
+
+/// A port nothing listens on right now. Another process could take it
+/// before sshd does, but only on a busy machine.
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?.port())
+}
+
+/// A fresh directory only the user can enter, from mkdtemp.
+fn private_dir() -> io::Result<PathBuf> {
+    let template = env::temp_dir().join("masuk-sshd-XXXXXX");
+    let mut template = CString::new(template.into_os_string().into_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .into_bytes_with_nul();
+    if unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
+fn keygen(path: &Path) -> io::Result<()> {
+    let status = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-C", "masuk-test", "-f"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("ssh-keygen exited with {}", status)));
+    }
+    Ok(())
+}
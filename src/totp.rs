@@ -0,0 +1,246 @@
+//! Time-based one-time passwords (RFC 6238), for hosts that ask for an
+//! authenticator code on login.
+//!
+//! The secret is what authenticator apps are set up with: the base32 text
+//! next to the QR code, or the `otpauth://totp/...` URI the QR code holds.
+//! Codes use HMAC-SHA1, like nearly every PAM module and app does.
+
+use anyhow::{anyhow, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A TOTP secret with its parameters.
+pub struct Totp {
+    key: Vec<u8>,
+    digits: u32,
+    period: u64,
+}
+
+impl Totp {
+    /// Parses a base32 secret (spaces and case don't matter) or an
+    /// `otpauth://totp/` URI with `secret`, and optionally `digits` and
+    /// `period`.
+    pub fn parse(secret: &str) -> Result<Totp> {
+        let mut totp = Totp {
+            key: Vec::new(),
+            digits: 6,
+            period: 30,
+        };
+        let encoded = match secret.strip_prefix("otpauth://") {
+            Some(uri) => {
+                let (kind, query) = uri.split_once('?').ok_or_else(|| anyhow!("The otpauth URI has no secret"))?;
+                if !kind.starts_with("totp/") {
+                    return Err(anyhow!("Only time-based (otpauth://totp/) secrets are supported"));
+                }
+                let mut encoded = None;
+                for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+                    match name {
+                        "secret" => encoded = Some(value.to_string()),
+                        "digits" => totp.digits = value.parse().map_err(|_| anyhow!("Invalid digits '{}'", value))?,
+                        "period" => totp.period = value.parse().map_err(|_| anyhow!("Invalid period '{}'", value))?,
+                        "algorithm" if !value.eq_ignore_ascii_case("SHA1") => {
+                            return Err(anyhow!("Only SHA1 codes are supported, not {}", value))
+                        }
+                        _ => {}
+                    }
+                }
+                encoded.ok_or_else(|| anyhow!("The otpauth URI has no secret"))?
+            }
+            None => secret.to_string(),
+        };
+        totp.key = base32(&encoded).ok_or_else(|| anyhow!("The TOTP secret is not valid base32"))?;
+        if totp.key.is_empty() || !(6..=10).contains(&totp.digits) || totp.period == 0 {
+            return Err(anyhow!("Invalid TOTP secret"));
+        }
+        Ok(totp)
+    }
+
+    /// The code for now and the seconds it stays valid.
+    pub fn now(&self) -> (String, u64) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        (self.code(time / self.period), self.period - time % self.period)
+    }
+
+    fn code(&self, counter: u64) -> String {
+        let mac = hmac_sha1(&self.key, &counter.to_be_bytes());
+        let offset = (mac[19] & 0x0f) as usize;
+        let value = u32::from_be_bytes([mac[offset] & 0x7f, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+        format!("{:0width$}", value as u64 % 10u64.pow(self.digits), width = self.digits as usize)
+    }
+}
+
+/// RFC 4648 base32, padding optional.
+fn base32(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bits = 0u64;
+    let mut count = 0;
+    for c in encoded.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=' && *c != b'-') {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        bits = bits << 5 | value as u64;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..20].copy_from_slice(&sha1(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(message);
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha1(&inner));
+    sha1(&outer)
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The key of the RFC 4226 and RFC 6238 test vectors, in base32.
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn hashes_like_sha1() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // Padding spills into a second block
+        assert_eq!(
+            hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn signs_like_hmac_sha1() {
+        // RFC 2202, test cases 2 and 6
+        assert_eq!(
+            hex(&hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        assert_eq!(
+            hex(&hmac_sha1(&[0xaa; 80], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+
+    #[test]
+    fn decodes_base32() {
+        assert_eq!(base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap(), b"12345678901234567890");
+        assert_eq!(base32("MY======").unwrap(), b"f");
+        assert_eq!(base32("MZXW6===").unwrap(), b"foo");
+        assert_eq!(base32("MZXW6YTBOI======").unwrap(), b"foobar");
+        assert_eq!(base32("MZXW6YTBOI").unwrap(), b"foobar");
+        assert_eq!(base32("mzxw 6ytb oi").unwrap(), b"foobar");
+        assert_eq!(base32("").unwrap(), b"");
+        assert!(base32("MZXW1").is_none());
+    }
+
+    #[test]
+    fn counts_like_hotp() {
+        // RFC 4226, appendix D
+        let totp = Totp::parse(SECRET).unwrap();
+        let codes = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583", "399871", "520489",
+        ];
+        for (counter, code) in codes.iter().enumerate() {
+            assert_eq!(totp.code(counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn counts_like_totp() {
+        // RFC 6238, appendix B, the SHA1 column
+        let totp = Totp::parse(&format!("otpauth://totp/test?secret={}&digits=8", SECRET)).unwrap();
+        let codes = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+        for (time, code) in codes {
+            assert_eq!(totp.code(time / totp.period), code);
+        }
+    }
+
+    #[test]
+    fn parses_otpauth_uris() {
+        let totp = Totp::parse(&format!(
+            "otpauth://totp/ACME:alice?secret={}&issuer=ACME&algorithm=SHA1&digits=8&period=60",
+            SECRET.to_lowercase()
+        ))
+        .unwrap();
+        assert_eq!(totp.key, b"12345678901234567890");
+        assert_eq!(totp.digits, 8);
+        assert_eq!(totp.period, 60);
+
+        let defaults = Totp::parse(&format!("otpauth://totp/alice?secret={}", SECRET)).unwrap();
+        assert_eq!((defaults.digits, defaults.period), (6, 30));
+
+        assert!(Totp::parse(&format!("otpauth://totp/alice?secret={}&algorithm=SHA256", SECRET)).is_err());
+        assert!(Totp::parse(&format!("otpauth://hotp/alice?secret={}&counter=1", SECRET)).is_err());
+        assert!(Totp::parse("otpauth://totp/alice?issuer=ACME").is_err());
+        assert!(Totp::parse(&format!("otpauth://totp/alice?secret={}&digits=4", SECRET)).is_err());
+        assert!(Totp::parse("not base32!").is_err());
+    }
+}
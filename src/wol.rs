@@ -0,0 +1,74 @@
+//! Wake-on-LAN, for machines that sleep until someone wants them.
+//!
+//! The magic packet is six `0xff` bytes followed by the MAC address sixteen
+//! times, sent as a UDP broadcast. The network card of the sleeping machine
+//! recognizes it and powers the machine on.
+
+use anyhow::{anyhow, Context, Result};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where magic packets go when the profile doesn't say.
+pub const DEFAULT_BROADCAST: &str = "255.255.255.255";
+const DEFAULT_PORT: u16 = 9;
+
+/// Parses `aa:bb:cc:dd:ee:ff` or `aa-bb-cc-dd-ee-ff`.
+pub fn parse_mac(value: &str) -> Option<[u8; 6]> {
+    let parts: Vec<&str> = value.split([':', '-']).collect();
+    if parts.len() != 6 {
+        return None;
+    }
+    let mut mac = [0; 6];
+    for (byte, part) in mac.iter_mut().zip(parts) {
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    Some(mac)
+}
+
+/// Sends the magic packet for `mac` to `broadcast`, an address with an
+/// optional port (9 by default).
+pub fn wake(mac: &str, broadcast: &str) -> Result<()> {
+    let mac = parse_mac(mac).ok_or_else(|| anyhow!("Invalid MAC address '{}'", mac))?;
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac);
+    }
+
+    let target = match broadcast.parse::<std::net::SocketAddr>() {
+        Ok(target) => target,
+        Err(_) => (broadcast, DEFAULT_PORT)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| anyhow!("Can't resolve broadcast address {}", broadcast))?,
+    };
+    let socket = UdpSocket::bind("0.0.0.0:0").context("Failed to open a UDP socket")?;
+    socket.set_broadcast(true).context("Failed to enable broadcasts")?;
+    socket
+        .send_to(&packet, target)
+        .with_context(|| format!("Failed to send the magic packet to {}", target))?;
+    Ok(())
+}
+
+/// Waits until `host` accepts connections on `port`, returning how long
+/// that took.
+pub fn wait_for_port(host: &str, port: u16, timeout: Duration) -> Result<Duration> {
+    let started = Instant::now();
+    loop {
+        // Resolved every time, the name may only be known once the machine is up
+        let addrs: Vec<_> = (host, port).to_socket_addrs().map(|addrs| addrs.collect()).unwrap_or_default();
+        for addr in addrs {
+            if TcpStream::connect_timeout(&addr, Duration::from_secs(1)).is_ok() {
+                return Ok(started.elapsed());
+            }
+        }
+        if started.elapsed() >= timeout {
+            return Err(anyhow!("{} didn't open port {} within {}s", host, port, timeout.as_secs()));
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
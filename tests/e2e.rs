@@ -0,0 +1,201 @@
+//! End-to-end tests: masuk connecting, running commands, copying files and
+//! forwarding ports to a throwaway sshd from masuk::test_support.
+//!
+//! Run with `cargo test --features test-support`. Without an sshd on the
+//! machine, the tests are skipped.
+
+use masuk::test_support::SshServer;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A server with a masuk config of its own holding the profile `box`.
+struct Lab {
+    server: SshServer,
+    home: PathBuf,
+}
+
+/// What a masuk run printed and how it exited.
+struct Run {
+    code: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+impl Lab {
+    /// `None` when there's no sshd to test against.
+    fn start() -> Option<Lab> {
+        let server = match SshServer::start() {
+            Ok(server) => server,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                eprintln!("Skipping end-to-end test: {}", e);
+                return None;
+            }
+            Err(e) => panic!("Failed to start sshd: {}", e),
+        };
+        let home = server.dir().join("home");
+        fs::create_dir(&home).unwrap();
+        let lab = Lab { server, home };
+        let add = lab.masuk(&lab.server.add_args("box").iter().map(String::as_str).collect::<Vec<_>>());
+        lab.check(&add);
+        Some(lab)
+    }
+
+    /// Runs masuk with its config in the lab's home. Output goes through
+    /// files, ssh forked into the background would keep pipes open.
+    fn masuk(&self, args: &[&str]) -> Run {
+        let stdout = self.server.dir().join("stdout");
+        let stderr = self.server.dir().join("stderr");
+        let status = Command::new(env!("CARGO_BIN_EXE_masuk"))
+            .args(args)
+            .env("HOME", &self.home)
+            .env("TERM", "dumb")
+            .env_remove("MASUK_CONFIG")
+            .env_remove("MASUK_CONTEXT")
+            .env_remove("SSH_AUTH_SOCK")
+            .current_dir(&self.home)
+            .stdin(Stdio::null())
+            .stdout(File::create(&stdout).unwrap())
+            .stderr(File::create(&stderr).unwrap())
+            .status()
+            .expect("Failed to run masuk");
+        Run {
+            code: status.code(),
+            stdout: fs::read_to_string(stdout).unwrap(),
+            stderr: fs::read_to_string(stderr).unwrap(),
+        }
+    }
+
+    /// Fails the test with everything that helps to find out why, unless
+    /// the run succeeded.
+    fn check(&self, run: &Run) {
+        assert_eq!(
+            run.code,
+            Some(0),
+            "masuk failed\nstdout:\n{}\nstderr:\n{}\nsshd:\n{}",
+            run.stdout,
+            run.stderr,
+            self.server.log_contents()
+        );
+    }
+}
+
+#[test]
+fn harness_accepts_plain_ssh() {
+    let Some(lab) = Lab::start() else { return };
+    let output = Command::new("ssh")
+        .args(lab.server.ssh_args())
+        .arg(lab.server.destination())
+        .arg("echo hello")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hello\n");
+}
+
+#[test]
+fn exec_runs_commands_and_passes_their_status_on() {
+    let Some(lab) = Lab::start() else { return };
+    // A single word is run as is, by the remote shell
+    let run = lab.masuk(&["exec", "box", "--", "echo \"hello from $USER\""]);
+    lab.check(&run);
+    assert_eq!(run.stdout.trim(), format!("hello from {}", lab.server.user()));
+
+    let run = lab.masuk(&["exec", "box", "--", "exit", "3"]);
+    assert_eq!(run.code, Some(3), "{}", run.stderr);
+}
+
+#[test]
+fn connect_logs_in_and_leaves() {
+    let Some(lab) = Lab::start() else { return };
+    let marker = lab.server.dir().join("connected");
+    let hook = format!("touch {}", marker.display());
+    lab.check(&lab.masuk(&["set", "box", "--post-connect", &hook]));
+    // Without a terminal, the remote shell ends with the empty input
+    let run = lab.masuk(&["connect", "box", "--no-preflight"]);
+    lab.check(&run);
+    assert!(run.stdout.contains("Connecting to box"), "{}", run.stdout);
+    assert!(marker.exists());
+}
+
+#[test]
+fn cp_copies_to_and_from_the_host() {
+    let Some(lab) = Lab::start() else { return };
+    let local = lab.home.join("notes.txt");
+    fs::write(&local, "first line\nsecond line\n").unwrap();
+    let remote = lab.server.dir().join("remote.txt");
+
+    let destination = format!("box:{}", remote.display());
+    lab.check(&lab.masuk(&["cp", &local.display().to_string(), &destination]));
+    assert_eq!(fs::read_to_string(&remote).unwrap(), "first line\nsecond line\n");
+
+    fs::write(&remote, "changed on the host\n").unwrap();
+    let back = lab.home.join("back.txt");
+    lab.check(&lab.masuk(&["cp", &destination, &back.display().to_string()]));
+    assert_eq!(fs::read_to_string(back).unwrap(), "changed on the host\n");
+}
+
+#[test]
+fn push_and_pull_use_the_profile_directories() {
+    let Some(lab) = Lab::start() else { return };
+    let remote_dir = lab.server.dir().join("uploads");
+    fs::create_dir(&remote_dir).unwrap();
+    let local_dir = lab.home.join("downloads");
+    fs::create_dir(&local_dir).unwrap();
+    lab.check(&lab.masuk(&[
+        "set",
+        "box",
+        "--remote-dir",
+        &remote_dir.display().to_string(),
+        "--local-dir",
+        &local_dir.display().to_string(),
+    ]));
+
+    fs::write(lab.home.join("report.csv"), "a,b\n1,2\n").unwrap();
+    lab.check(&lab.masuk(&["push", "box", "report.csv"]));
+    assert_eq!(fs::read_to_string(remote_dir.join("report.csv")).unwrap(), "a,b\n1,2\n");
+
+    fs::write(remote_dir.join("result.txt"), "done\n").unwrap();
+    lab.check(&lab.masuk(&["pull", "box", "result.txt"]));
+    assert_eq!(fs::read_to_string(local_dir.join("result.txt")).unwrap(), "done\n");
+}
+
+#[test]
+fn tunnel_forwards_a_local_port() {
+    let Some(lab) = Lab::start() else { return };
+    // A service on the host side of the tunnel, answering once
+    let service = TcpListener::bind("127.0.0.1:0").unwrap();
+    let service_port = service.local_addr().unwrap().port();
+    thread::spawn(move || {
+        if let Ok((mut stream, _)) = service.accept() {
+            let _ = stream.write_all(b"pong");
+        }
+    });
+    let local_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+    let spec = format!("{}:127.0.0.1:{}", local_port, service_port);
+    lab.check(&lab.masuk(&["tunnel", "box", "-L", &spec, "--background"]));
+    let list = lab.masuk(&["tunnel", "ls"]);
+    lab.check(&list);
+    assert!(list.stdout.contains("box"), "{}", list.stdout);
+
+    let started = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", local_port)) {
+            Ok(stream) => break stream,
+            Err(e) if started.elapsed() > Duration::from_secs(5) => panic!("The tunnel didn't open: {}", e),
+            Err(_) => thread::sleep(Duration::from_millis(50)),
+        }
+    };
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    assert_eq!(answer, "pong");
+
+    lab.check(&lab.masuk(&["tunnel", "stop", "box"]));
+    assert!(lab.masuk(&["tunnel", "ls"]).stdout.lines().all(|line| !line.contains(&spec)));
+}